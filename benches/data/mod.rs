@@ -0,0 +1,99 @@
+//! Generators for the datasets of the benchmarks. The lines look like the peptides of
+//! `create_data_file.py`, and every dataset is the same for the same seed.
+
+const AMINO_ACIDS: &[u8] = b"ARNDCEQGHILKMFPSTWYV";
+
+#[derive(Clone, Copy, Debug)]
+pub enum Dataset {
+    /// Lines of 5 to 150 random amino acids
+    Random,
+
+    /// The random lines in sorted order
+    Sorted,
+
+    /// The random lines in reverse sorted order
+    ReverseSorted,
+
+    /// Random lines that are picked from only 100 different lines
+    Duplicates,
+
+    /// Lines of 1000 to 10000 random amino acids
+    LongLines
+}
+
+impl Dataset {
+    pub const ALL: [Dataset; 5] = [
+        Dataset::Random,
+        Dataset::Sorted,
+        Dataset::ReverseSorted,
+        Dataset::Duplicates,
+        Dataset::LongLines
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dataset::Random        => "random",
+            Dataset::Sorted        => "sorted",
+            Dataset::ReverseSorted => "reverse-sorted",
+            Dataset::Duplicates    => "duplicates",
+            Dataset::LongLines     => "long-lines"
+        }
+    }
+
+    /// Generates about `bytes` bytes of newline terminated lines
+    pub fn generate(&self, bytes: usize) -> Vec<u8> {
+        let mut random = XorShift(0x5eed_1234_abcd_ef01);
+
+        let mut lines: Vec<Vec<u8>> = match self {
+            Dataset::Duplicates => {
+                let distinct: Vec<Vec<u8>> = (0..100).map(|_| random.peptide(5, 150)).collect();
+                take_bytes(bytes, || distinct[random.below(distinct.len())].clone())
+            },
+            Dataset::LongLines => take_bytes(bytes, || random.peptide(1000, 10000)),
+            _                  => take_bytes(bytes, || random.peptide(5, 150))
+        };
+
+        match self {
+            Dataset::Sorted        => lines.sort(),
+            Dataset::ReverseSorted => lines.sort_by(|a, b| b.cmp(a)),
+            _                      => ()
+        }
+
+        lines.into_iter().flat_map(|mut line| { line.push(b'\n'); line }).collect()
+    }
+}
+
+/// Generates lines until they hold at least `bytes` bytes
+fn take_bytes(bytes: usize, mut line: impl FnMut() -> Vec<u8>) -> Vec<Vec<u8>> {
+    let mut lines = vec![];
+    let mut total = 0;
+
+    while total < bytes {
+        let next = line();
+        total += next.len() + 1;
+        lines.push(next);
+    }
+
+    lines
+}
+
+/// A small and fast pseudo random generator, so the datasets need no extra dependencies
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn peptide(&mut self, min_length: usize, max_length: usize) -> Vec<u8> {
+        let length = min_length + self.below(max_length - min_length + 1);
+        (0..length).map(|_| AMINO_ACIDS[self.below(AMINO_ACIDS.len())]).collect()
+    }
+}
@@ -0,0 +1,137 @@
+//! Benchmarks of the building blocks of the sort and of the whole sort. Save a
+//! baseline with `cargo bench -- --save-baseline main` and compare a change
+//! against it with `cargo bench -- --baseline main`.
+
+use std::{hint::black_box, io::{self, Cursor}};
+
+use bytesize::MB;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use sorter::{external_sort, internals::{Chunk, Chunks, Line, WinnerHeap}, Configuration, MemoryStorage};
+
+mod data;
+
+use data::Dataset;
+
+/// The size of the datasets of the building blocks
+const DATASET_SIZE: usize = 4 * MB as usize;
+
+/// The size of the datasets of the whole sort
+const SORT_DATASET_SIZE: usize = 16 * MB as usize;
+
+/// The number of sorted runs that are merged
+const RUNS: usize = 16;
+
+/// Reads the whole input into a single chunk
+fn read_chunk(input: &[u8]) -> Chunk {
+    Chunks::new(Cursor::new(input), input.len() + 1, Configuration::default()).next().unwrap().unwrap()
+}
+
+/// Splits the input into sorted runs of about the same size
+fn sorted_runs(input: &[u8]) -> Vec<Vec<Line>> {
+    Chunks::new(Cursor::new(input), input.len() / RUNS + 1, Configuration::default())
+        .map(|chunk| {
+            let mut chunk = chunk.unwrap();
+            chunk.sort();
+            chunk.collect()
+        })
+        .collect()
+}
+
+fn bench_chunk_sort(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunk_sort");
+
+    for dataset in Dataset::ALL {
+        let input = dataset.generate(DATASET_SIZE);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+
+        group.bench_function(dataset.name(), |b| {
+            b.iter_batched(|| read_chunk(&input), |mut chunk| chunk.sort(), BatchSize::LargeInput)
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_heap_merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("heap_merge");
+
+    for dataset in Dataset::ALL {
+        let input = dataset.generate(DATASET_SIZE);
+        let runs = sorted_runs(&input);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+
+        group.bench_function(dataset.name(), |b| {
+            b.iter_batched(
+                || runs.clone(),
+                |runs| {
+                    let mut runs: Vec<_> = runs.into_iter().map(Vec::into_iter).collect();
+                    let mut heap = WinnerHeap::new(
+                        runs.iter_mut().enumerate().filter_map(|(run, lines)| lines.next().map(|line| (line, run))).collect()
+                    );
+
+                    while let Some((line, run)) = heap.pop() {
+                        black_box(line);
+
+                        if let Some(next_line) = runs[run].next() {
+                            heap.push((next_line, run));
+                        }
+                    }
+                },
+                BatchSize::LargeInput
+            )
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_line_cmp(c: &mut Criterion) {
+    let mut group = c.benchmark_group("line_cmp");
+
+    for dataset in Dataset::ALL {
+        let lines: Vec<Line> = read_chunk(&dataset.generate(DATASET_SIZE)).collect();
+        group.throughput(Throughput::Elements(lines.len() as u64 - 1));
+
+        group.bench_function(dataset.name(), |b| {
+            b.iter(|| lines.windows(2).filter(|pair| pair[0] < pair[1]).count())
+        });
+    }
+
+    group.finish();
+}
+
+fn sort(input: &[u8], buffer_size: usize, threads: usize) {
+    let config = Configuration { threads, buffer_size, ..Configuration::default() };
+
+    external_sort(&mut Cursor::new(input), &mut io::sink(), &mut MemoryStorage::new(), config).unwrap();
+}
+
+fn bench_external_sort(c: &mut Criterion) {
+    let mut group = c.benchmark_group("external_sort");
+    group.sample_size(10);
+
+    for dataset in Dataset::ALL {
+        let input = dataset.generate(SORT_DATASET_SIZE);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+
+        group.bench_function(dataset.name(), |b| b.iter(|| sort(&input, 32 * MB as usize, 4)));
+    }
+
+    // The buffer decides how many runs are merged, the threads how many are sorted at once
+    let input = Dataset::Random.generate(SORT_DATASET_SIZE);
+    group.throughput(Throughput::Bytes(input.len() as u64));
+
+    for buffer_size in [4 * MB as usize, 32 * MB as usize] {
+        for threads in [1, 4] {
+            let id = BenchmarkId::new("random", format!("{}MB/{}threads", buffer_size / MB as usize, threads));
+            group.bench_with_input(id, &(buffer_size, threads), |b, (buffer_size, threads)| {
+                b.iter(|| sort(&input, *buffer_size, *threads))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_chunk_sort, bench_heap_merge, bench_line_cmp, bench_external_sort);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+//! Reads arbitrary bytes with arbitrary buffer sizes and read boundaries through
+//! `Chunks` and `Lines`, which have to hand out the same lines as a naive split
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use sorter::{internals::{Chunks, Lines}, Configuration};
+use sorter_fuzz::{join_lines, naive_lines, SplitReader};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    buffer_size: u16,
+    splits: Vec<u16>,
+    data: Vec<u8>
+}
+
+fuzz_target!(|input: Input| {
+    let buffer_size = input.buffer_size as usize + 1;
+    let expected = join_lines(&naive_lines(&input.data));
+
+    let mut output = vec![];
+    let chunks = Chunks::new(SplitReader::new(&input.data, &input.splits), buffer_size, Configuration::default());
+
+    for chunk in chunks {
+        chunk.unwrap().write(&mut output).unwrap();
+    }
+
+    assert_eq!(output, expected);
+
+    let mut output = vec![];
+    let lines = Lines::new(SplitReader::new(&input.data, &input.splits), buffer_size, Configuration::default());
+
+    for line in lines {
+        line.unwrap().write(&mut output).unwrap();
+    }
+
+    assert_eq!(output, expected);
+});
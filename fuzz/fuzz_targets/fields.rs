@@ -0,0 +1,57 @@
+//! Sorts chunks of arbitrary lines on an arbitrary field or range of fields, which lines can lack.
+//! Every line has to be kept, in the order of its key.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use sorter::{internals::Chunks, Configuration};
+use sorter_fuzz::{naive_key, naive_lines, SplitReader};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    field: u8,
+    last_field: Option<u8>,
+    delimiter: u8,
+    buffer_size: u16,
+    splits: Vec<u16>,
+    data: Vec<u8>
+}
+
+fuzz_target!(|input: Input| {
+    let field = input.field as usize % 8 + 1;
+
+    let config = Configuration {
+        field,
+        last_field: input.last_field.map(|last_field| field + last_field as usize % 4),
+        delimiter: input.delimiter,
+        ..Configuration::default()
+    };
+
+    let mut lines = vec![];
+    let chunks = Chunks::new(SplitReader::new(&input.data, &input.splits), input.buffer_size as usize + 1, config.clone());
+
+    for chunk in chunks {
+        let mut chunk = chunk.unwrap();
+        chunk.sort();
+
+        let mut output = vec![];
+        chunk.write(&mut output).unwrap();
+
+        let sorted = naive_lines(&output).into_iter().map(<[u8]>::to_vec).collect::<Vec<Vec<u8>>>();
+        assert!(sorted.windows(2).all(|pair| {
+            naive_key(&pair[0], config.field, config.last_field, config.delimiter)
+                <= naive_key(&pair[1], config.field, config.last_field, config.delimiter)
+        }));
+
+        lines.extend(sorted);
+    }
+
+    let mut expected: Vec<&[u8]> = naive_lines(&input.data);
+    expected.sort();
+
+    let mut lines: Vec<&[u8]> = lines.iter().map(Vec::as_slice).collect();
+    lines.sort();
+
+    assert_eq!(lines, expected);
+});
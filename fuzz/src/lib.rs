@@ -0,0 +1,75 @@
+//! Helpers that the fuzz targets share
+
+use std::io::{self, Read};
+
+/// A reader that hands out its data in pieces of the given sizes, so the reads
+/// of the sorter end at arbitrary positions
+pub struct SplitReader<'a> {
+    data: &'a [u8],
+    splits: &'a [u16],
+    reads: usize
+}
+
+impl<'a> SplitReader<'a> {
+    pub fn new(data: &'a [u8], splits: &'a [u16]) -> Self {
+        SplitReader { data, splits, reads: 0 }
+    }
+}
+
+impl Read for SplitReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        // A read of zero bytes would end the input, so every piece holds at least a byte
+        let split = match self.splits {
+            []     => usize::MAX,
+            splits => splits[self.reads % splits.len()].max(1) as usize
+        };
+        self.reads += 1;
+
+        let bytes = split.min(buffer.len()).min(self.data.len());
+        buffer[..bytes].copy_from_slice(&self.data[..bytes]);
+        self.data = &self.data[bytes..];
+
+        Ok(bytes)
+    }
+}
+
+/// Splits the data into lines, the last line does not need a newline
+pub fn naive_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines: Vec<&[u8]> = data.split(|byte| *byte == b'\n').collect();
+
+    // The data ends with a newline, or is empty
+    if lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    lines
+}
+
+/// Writes the lines like the sorter does, every line ends with a newline
+pub fn join_lines(lines: &[&[u8]]) -> Vec<u8> {
+    lines.iter().flat_map(|line| line.iter().copied().chain([b'\n'])).collect()
+}
+
+/// Returns the part of a line that it is sorted on. A field starts at the
+/// delimiter in front of it and runs until the end of the line, or until the
+/// delimiter after the last field if there is one. A line without the field
+/// has an empty key.
+pub fn naive_key(line: &[u8], field: usize, last_field: Option<usize>, delimiter: u8) -> &[u8] {
+    let delimiters: Vec<usize> = line.iter()
+        .enumerate()
+        .filter(|(_, byte)| **byte == delimiter)
+        .map(|(offset, _)| offset)
+        .collect();
+
+    let start = match field {
+        0 | 1 => 0,
+        field => match delimiters.get(field - 2) {
+            Some(offset) => *offset,
+            None         => return &[]
+        }
+    };
+
+    let end = last_field.and_then(|last_field| delimiters.get(last_field - 1)).copied().unwrap_or(line.len());
+
+    &line[start..end]
+}
@@ -0,0 +1,376 @@
+use std::{io::{self, Read, Write, ErrorKind}, sync::{atomic::{AtomicUsize, Ordering}, mpsc::{channel, sync_channel, Receiver, Sender, SyncSender}, Arc, Mutex}, thread::{self, JoinHandle}};
+
+/// The largest number of inputs that are read ahead at the same time. Inputs
+/// that are opened once this many threads are reading are read when they are
+/// needed instead, so wide merges on all threads do not run out of threads.
+const MAX_READ_AHEAD_THREADS: usize = 128;
+
+/// The number of threads that are reading ahead
+static READ_AHEAD_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// A reader that reads the next buffer of its input on a background thread,
+/// while the previous buffer is being consumed. The input is read directly
+/// if no thread can be started for it.
+pub enum ReadAhead<R: Read + Send + 'static> {
+    Background {
+        /// The buffers that were read in the background
+        filled: Receiver<io::Result<Vec<u8>>>,
+
+        /// Used buffers are sent back to be filled again
+        recycled: Sender<Vec<u8>>,
+
+        /// The current buffer
+        buffer: Vec<u8>,
+
+        /// The position of the next unread byte in the current buffer
+        position: usize,
+
+        /// The thread that reads the input, which returns the input when it is done
+        reader: JoinHandle<R>,
+
+        /// The error that stopped the thread, which every later read returns as well
+        error: Option<(ErrorKind, String)>
+    },
+
+    /// The input is read on the thread that reads from this reader, because
+    /// too many other inputs are read ahead already
+    Direct(R)
+}
+
+impl<R: Read + Send + 'static> ReadAhead<R> {
+    /// Starts reading the input in the background, unless too many inputs are read ahead already
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The input to read from
+    /// * `buffer_size` - The size of each of the two buffers
+    ///
+    /// # Returns
+    ///
+    /// A reader over the input
+    pub fn new(input: R, buffer_size: usize) -> Self {
+        let Some(slot) = ThreadSlot::take() else {
+            return ReadAhead::Direct(input);
+        };
+
+        // One buffer is filled while the other one is read
+        let (filled_sender, filled) = sync_channel(1);
+        let (recycled, recycled_receiver) = channel();
+
+        // The input is taken back if the thread cannot be started
+        let input = Arc::new(Mutex::new(Some(input)));
+        let thread_input = Arc::clone(&input);
+
+        let spawned = thread::Builder::new().spawn(move || {
+            let _slot = slot;
+            let mut input = thread_input.lock().unwrap().take().unwrap();
+
+            read_buffers(&mut input, buffer_size.max(1), &filled_sender, &recycled_receiver);
+            input
+        });
+
+        match spawned {
+            Ok(reader) => ReadAhead::Background { filled, recycled, buffer: vec![], position: 0, reader, error: None },
+            Err(_)     => ReadAhead::Direct(input.lock().unwrap().take().unwrap())
+        }
+    }
+
+    /// Stops reading and returns the input
+    pub fn into_inner(self) -> R {
+        match self {
+            ReadAhead::Background { filled, recycled, reader, .. } => {
+                // Stop the reader thread if it is waiting for a free buffer
+                drop(filled);
+                drop(recycled);
+
+                reader.join().expect("The read-ahead thread panicked")
+            },
+            ReadAhead::Direct(input) => input
+        }
+    }
+}
+
+impl<R: Read + Send + 'static> Read for ReadAhead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (filled, recycled, buffer, position, error) = match self {
+            ReadAhead::Background { filled, recycled, buffer, position, error, .. } => (filled, recycled, buffer, position, error),
+            ReadAhead::Direct(input) => return input.read(buf)
+        };
+
+        // The thread stopped at the error, so the input would seem to end there
+        if let Some((kind, message)) = error {
+            return Err(io::Error::new(*kind, message.clone()));
+        }
+
+        if *position == buffer.len() {
+            match filled.recv() {
+                Ok(Ok(next)) => {
+                    let used = std::mem::replace(buffer, next);
+                    let _ = recycled.send(used);
+                    *position = 0;
+                },
+                Ok(Err(err)) => {
+                    *error = Some((err.kind(), err.to_string()));
+                    return Err(err);
+                },
+                // The input is exhausted
+                Err(_) => return Ok(0)
+            }
+        }
+
+        let bytes_read = buf.len().min(buffer.len() - *position);
+        buf[..bytes_read].copy_from_slice(&buffer[*position..*position + bytes_read]);
+        *position += bytes_read;
+
+        Ok(bytes_read)
+    }
+}
+
+/// One of the threads that can read ahead, which is given back when it is dropped
+struct ThreadSlot;
+
+impl ThreadSlot {
+    fn take() -> Option<Self> {
+        READ_AHEAD_THREADS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |threads| (threads < MAX_READ_AHEAD_THREADS).then_some(threads + 1))
+            .ok()
+            .map(|_| ThreadSlot)
+    }
+}
+
+impl Drop for ThreadSlot {
+    fn drop(&mut self) {
+        READ_AHEAD_THREADS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Fills buffers until the input is exhausted or the receiving side is gone
+fn read_buffers(
+    input: &mut impl Read,
+    buffer_size: usize,
+    filled: &SyncSender<io::Result<Vec<u8>>>,
+    recycled: &Receiver<Vec<u8>>
+) {
+    loop {
+        let mut buffer = recycled.try_recv().unwrap_or_else(|_| Vec::with_capacity(buffer_size));
+        buffer.resize(buffer_size, 0);
+
+        let mut bytes_read = 0;
+        while bytes_read < buffer_size {
+            match input.read(&mut buffer[bytes_read..]) {
+                Ok(0) => break,
+                Ok(n) => bytes_read += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => {
+                    let _ = filled.send(Err(err));
+                    return;
+                }
+            }
+        }
+
+        if bytes_read == 0 {
+            return;
+        }
+
+        buffer.truncate(bytes_read);
+
+        if filled.send(Ok(buffer)).is_err() {
+            return;
+        }
+    }
+}
+
+/// A writer that hands full buffers to another thread
+pub struct BufferSender {
+    buffer: Vec<u8>,
+    buffer_size: usize,
+    sender: SyncSender<Vec<u8>>,
+    recycled: Receiver<Vec<u8>>
+}
+
+impl BufferSender {
+    fn send_buffer(&mut self) -> io::Result<()> {
+        let next = self.recycled.try_recv().unwrap_or_else(|_| Vec::with_capacity(self.buffer_size));
+        let buffer = std::mem::replace(&mut self.buffer, next);
+
+        self.sender.send(buffer).map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "The writer thread stopped"))
+    }
+}
+
+impl Write for BufferSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        if self.buffer.len() >= self.buffer_size {
+            self.send_buffer()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send_buffer()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs a producer on a separate thread and writes its output to the given
+/// writer on the current thread. The writer does not have to be `Send`, so
+/// this also works for the final output of the sort.
+///
+/// # Arguments
+///
+/// * `output` - The writer to write to
+/// * `buffer_size` - The size of each of the two buffers
+/// * `produce` - Writes its output to the given writer
+///
+/// # Returns
+///
+/// The result of the producer, or the first error of the writer. The producer
+/// runs into an error as well once the writer fails, so it stops early.
+pub fn write_behind<T: Send>(
+    output: &mut impl Write,
+    buffer_size: usize,
+    produce: impl FnOnce(&mut BufferSender) -> io::Result<T> + Send
+) -> io::Result<T> {
+    // One buffer is filled while the other one is written
+    let (sender, receiver) = sync_channel(1);
+    let (recycler, recycled) = channel();
+
+    thread::scope(|scope| {
+        let producer = scope.spawn(move || {
+            let mut writer = BufferSender { buffer: Vec::with_capacity(buffer_size), buffer_size: buffer_size.max(1), sender, recycled };
+            let result = produce(&mut writer)?;
+            writer.flush()?;
+
+            Ok(result)
+        });
+
+        let mut written = Ok(());
+
+        for buffer in &receiver {
+            written = output.write_all(&buffer);
+
+            if written.is_err() {
+                break;
+            }
+
+            let mut buffer = buffer;
+            buffer.clear();
+            let _ = recycler.send(buffer);
+        }
+
+        // The producer cannot send any more buffers once the receiver is gone
+        drop(receiver);
+
+        let result = producer.join().expect("The merging thread panicked");
+        written.and(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fault::{Fault, Faults, Faulty};
+
+    use super::*;
+
+    #[test]
+    fn test_read_ahead() {
+        let content: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+
+        let mut reader = ReadAhead::new(io::Cursor::new(content.clone()), 64);
+        let mut output = vec![];
+        reader.read_to_end(&mut output).unwrap();
+
+        assert_eq!(output, content);
+        assert_eq!(reader.into_inner().position(), 10000);
+    }
+
+    #[test]
+    fn test_read_ahead_stops_early() {
+        let content = vec![0; 10000];
+
+        let mut reader = ReadAhead::new(io::Cursor::new(content), 64);
+        reader.read_exact(&mut [0; 10]).unwrap();
+
+        assert!(reader.into_inner().position() < 10000);
+    }
+
+    #[test]
+    fn test_read_ahead_error_stays() {
+        // The thread is started here, other tests can hold all threads that read ahead
+        let (filled_sender, filled) = sync_channel(1);
+        let (recycled, recycled_receiver) = channel();
+
+        let reader = thread::spawn(move || {
+            let mut input = Faulty::new(io::Cursor::new(vec![0; 1000]), Faults::new().at(100, Fault::Io));
+            read_buffers(&mut input, 64, &filled_sender, &recycled_receiver);
+            input
+        });
+
+        let mut reader = ReadAhead::Background { filled, recycled, buffer: vec![], position: 0, reader, error: None };
+
+        let mut output = vec![];
+        let err = reader.read_to_end(&mut output).unwrap_err();
+        assert_eq!(output.len(), 64);
+
+        // The input is not treated as exhausted after the error was returned once
+        assert_eq!(reader.read(&mut [0; 10]).unwrap_err().kind(), err.kind());
+        assert_eq!(reader.read(&mut [0; 10]).unwrap_err().kind(), err.kind());
+    }
+
+    #[test]
+    fn test_read_ahead_thread_limit() {
+        let content: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+
+        // The inputs after the limit are read directly, other tests can hold some threads as well
+        let mut readers: Vec<ReadAhead<io::Cursor<Vec<u8>>>> = (0..MAX_READ_AHEAD_THREADS + 1)
+            .map(|_| ReadAhead::new(io::Cursor::new(content.clone()), 64))
+            .collect();
+
+        assert!(matches!(readers.last(), Some(ReadAhead::Direct(_))));
+
+        for reader in &mut readers {
+            let mut output = vec![];
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, content);
+        }
+
+        readers.into_iter().for_each(|reader| { reader.into_inner(); });
+    }
+
+    #[test]
+    fn test_write_behind() {
+        let mut output = vec![];
+
+        let result = write_behind(&mut output, 4, |writer| {
+            for i in 0..100 {
+                writeln!(writer, "{}", i)?;
+            }
+            Ok(42)
+        });
+
+        let expected: String = (0..100).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(output, expected.as_bytes());
+    }
+
+    #[test]
+    fn test_write_behind_output_fails() {
+        let mut output = Faulty::new(vec![], Faults::new().at(10, Fault::NoSpace));
+
+        // The producer stops once its buffers are no longer written
+        let result = write_behind(&mut output, 4, |writer| {
+            for i in 0.. {
+                writeln!(writer, "{}", i)?;
+            }
+            Ok(())
+        });
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::StorageFull);
+        assert_eq!(output.into_inner().len(), 10);
+    }
+}
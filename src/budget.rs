@@ -0,0 +1,381 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The memory that the buffers of a sort share. Buffers reserve their memory
+/// before they are allocated, and wait for other buffers to release theirs
+/// when the limit is reached. Buffers that are handed out by the budget are
+/// reused once nothing refers to them anymore. Clones share the same budget.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    state: Arc<BudgetState>
+}
+
+struct BudgetState {
+    /// The maximum number of bytes that can be reserved, if any
+    limit: Option<usize>,
+
+    /// The reserved memory and the buffers that can be reused
+    pool: Mutex<BufferPool>,
+
+    /// Signalled whenever memory is released
+    released: Condvar
+}
+
+/// The reserved memory and the buffers that can be reused
+#[derive(Default)]
+struct BufferPool {
+    /// The number of bytes that are reserved
+    reserved: usize,
+
+    /// Buffers that are no longer used, which count towards the limit until they
+    /// are handed out again or freed to make room for a reservation
+    idle: Vec<Vec<u8>>,
+
+    /// The total capacity of the idle buffers
+    idle_bytes: usize,
+
+    /// Buffers that are handed out, which become idle once nothing else refers to them
+    lent: Vec<Arc<Vec<u8>>>,
+
+    /// The largest number of bytes that were reserved or idle at once
+    peak: usize
+}
+
+impl BufferPool {
+    /// Moves the buffers that are no longer referenced to the idle buffers, as
+    /// far as the limit allows
+    fn reclaim(&mut self, limit: Option<usize>) {
+        let mut index = 0;
+
+        while index < self.lent.len() {
+            if Arc::strong_count(&self.lent[index]) > 1 {
+                index += 1;
+                continue;
+            }
+
+            // Nothing else refers to the buffer, so nothing can clone it anymore
+            let buffer = Arc::try_unwrap(self.lent.swap_remove(index)).unwrap();
+
+            if limit.is_none_or(|limit| self.reserved + self.idle_bytes + buffer.capacity() <= limit) {
+                self.idle_bytes += buffer.capacity();
+                self.idle.push(buffer);
+            }
+        }
+    }
+
+    /// Reserves bytes that are already made room for
+    fn add_reserved(&mut self, bytes: usize) {
+        self.reserved += bytes;
+        self.peak = self.peak.max(self.reserved + self.idle_bytes);
+    }
+
+    /// Frees idle buffers until `bytes` more can be reserved
+    fn make_room(&mut self, bytes: usize, limit: Option<usize>) {
+        let Some(limit) = limit else {
+            return;
+        };
+
+        while self.reserved + self.idle_bytes + bytes > limit {
+            match self.idle.pop() {
+                Some(buffer) => self.idle_bytes -= buffer.capacity(),
+                None         => break
+            }
+        }
+    }
+}
+
+impl MemoryBudget {
+    /// Creates a budget that never reserves more than `limit` bytes at once
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget::with_limit(Some(limit))
+    }
+
+    /// Creates a budget that only keeps track of the reserved memory
+    pub fn unlimited() -> Self {
+        MemoryBudget::with_limit(None)
+    }
+
+    fn with_limit(limit: Option<usize>) -> Self {
+        MemoryBudget { state: Arc::new(BudgetState { limit, pool: Mutex::default(), released: Condvar::new() }) }
+    }
+
+    /// Returns the limit of the budget, if any
+    pub fn limit(&self) -> Option<usize> {
+        self.state.limit
+    }
+
+    /// Returns the number of bytes that are reserved
+    pub fn reserved(&self) -> usize {
+        self.state.pool.lock().unwrap().reserved
+    }
+
+    /// Returns the largest number of bytes that were reserved or held by idle
+    /// buffers at once
+    pub fn peak(&self) -> usize {
+        self.state.pool.lock().unwrap().peak
+    }
+
+    /// Returns the number of bytes that can be reserved without waiting
+    pub fn available(&self) -> usize {
+        match self.state.limit {
+            Some(limit) => limit.saturating_sub(self.reserved()),
+            None        => usize::MAX
+        }
+    }
+
+    /// Reserves memory, waiting until enough memory is released if necessary.
+    /// A reservation that is larger than the limit is granted once nothing else
+    /// is reserved, so a single buffer can never wait forever.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The number of bytes to reserve
+    ///
+    /// # Returns
+    ///
+    /// The reservation, which releases the memory when it is dropped
+    pub fn reserve(&self, bytes: usize) -> Reservation {
+        self.reserve_between(bytes, bytes)
+    }
+
+    /// Reserves as much memory as is available, up to `max` bytes. Waits until
+    /// at least `min` bytes are available.
+    ///
+    /// # Arguments
+    ///
+    /// * `min` - The number of bytes that is needed
+    /// * `max` - The number of bytes that could be used
+    ///
+    /// # Returns
+    ///
+    /// The reservation, which releases the memory when it is dropped
+    pub fn reserve_between(&self, min: usize, max: usize) -> Reservation {
+        let bytes = self.acquire(min.min(max), max);
+
+        Reservation { budget: self.clone(), bytes }
+    }
+
+    /// Hands out a buffer of the given size with its reservation. An idle buffer
+    /// that is large enough is reused, without waiting and without filling it
+    /// again. Otherwise a new buffer is allocated once its memory is reserved.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the buffer
+    ///
+    /// # Returns
+    ///
+    /// The buffer and the reservation of its capacity
+    pub fn take_buffer(&self, size: usize) -> (Vec<u8>, Reservation) {
+        let mut pool = self.state.pool.lock().unwrap();
+        pool.reclaim(self.state.limit);
+
+        // The smallest idle buffer that is large enough
+        let reusable = pool.idle
+            .iter()
+            .enumerate()
+            .filter(|(_, buffer)| buffer.capacity() >= size)
+            .min_by_key(|(_, buffer)| buffer.capacity())
+            .map(|(index, _)| index);
+
+        if let Some(index) = reusable {
+            let mut buffer = pool.idle.swap_remove(index);
+            pool.idle_bytes -= buffer.capacity();
+            pool.add_reserved(buffer.capacity());
+            drop(pool);
+
+            // Only the part that is new to the buffer is filled
+            buffer.resize(size, 0);
+
+            let bytes = buffer.capacity();
+            return (buffer, Reservation { budget: self.clone(), bytes });
+        }
+
+        drop(pool);
+
+        let reservation = self.reserve(size);
+        (vec![0; size], reservation)
+    }
+
+    /// Keeps track of a buffer that is shared, so it can be reused once nothing
+    /// else refers to it
+    pub fn lend(&self, buffer: &Arc<Vec<u8>>) {
+        self.state.pool.lock().unwrap().lent.push(Arc::clone(buffer));
+    }
+
+    /// Waits until at least `min` bytes are available, or until nothing is
+    /// reserved, and reserves up to `max` bytes
+    fn acquire(&self, min: usize, max: usize) -> usize {
+        let limit = self.state.limit;
+        let mut pool = self.state.pool.lock().unwrap();
+
+        loop {
+            pool.reclaim(limit);
+
+            // Idle buffers are freed to make room
+            let available = match limit {
+                Some(limit) => limit.saturating_sub(pool.reserved),
+                None        => usize::MAX
+            };
+
+            if available >= min || pool.reserved == 0 {
+                let bytes = max.min(available.max(min));
+
+                pool.make_room(bytes, limit);
+                pool.add_reserved(bytes);
+
+                return bytes;
+            }
+
+            pool = self.state.released.wait(pool).unwrap();
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        if bytes > 0 {
+            self.state.pool.lock().unwrap().reserved -= bytes;
+            self.state.released.notify_all();
+        }
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget::unlimited()
+    }
+}
+
+/// Memory that is reserved in a budget
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize
+}
+
+impl Reservation {
+    /// Returns the number of reserved bytes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Reserves more memory for a buffer that is already allocated. This does
+    /// not wait, because the caller may hold the memory that it would wait for.
+    /// The limit can be exceeded until the memory is released, new reservations
+    /// wait until then.
+    pub fn grow(&mut self, bytes: usize) {
+        let mut pool = self.budget.state.pool.lock().unwrap();
+        pool.make_room(bytes, self.budget.state.limit);
+        pool.add_reserved(bytes);
+
+        self.bytes += bytes;
+    }
+
+    /// Releases the memory that is no longer needed
+    pub fn shrink_to(&mut self, bytes: usize) {
+        if bytes < self.bytes {
+            self.budget.release(self.bytes - bytes);
+            self.bytes = bytes;
+        }
+    }
+
+    /// Moves part of the reserved memory to a reservation of its own
+    pub fn split_off(&mut self, bytes: usize) -> Reservation {
+        let bytes = bytes.min(self.bytes);
+        self.bytes -= bytes;
+
+        Reservation { budget: self.budget.clone(), bytes }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_reserve_and_release() {
+        let budget = MemoryBudget::new(100);
+
+        let first = budget.reserve(60);
+        let mut second = budget.reserve_between(10, 60);
+
+        assert_eq!(second.bytes(), 40);
+        assert_eq!(budget.available(), 0);
+
+        second.shrink_to(30);
+        drop(first);
+
+        assert_eq!(budget.reserved(), 30);
+        assert_eq!(budget.peak(), 100);
+    }
+
+    #[test]
+    fn test_wait_for_release() {
+        let budget = MemoryBudget::new(100);
+        let reservation = budget.reserve(80);
+
+        let waiting = {
+            let budget = budget.clone();
+            thread::spawn(move || budget.reserve(50).bytes())
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(budget.reserved(), 80);
+
+        drop(reservation);
+        assert_eq!(waiting.join().unwrap(), 50);
+        assert_eq!(budget.reserved(), 0);
+    }
+
+    #[test]
+    fn test_oversized_reservation() {
+        let budget = MemoryBudget::new(100);
+
+        // Nothing else is reserved, so waiting would never end
+        let mut reservation = budget.reserve(150);
+        reservation.grow(50);
+
+        assert_eq!(reservation.bytes(), 200);
+    }
+
+    #[test]
+    fn test_reuse_buffer() {
+        let budget = MemoryBudget::new(100);
+
+        let (buffer, reservation) = budget.take_buffer(40);
+        let buffer = Arc::new(buffer);
+        budget.lend(&buffer);
+
+        let address = buffer.as_ptr();
+        let line = Arc::clone(&buffer);
+        drop((buffer, reservation));
+
+        // A line still refers to the buffer
+        assert_ne!(budget.take_buffer(40).0.as_ptr(), address);
+
+        drop(line);
+
+        let (buffer, reservation) = budget.take_buffer(30);
+        assert_eq!(buffer.as_ptr(), address);
+        assert_eq!(reservation.bytes(), 40);
+    }
+
+    #[test]
+    fn test_free_idle_buffers() {
+        let budget = MemoryBudget::new(100);
+
+        let (buffer, reservation) = budget.take_buffer(60);
+        budget.lend(&Arc::new(buffer));
+        drop(reservation);
+
+        // The idle buffer does not fit next to this reservation, so it is freed
+        let reservation = budget.reserve(50);
+        assert_eq!(budget.take_buffer(50).1.bytes(), 50);
+        drop(reservation);
+    }
+}
@@ -1,28 +1,78 @@
-use std::{io::{Read, Write}, rc::Rc};
+use std::{io::{self, ErrorKind, Read, Write}, mem::size_of, sync::Arc};
 
-use memchr::{memrchr_iter, memchr_iter};
+use crc32fast::Hasher;
+use memchr::{memrchr, memchr_iter};
 
-use crate::{line::Line, Configuration};
+use crate::{budget::Reservation, line::Line, Configuration, MissingField};
 
 pub struct Chunk {
     lines: Vec<Line>,
-    current_line: usize
+    current_line: usize,
+
+    /// The number of input bytes covered by this chunk
+    bytes: usize,
+
+    /// Whether this chunk ends at the end of the input
+    last: bool,
+
+    /// Whether the lines of this chunk are already in sorted order
+    sorted: bool,
+
+    /// The number of lines that were left out, because they miss the sort field
+    skipped: usize,
+
+    /// The memory of the buffer and the lines, released when the chunk is dropped
+    reservation: Option<Reservation>
 }
 
 impl Chunk {
+    /// Reads the next chunk of the input
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The input to read from
+    /// * `carry_over` - The incomplete last line of the previous chunk, which is replaced by the one of this chunk
+    /// * `buffer_size` - The size of the buffer of the chunk
+    /// * `lines_before` - The number of input lines before this chunk, to number rejected lines
+    /// * `checksum` - The checksum of the input before this chunk, which is updated with the bytes of this chunk
+    /// * `config` - The configuration that determines the sort field
+    ///
+    /// # Returns
+    ///
+    /// The chunk, `None` at the end of the input, or the error of the input
     pub fn read<R: Read>(
         input: &mut R, 
         carry_over: &mut Vec<u8>,
         buffer_size: usize,
+        lines_before: u64,
+        checksum: &mut Hasher,
         config: &Configuration
-    ) -> Option<Self> {
-        let mut buffer = vec![0; buffer_size];
+    ) -> io::Result<Option<Self>> {
+        // The carry over bytes can be more than a buffer if a line did not fit
+        let (mut buffer, mut reservation) = config.memory.take_buffer(buffer_size.max(carry_over.len()));
     
         // Put the carry over bytes at the beginning of the buffer
         buffer[..carry_over.len()].copy_from_slice(carry_over);
     
-        // Fill the buffer with the next input bytes
-        let (completed, bytes_read) = fill_buffer(input, &mut buffer, carry_over.len());
+        // Fill the buffer with the next input bytes. The buffer grows until it
+        // holds at least a single complete line.
+        let mut offset = carry_over.len();
+        let (completed, bytes_read) = loop {
+            if fill_buffer(input, &mut buffer, offset)? {
+                break (true, buffer.len());
+            }
+
+            // The last line is incomplete, so it is carried over to the next chunk
+            if let Some(newline) = memrchr(b'\n', &buffer) {
+                break (false, newline + 1);
+            }
+
+            offset = buffer.len();
+            let capacity = buffer.capacity();
+
+            buffer.resize((2 * offset).max(1), 0);
+            reservation.grow(buffer.capacity() - capacity);
+        };
     
         // Move the carry over bytes from the end of the buffer to the carry over vector
         carry_over.clear();
@@ -30,43 +80,163 @@ impl Chunk {
             carry_over.extend_from_slice(&buffer[bytes_read..]);
         }
 
-        let buffer = Rc::new(buffer);
+        checksum.update(&buffer[..bytes_read]);
+
+        // The last line of the input can lack its newline
+        let mut line_bytes = bytes_read;
+        if completed && bytes_read > 0 && buffer[bytes_read - 1] != b'\n' {
+            let capacity = buffer.capacity();
+
+            buffer.push(b'\n');
+            reservation.grow(buffer.capacity() - capacity);
+            line_bytes += 1;
+        }
+
+        // The buffer is reused once all lines that refer to it are gone
+        let buffer = Arc::new(buffer);
+        config.memory.lend(&buffer);
     
         // If we read some new bytes
         if bytes_read != 0 {
+            // Every line takes memory of its own next to its bytes in the buffer,
+            // which is only known once the buffer is read
+            let line_count = memchr_iter(b'\n', &buffer[..line_bytes]).count();
+            reservation.grow(line_count * size_of::<Line>());
+
             let mut start_index = 0;
-            let mut lines = Vec::with_capacity(bytes_read);
-
-            for end_index in memchr_iter(b'\n', &buffer[..bytes_read]) {
-                if config.has_field() {
-                    let offset = memchr_iter(config.delimiter, &buffer[start_index..end_index])
-                        .skip(config.field - 2)
-                        .next()
-                        .expect("Fields should be correct");
-
-                    lines.push(Line::new_with_field(Rc::clone(&buffer), start_index, end_index - 1, (start_index + offset, end_index - 1)));
-                } else {
-                    lines.push(Line::new(Rc::clone(&buffer), start_index, end_index - 1));
+            let mut lines = Vec::with_capacity(line_count);
+            let mut skipped = 0;
+
+            for (index, end_index) in memchr_iter(b'\n', &buffer[..line_bytes]).enumerate() {
+                match parse_line(&buffer, start_index, end_index, config) {
+                    Some(line) => lines.push(line),
+                    None => {
+                        if let MissingField::Reject(rejects) = &config.missing_field {
+                            rejects.write(lines_before + index as u64 + 1, &buffer[start_index..end_index]);
+                        }
+
+                        skipped += 1;
+                    }
                 }
 
                 // End index includes the newline
                 start_index = end_index + 1;
             }
 
-            return Some(Chunk { lines, current_line: 0 });
+            // Lines are ordered in reverse, so sorted lines are descending
+            let sorted = lines.windows(2).all(|pair| pair[0] >= pair[1]);
+
+            return Ok(Some(Chunk {
+                lines,
+                current_line: 0,
+                bytes: bytes_read,
+                last: completed,
+                sorted,
+                skipped,
+                reservation: Some(reservation)
+            }));
         }
     
-        None
+        Ok(None)
     }
 
-    pub fn write<W: Write>(&self, writer: &mut W) {
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         for line in &self.lines {
-            line.write(writer);
+            line.write(writer)?;
         }
+
+        Ok(())
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.last
     }
 
-    pub fn sort_unstable(&mut self) {
-        self.lines.sort_unstable_by(|a, b| b.cmp(a));
+    pub fn is_presorted(&self) -> bool {
+        self.sorted
+    }
+
+    /// Returns the number of lines that were left out, because they miss the sort field
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn first_line(&self) -> Option<&Line> {
+        self.lines.first()
+    }
+
+    pub fn last_line(&self) -> Option<&Line> {
+        self.lines.last()
+    }
+
+    /// Returns the number of lines in this chunk
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Returns true if this chunk has no lines
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn line(&self, index: usize) -> Option<&Line> {
+        self.lines.get(index)
+    }
+
+    /// Returns the index of the first line that does not sort before the given
+    /// line. The chunk has to be sorted.
+    pub fn partition_point(&self, line: &Line) -> usize {
+        // Lines are ordered in reverse
+        self.lines.partition_point(|other| other > line)
+    }
+
+    /// Splits the chunk in two at the given line index
+    ///
+    /// # Arguments
+    ///
+    /// * `at` - The index of the first line of the returned chunk
+    ///
+    /// # Returns
+    ///
+    /// A chunk with the lines from `at` onwards
+    pub fn split_off(&mut self, at: usize) -> Chunk {
+        let lines = self.lines.split_off(at);
+
+        // Split the input bytes in proportion to the number of lines
+        let bytes = self.bytes * lines.len() / (self.lines.len() + lines.len()).max(1);
+        self.bytes -= bytes;
+
+        // The buffer is shared, so its memory is split like the input bytes
+        let reservation = self.reservation.as_mut().map(|reservation| {
+            let total = self.bytes + bytes;
+            reservation.split_off((reservation.bytes() as u128 * bytes as u128 / total.max(1) as u128) as usize)
+        });
+
+        let chunk = Chunk { lines, current_line: 0, bytes, last: self.last, sorted: self.sorted, skipped: 0, reservation };
+        self.last = false;
+
+        chunk
+    }
+
+    /// Sorts the lines of the chunk and returns the number of comparisons. Lines
+    /// with equal keys keep the order of the input.
+    pub fn sort(&mut self) -> u64 {
+        let mut comparisons = 0;
+
+        // Presorted chunks are common in concatenations of sorted files
+        if !self.sorted {
+            self.lines.sort_by(|a, b| {
+                comparisons += 1;
+                b.cmp(a)
+            });
+            self.sorted = true;
+        }
+
+        comparisons
     }
 }
 
@@ -83,206 +253,212 @@ impl Iterator for Chunk {
     }
 }
 
+/// Creates a line from the bytes of a buffer
+///
+/// # Arguments
+///
+/// * `buffer` - The buffer that holds the line
+/// * `start_index` - The index of the first byte of the line
+/// * `end_index` - The index of the newline that ends the line
+/// * `config` - The configuration that determines the sort field
+///
+/// # Returns
+///
+/// The line, without its newline, or `None` if the line misses the sort field and is left out
+pub fn parse_line(buffer: &Arc<Vec<u8>>, start_index: usize, end_index: usize, config: &Configuration) -> Option<Line> {
+    // An empty line at the start of the buffer cannot end before index 0,
+    // so empty lines are placed right after their newline instead
+    let (start, end) = if start_index == end_index { (end_index + 1, end_index) } else { (start_index, end_index - 1) };
+
+    if !config.has_field() {
+        return Some(Line::new(Arc::clone(buffer), start, end));
+    }
+
+    let mut delimiters = memchr_iter(config.delimiter, &buffer[start_index..end_index]);
+
+    // A field starts at the delimiter in front of it
+    let key_start = match config.field {
+        0 | 1 => Some(start),
+        field => delimiters.nth(field - 2).map(|offset| start_index + offset)
+    };
+
+    if let Some(key_start) = key_start {
+        // A key with a last field ends in front of the delimiter after that field, or at the end of the line
+        let key_end = config.last_field
+            .and_then(|last_field| delimiters.nth(last_field - config.field.max(1)))
+            .map_or(end + 1, |offset| start_index + offset);
+
+        // Only the first field can be empty, which gets an empty key after the end of the line
+        let key = if key_start < key_end { (key_start, key_end - 1) } else { (end + 1, end) };
+
+        return Some(Line::new_with_field(Arc::clone(buffer), start, end, key));
+    }
+
+    match config.missing_field {
+        // A line without the field has an empty key, so it sorts before all other lines
+        MissingField::First => Some(Line::new_with_field(Arc::clone(buffer), start, end, (end + 1, end))),
+        MissingField::Last  => Some(Line::new_last(Arc::clone(buffer), start, end)),
+        MissingField::Skip | MissingField::Reject(_) => None
+    }
+}
+
+/// Reads input into the buffer from the given offset, until the buffer is full
+/// or the end of the input is reached. The buffer is truncated to the bytes
+/// that were read if the input ends first.
+///
+/// # Returns
+///
+/// True if the end of the input was reached, or the error of the input
 fn fill_buffer<T: Read>(
     input: &mut T,
     buffer: &mut Vec<u8>,
     offset: usize
-) -> (bool, usize) {
-    // Store the buffer size in advance, because rust will complain 
-    // about the buffer being borrowed mutably while it's borrowed
-    let buffer_size = buffer.len();
-
-    // Skip the first {offset} bytes that we still need to read from 
-    // the previous read operation
-    let mut writable_buffer_space = buffer[offset..].as_mut();
-
-    loop {
-        match input.read(writable_buffer_space) {
-            // No bytes written, which means we've completely filled the buffer
-            // or we've reached the end of the file
-            Ok(0) => {
-                // No bytes written and the buffer slice has size zero. This means 
-                // that we've completely filled the buffer
-                if writable_buffer_space.is_empty() {
-                    // Create a very optimized reversed iterator over the newlines
-                    let mut lines_reversed = memrchr_iter(b'\n', buffer);
-
-                    // The last line is incomplete, so we only report the number 
-                    // of bytes that we've read till that last line. We add 1
-                    // because we don't want to keep the newline.
-                    let bytes_read = lines_reversed.next().unwrap() + 1;
-
-                    // Return the number of bytes that we read
-                    return (false, bytes_read);
-                }
-
-                // No bytes written and a non-empty buffer indicates that we've 
-                // reached the end of the file
-                else {
-                    let bytes_read = buffer_size - writable_buffer_space.len();
+) -> io::Result<bool> {
+    let mut filled = offset;
 
-                    // Return the number of bytes that we read
-                    return (true, bytes_read);
-                }
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]) {
+            // No bytes read in a non-empty slice means the end of the input
+            Ok(0) => {
+                buffer.truncate(filled);
+                return Ok(true);
             },
 
-            // We've read {bytes_read} bytes
-            Ok(bytes_read) => {
-                // Shrink the writable buffer slice
-                writable_buffer_space = writable_buffer_space[bytes_read..].as_mut();
-            },
+            Ok(bytes_read) => filled += bytes_read,
 
-            Err(err) => {
-                panic!("Error while reading input: {}", err);
-            }
+            // The read was interrupted before it read anything, so it is tried again
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+
+            Err(err) => return Err(err)
         }
     }
+
+    Ok(false)
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use std::rc::Rc;
-
-//     use crate::{line::Line, chunk::Chunk};
-
-//     const BUFFER_STRING: &str = "AAAALTER\nAAA\nAAAA\nAAAALTER\nAAAALTERRR\nCAAAALTER\n";
-
-//     fn new_chunk() -> Chunk {
-//         let buffer = Rc::new(BUFFER_STRING.as_bytes().to_vec());
-
-//         let line1 = Line::new(Rc::clone(&buffer), 0, 7);
-//         let line2 = Line::new(Rc::clone(&buffer), 9, 11);
-//         let line3 = Line::new(Rc::clone(&buffer), 13, 16);
-//         let line4 = Line::new(Rc::clone(&buffer), 18, 25);
-//         let line5 = Line::new(Rc::clone(&buffer), 27, 36);
-//         let line6 = Line::new(Rc::clone(&buffer), 38, 46);
-
-//         Chunk { lines: vec![ line1, line2, line3, line4, line5, line6 ], current_line: 0 }
-//     }
-
-//     #[test]
-//     fn test_chunk_read_sufficient_buffer() {
-//         // TODO: add a test where a line is too big to fit in the buffer
-//         let mut carry_over = vec![];
-//         let mut input = BUFFER_STRING.as_bytes();
-
-//         let chunk = Chunk::read(&mut input, &mut carry_over, 32).unwrap();
-//         assert_eq!(chunk.lines.len(), 4);
-//         assert_eq!(chunk.lines[0].as_bytes(), "AAAALTER".as_bytes());
-//         assert_eq!(chunk.lines[1].as_bytes(), "AAA".as_bytes());
-//         assert_eq!(chunk.lines[2].as_bytes(), "AAAA".as_bytes());
-//         assert_eq!(chunk.lines[3].as_bytes(), "AAAALTER".as_bytes());
+#[cfg(test)]
+mod tests {
+    use crate::{reject::SharedBuffer, RejectFile};
+
+    use super::*;
+
+    const BUFFER_STRING: &str = "AAAALTER\nAAA\nAAAA\nAAAALTER\nAAAALTERRR\nCAAAALTER\n";
+
+    fn contents(chunk: &Chunk) -> Vec<String> {
+        chunk.lines
+            .iter()
+            .map(|line| {
+                let mut output = vec![];
+                line.write(&mut output).unwrap();
+                String::from_utf8(output).unwrap().trim_end().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_read_sufficient_buffer() {
+        let config = Configuration::default();
+        let mut carry_over = vec![];
+        let mut input = BUFFER_STRING.as_bytes();
+
+        let chunk = Chunk::read(&mut input, &mut carry_over, 32, 0, &mut Hasher::new(), &config).unwrap().unwrap();
+        assert_eq!(contents(&chunk), vec!["AAAALTER", "AAA", "AAAA", "AAAALTER"]);
+        assert!(!chunk.is_last());
+
+        let chunk = Chunk::read(&mut input, &mut carry_over, 32, 0, &mut Hasher::new(), &config).unwrap().unwrap();
+        assert_eq!(contents(&chunk), vec!["AAAALTERRR", "CAAAALTER"]);
+        assert!(chunk.is_last());
 
-//         let chunk = Chunk::read(&mut input, &mut carry_over, 32).unwrap();
-//         assert_eq!(chunk.lines.len(), 2);
-//         assert_eq!(chunk.lines[0].as_bytes(), "AAAALTERRR".as_bytes());
-//         assert_eq!(chunk.lines[1].as_bytes(), "CAAAALTER".as_bytes());
-
-//         assert!(carry_over.is_empty());
-//     }
+        assert!(carry_over.is_empty());
+    }
+
+    #[test]
+    fn test_chunk_read_insufficient_buffer() {
+        let config = Configuration::default();
+        let mut carry_over = vec![];
+        let mut input = BUFFER_STRING.as_bytes();
 
-//     #[test]
-//     fn test_chunk_read_insufficient_buffer() {
-//         unimplemented!()
-//     }
-
-//     #[test]
-//     fn test_chunk_len() {
-//         let chunk = new_chunk();
-//         assert_eq!(chunk.len(), 6);
-//     }
-
-//     #[test]
-//     fn test_chunk_next_line() {
-//         let mut chunk = new_chunk();
-
-//         assert_eq!(chunk.next_line().as_bytes(), "AAAALTER".as_bytes());
-//         assert_eq!(chunk.next_line().as_bytes(), "AAA".as_bytes());
-//         assert_eq!(chunk.next_line().as_bytes(), "AAAA".as_bytes());
-//         assert_eq!(chunk.next_line().as_bytes(), "AAAALTER".as_bytes());
-//         assert_eq!(chunk.next_line().as_bytes(), "AAAALTERRR".as_bytes());
-//         assert_eq!(chunk.next_line().as_bytes(), "CAAAALTER".as_bytes());
-//     }
-
-//     #[test]
-//     fn test_chunk_is_empty() {
-//         let non_empty_chunk = new_chunk();
-//         assert_eq!(non_empty_chunk.is_empty(), false);
-
-//         let empty_chunk = Chunk { lines: vec![], current_line: 0 };
-//         assert_eq!(empty_chunk.is_empty(), true);
-//     }
-
-//     #[test]
-//     fn test_chunk_sort_unstable() {
-//         let mut chunk = new_chunk();
-
-//         chunk.sort_unstable();
-
-//         assert_eq!(chunk.lines[0].as_bytes(), "AAA".as_bytes());
-//         assert_eq!(chunk.lines[1].as_bytes(), "AAAA".as_bytes());
-//         assert_eq!(chunk.lines[2].as_bytes(), "AAAALTER".as_bytes());
-//         assert_eq!(chunk.lines[3].as_bytes(), "AAAALTER".as_bytes());
-//         assert_eq!(chunk.lines[4].as_bytes(), "AAAALTERRR".as_bytes());
-//         assert_eq!(chunk.lines[5].as_bytes(), "CAAAALTER".as_bytes());
-//     }
-
-//     // #[test]
-//     // fn test_chunk_cmp() {
-//     //     let buffer = Rc::new(BUFFER_STRING.as_bytes().to_vec());
-
-//     //     let line1 = Line::new(Rc::clone(&buffer), 0, 8);
-//     //     let line2 = Line::new(Rc::clone(&buffer), 9, 12);
-//     //     let line3 = Line::new(Rc::clone(&buffer), 13, 17);
-//     //     let line4 = Line::new(Rc::clone(&buffer), 18, 26);
-//     //     let line5 = Line::new(Rc::clone(&buffer), 27, 37);
-//     //     let line6 = Line::new(Rc::clone(&buffer), 38, 47);
-
-//     //     let mut chunk1 = Chunk { lines: vec![ line1, line2, line3 ], current_line: 0 };
-//     //     let mut chunk2 = Chunk { lines: vec![ line4, line5, line6 ], current_line: 0 };
-
-//     //     assert_eq!(chunk1.cmp(&chunk2), std::cmp::Ordering::Equal);
-//     //     assert_eq!(chunk2.cmp(&chunk1), std::cmp::Ordering::Equal);
-
-//     //     chunk1.next();
-
-//     //     assert_eq!(chunk1.cmp(&chunk2), std::cmp::Ordering::Less);
-//     //     assert_eq!(chunk2.cmp(&chunk1), std::cmp::Ordering::Greater);
-
-//     //     chunk1.next();
-//     //     chunk2.next();
-//     //     chunk2.next();
-
-//     //     assert_eq!(chunk1.cmp(&chunk2), std::cmp::Ordering::Less);
-//     //     assert_eq!(chunk2.cmp(&chunk1), std::cmp::Ordering::Greater);
-//     // }
-// }
-
-// // #[cfg(test)]
-// // mod tests {
-// //     use std::str::from_utf8;
-
-// //     use crate::line;
-
-// //     use super::{Chunk, ChunkData};
-
-// //     fn new_chunk(amount_of_lines: usize) -> Chunk {
-// //         let buffer = (1..=amount_of_lines).map(|i| format!("line{}\n", i)).collect::<String>().as_bytes().to_vec();
-
-// //         Chunk::new(buffer, |buffer| {
-// //             let lines_str = from_utf8(&buffer).unwrap();    
-// //             let lines = line::lines(lines_str).collect();
-
-// //             ChunkData { lines, current_line: 0 }
-// //         })
-// //     }
-
-// //     #[test]
-// //     fn test_chunk_next_line() {
-// //         let mut chunk = new_chunk(3);
-
-// //         assert_eq!(chunk.next_line().content, "line1");
-// //         assert_eq!(chunk.next_line().content, "line2");
-// //         assert_eq!(chunk.next_line().content, "line3");
-// //     }
-// // }
+        // The buffer grows until it holds a complete line
+        let mut lines = vec![];
+        while let Some(chunk) = Chunk::read(&mut input, &mut carry_over, 4, 0, &mut Hasher::new(), &config).unwrap() {
+            lines.extend(contents(&chunk));
+        }
+
+        assert_eq!(lines, vec!["AAAALTER", "AAA", "AAAA", "AAAALTER", "AAAALTERRR", "CAAAALTER"]);
+    }
+
+    #[test]
+    fn test_chunk_read_last_line_without_newline() {
+        let chunk = Chunk::read(&mut "B\nA".as_bytes(), &mut vec![], 32, 0, &mut Hasher::new(), &Configuration::default()).unwrap().unwrap();
+
+        assert_eq!(contents(&chunk), vec!["B", "A"]);
+        assert_eq!(chunk.bytes(), 3);
+    }
+
+    fn sort_missing_fields(missing_field: MissingField) -> (Vec<String>, usize) {
+        let config = Configuration { field: 2, delimiter: b',', missing_field, ..Configuration::default() };
+        let mut chunk = Chunk::read(&mut "a,2\nb\n\nc,1\n".as_bytes(), &mut vec![], 32, 10, &mut Hasher::new(), &config).unwrap().unwrap();
+
+        chunk.sort();
+
+        (contents(&chunk), chunk.skipped())
+    }
+
+    #[test]
+    fn test_chunk_read_missing_field() {
+        let (lines, _) = sort_missing_fields(MissingField::First);
+        assert_eq!(lines[2..], ["c,1", "a,2"]);
+
+        let (lines, _) = sort_missing_fields(MissingField::Last);
+        assert_eq!(lines[..2], ["c,1", "a,2"]);
+
+        assert_eq!(sort_missing_fields(MissingField::Skip), (vec!["c,1".to_string(), "a,2".to_string()], 2));
+
+        // Rejected lines are numbered after the lines before the chunk
+        let buffer = SharedBuffer::default();
+        assert_eq!(sort_missing_fields(MissingField::Reject(RejectFile::new(buffer.clone()))).1, 2);
+        assert_eq!(buffer.content(), b"12\tb\n13\t\n");
+    }
+
+    fn read_fields(input: &str, field: usize, last_field: Option<usize>) -> Chunk {
+        let config = Configuration { field, last_field, ..Configuration::default() };
+        Chunk::read(&mut input.as_bytes(), &mut vec![], 64, 0, &mut Hasher::new(), &config).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_chunk_read_field_range() {
+        // The key of a single field ends at the next delimiter, so the columns after it do not count
+        let chunk = read_fields("b\t1\tz\na\t1\ty\nc\t0\n", 2, Some(2));
+        assert_eq!(chunk.line(0), chunk.line(1));
+        assert!(chunk.line(2) > chunk.line(0));
+
+        let mut chunk = read_fields("b\t1\tz\na\t1\ty\nc\t0\n", 2, Some(2));
+        chunk.sort();
+        assert_eq!(contents(&chunk)[0], "c\t0");
+
+        // A range of fields ends with its last field, or at the end of a line that is shorter
+        let chunk = read_fields("b\t1\tz\td\na\t1\tz\tc\nc\t1\n", 2, Some(3));
+        assert_eq!(chunk.line(0), chunk.line(1));
+        assert!(chunk.line(2) > chunk.line(0));
+
+        // The first field alone, where an empty first field sorts before the others
+        let mut chunk = read_fields("b\t1\na\t2\n\t3\na\t0\n", 1, Some(1));
+        assert_eq!(chunk.line(1), chunk.line(3));
+        chunk.sort();
+        assert_eq!(contents(&chunk)[0], "\t3");
+        assert_eq!(contents(&chunk)[1..3], ["a\t2", "a\t0"]);
+        assert_eq!(contents(&chunk)[3], "b\t1");
+    }
+
+    #[test]
+    fn test_chunk_sort() {
+        let config = Configuration::default();
+        let mut chunk = Chunk::read(&mut BUFFER_STRING.as_bytes(), &mut vec![], 64, 0, &mut Hasher::new(), &config).unwrap().unwrap();
+
+        assert!(chunk.sort() > 0);
+        assert_eq!(contents(&chunk), vec!["AAA", "AAAA", "AAAALTER", "AAAALTER", "AAAALTERRR", "CAAAALTER"]);
+
+        // A sorted chunk is not sorted again
+        assert_eq!(chunk.sort(), 0);
+    }
+}
@@ -1,6 +1,9 @@
-use std::io::Read;
+use std::io::{self, Read, Write};
 
-use crate::Configuration;
+use crc32fast::Hasher;
+use memchr::memchr_iter;
+
+use crate::{Configuration, MissingField};
 
 use super::chunk::Chunk;
 
@@ -8,7 +11,19 @@ pub struct Chunks<R: Read> {
     input: R,
     carry_over: Vec<u8>,
     buffer_size: usize,
-    config: Configuration
+    config: Configuration,
+
+    /// The number of input bytes that were handed out in chunks
+    offset: u64,
+
+    /// The number of input lines that were handed out in chunks, or left out of them
+    lines: u64,
+
+    /// The checksum of the input bytes that were handed out in chunks
+    checksum: Hasher,
+
+    /// Whether the end of the input has been reached
+    exhausted: bool
 }
 
 impl<R: Read> Chunks<R> {
@@ -17,15 +32,104 @@ impl<R: Read> Chunks<R> {
             input,
             carry_over: vec![],
             buffer_size,
-            config
+            config,
+            offset: 0,
+            lines: 0,
+            checksum: Hasher::new(),
+            exhausted: false
         }
     }
+
+    /// Creates a chunk iterator that starts at the given byte offset of the
+    /// input. All bytes before this offset are read and discarded, which fails
+    /// if the input cannot be read. An input that ends before the offset is
+    /// read up to its end, so the offset of the iterator tells where it starts.
+    pub fn new_at_offset(mut input: R, offset: u64, buffer_size: usize, config: Configuration) -> io::Result<Self> {
+        // The lines are counted, so the lines after them keep their line numbers
+        let mut skipped = SkippedInput { lines: 0, checksum: Hasher::new() };
+        let offset = io::copy(&mut (&mut input).take(offset), &mut skipped)?;
+
+        Ok(Chunks { offset, lines: skipped.lines, checksum: skipped.checksum, ..Chunks::new(input, buffer_size, config) })
+    }
+
+    /// Returns the number of input bytes that were handed out in chunks
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the CRC32 checksum of the input bytes that were handed out in chunks
+    pub fn checksum(&self) -> u32 {
+        self.checksum.clone().finalize()
+    }
+
+    /// Returns the size of the buffer of a chunk
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Returns true if no chunks are left
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
 }
 
 impl<R: Read> Iterator for Chunks<R> {
-    type Item = Chunk;
+    type Item = io::Result<Chunk>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        Chunk::read(&mut self.input, &mut self.carry_over, self.buffer_size, &self.config)
+        if self.exhausted {
+            return None;
+        }
+
+        // The rest of the input is not read once its rejects cannot be written,
+        // the sort returns the error when it flushes the reject file
+        if let MissingField::Reject(rejects) = &self.config.missing_field {
+            if rejects.failed() {
+                self.exhausted = true;
+                return None;
+            }
+        }
+
+        let chunk = Chunk::read(&mut self.input, &mut self.carry_over, self.buffer_size, self.lines, &mut self.checksum, &self.config);
+
+        match &chunk {
+            Ok(Some(chunk)) => {
+                let lines = (chunk.len() + chunk.skipped()) as u64;
+
+                self.offset += chunk.bytes() as u64;
+                self.lines += lines;
+                self.config.progress.add_bytes(chunk.bytes() as u64);
+                self.config.stats.add_input(chunk.bytes() as u64, lines);
+                self.config.stats.add_skipped(chunk.skipped() as u64);
+                self.exhausted = chunk.is_last();
+
+                // The rejects of a chunk are written while it is read
+                if let MissingField::Reject(rejects) = &self.config.missing_field {
+                    rejects.mark(self.offset);
+                }
+            },
+            // Nothing is read after an error, the lines after it would be out of place
+            Ok(None) | Err(_) => self.exhausted = true
+        }
+
+        chunk.transpose()
+    }
+}
+
+/// A writer that only counts the newlines it is given and checksums its bytes
+struct SkippedInput {
+    lines: u64,
+    checksum: Hasher
+}
+
+impl Write for SkippedInput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lines += memchr_iter(b'\n', buf).count() as u64;
+        self.checksum.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
@@ -1,6 +1,7 @@
+#[allow(clippy::module_inception)]
 mod chunk;
 mod iter;
 
-pub use chunk::Chunk;
+pub use chunk::{Chunk, parse_line};
 
 pub use iter::Chunks;
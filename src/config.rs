@@ -1,17 +1,136 @@
-use bytesize::MB;
+use bytesize::{ByteSize, KIB, MB};
+
+use crate::{system, MemoryBudget, Progress, RejectFile, StatsCollector};
 
 #[derive(Clone)]
 pub struct Configuration {
     pub threads: usize,
     pub buffer_size: usize,
-    pub chunk_size: usize,
+
+    /// The number of runs that are merged at once, computed from the buffer size if not set
+    pub chunk_size: Option<usize>,
+
+    /// The smallest buffer a run gets while it is merged, which limits the fan-in
+    pub min_stream_buffer: usize,
+
+    /// Explain what the sort does on stderr
+    pub verbose: bool,
+
+    /// The memory that all buffers of the sort share. `external_sort` limits an
+    /// unlimited budget to the buffer size, a limited budget can be shared by
+    /// several sorts.
+    pub memory: MemoryBudget,
+
+    /// The progress of the sort, which is reported on stderr if it is enabled
+    pub progress: Progress,
+
+    /// Collects the statistics that `external_sort` returns
+    pub stats: StatsCollector,
+
     pub delimiter: u8,
-    pub field: usize // Maybe multiple fields in the future
+    pub field: usize,
+
+    /// The last field of the sort key, which runs until the end of the line if it is not set
+    pub last_field: Option<usize>,
+
+    /// What happens to lines that have fewer fields than the field they are sorted on
+    pub missing_field: MissingField,
+
+    pub run_generation: RunGeneration
+}
+
+/// What happens to a line that has fewer fields than the field it is sorted on.
+/// Merges parse their lines again, so they order them in the same way.
+#[derive(Clone, Default)]
+pub enum MissingField {
+    /// The line gets an empty key, so it sorts before the other lines
+    #[default]
+    First,
+
+    /// The line sorts after all lines that have the field
+    Last,
+
+    /// The line is left out of the output
+    Skip,
+
+    /// The line is left out of the output and written to the reject file instead
+    Reject(RejectFile)
+}
+
+impl MissingField {
+    /// Returns the name of the policy, as it is given on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            MissingField::First     => "first",
+            MissingField::Last      => "last",
+            MissingField::Skip      => "skip",
+            MissingField::Reject(_) => "reject"
+        }
+    }
+}
+
+/// The strategy used to turn the input into sorted runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunGeneration {
+    /// Sort chunks of the input in parallel, every run is as large as a single chunk
+    Chunks,
+
+    /// Stream the input through a tournament tree, which produces runs of about
+    /// twice the buffer size on random input and a single run on sorted input
+    ReplacementSelection
 }
 
 impl Configuration {
+    /// Creates the default configuration with as many threads as the process can
+    /// run at once and a buffer of half of the memory that is available to it
+    pub fn auto() -> Self {
+        Configuration {
+            threads: system::available_threads(),
+            buffer_size: system::default_buffer_size(),
+            ..Configuration::default()
+        }
+    }
+
+    /// Returns the part of the buffer that every thread sorts or merges with
+    pub fn thread_buffer_size(&self) -> usize {
+        self.buffer_size / self.threads.max(1)
+    }
+
+    /// Checks that the buffer leaves every thread enough memory to merge at
+    /// least two runs, or the requested number of runs, at once
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threads == 0 {
+            return Err("At least one thread is needed".to_string());
+        }
+
+        if self.last_field.is_some_and(|last_field| last_field < self.field.max(1)) {
+            return Err(format!("The last field of the key cannot come before its first field {}", self.field.max(1)));
+        }
+
+        if self.chunk_size.is_some_and(|fan_in| fan_in < 2) {
+            return Err("The fan-in has to be at least 2".to_string());
+        }
+
+        // Every run that is merged and the output get a stream buffer
+        let fan_in = self.chunk_size.unwrap_or(2);
+        let needed = (fan_in + 1).saturating_mul(self.min_stream_buffer);
+
+        if self.thread_buffer_size() < needed {
+            return Err(format!(
+                "A buffer of {} is too small for {} threads with a fan-in of {}, it needs at least {}",
+                ByteSize(self.buffer_size as u64),
+                self.threads,
+                fan_in,
+                ByteSize(needed.saturating_mul(self.threads) as u64)
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if lines are sorted on a part of the line instead of the whole line
     pub fn has_field(&self) -> bool {
-        self.field > 1
+        self.field > 1 || self.last_field.is_some()
     }
 }
 
@@ -20,9 +139,34 @@ impl Default for Configuration {
         Configuration {
             threads: 4,
             buffer_size: 400 * MB as usize,
-            chunk_size: 16,
+            chunk_size: None,
+            min_stream_buffer: 256 * KIB as usize,
+            verbose: false,
+            memory: MemoryBudget::unlimited(),
+            progress: Progress::disabled(),
+            stats: StatsCollector::default(),
             delimiter: b'\t',
-            field: 1
+            field: 1,
+            last_field: None,
+            missing_field: MissingField::default(),
+            run_generation: RunGeneration::Chunks
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config = Configuration { threads: 4, buffer_size: 4 * MB as usize, ..Configuration::default() };
+
+        assert!(config.validate().is_ok());
+        assert!(Configuration { threads: 16, ..config.clone() }.validate().is_err());
+        assert!(Configuration { chunk_size: Some(8), ..config.clone() }.validate().is_err());
+        assert!(Configuration { threads: 0, ..config.clone() }.validate().is_err());
+        assert!(Configuration { field: 2, last_field: Some(2), ..config.clone() }.validate().is_ok());
+        assert!(Configuration { field: 3, last_field: Some(2), ..config }.validate().is_err());
+    }
+}
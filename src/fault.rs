@@ -0,0 +1,329 @@
+//! Readers, writers and a temporary storage that run into I/O faults at chosen
+//! offsets, to test how the sort handles interrupted calls and failing disks
+
+use std::{collections::HashMap, fs::read_dir, io::{self, ErrorKind, Read, Write}, panic::{catch_unwind, AssertUnwindSafe}};
+
+use crate::tempfile::{TmpDir, TmpDirBuilder, TmpStorage, TmpFileOpened, TmpFileClosed, TmpFileWrite, TmpFileRead};
+
+/// A fault that a read or write runs into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The call is interrupted by a signal before it transfers any bytes (`EINTR`)
+    Interrupted,
+
+    /// The call transfers a single byte
+    Short,
+
+    /// The disk is full (`ENOSPC`), every later call fails as well
+    NoSpace,
+
+    /// The device fails (`EIO`), every later call fails as well
+    Io
+}
+
+impl Fault {
+    fn error(self) -> io::Error {
+        match self {
+            Fault::Interrupted => io::Error::new(ErrorKind::Interrupted, "Interrupted system call"),
+            Fault::Short       => unreachable!(),
+            Fault::NoSpace     => io::Error::new(ErrorKind::StorageFull, "No space left on device"),
+            Fault::Io          => io::Error::other("Input/output error")
+        }
+    }
+}
+
+/// The faults of a single reader or writer, by the offset in its bytes at which they happen
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// The faults that did not happen yet, ordered by their offset
+    pending: Vec<(u64, Fault)>,
+
+    /// The fault that every call runs into, once the disk is full or failed
+    failed: Option<Fault>,
+
+    /// The number of bytes that were read or written
+    position: u64
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Faults::default()
+    }
+
+    /// Adds a fault that happens once the given number of bytes is read or written
+    pub fn at(mut self, offset: u64, fault: Fault) -> Self {
+        self.pending.push((offset, fault));
+        self.pending.sort_by_key(|(offset, _)| *offset);
+        self
+    }
+
+    /// Returns the faults of a reader that starts at the given offset, which
+    /// never runs into the faults before it
+    fn starting_at(mut self, offset: u64) -> Self {
+        self.pending.retain(|(fault_offset, _)| *fault_offset >= offset);
+        self.position = offset;
+        self
+    }
+
+    /// Transfers at most `len` bytes with the given call, unless it runs into a fault
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - The number of bytes the caller asked for
+    /// * `transfer` - Reads or writes at most the given number of bytes
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes that were transferred, or the error of the fault
+    fn transfer(&mut self, len: usize, transfer: impl FnOnce(usize) -> io::Result<usize>) -> io::Result<usize> {
+        if let Some(fault) = self.failed {
+            return Err(fault.error());
+        }
+
+        let mut len = len;
+
+        match self.pending.first().copied() {
+            Some((offset, fault)) if offset <= self.position => {
+                self.pending.remove(0);
+
+                match fault {
+                    Fault::Interrupted       => return Err(fault.error()),
+                    Fault::Short             => len = len.min(1),
+                    Fault::NoSpace | Fault::Io => {
+                        self.failed = Some(fault);
+                        return Err(fault.error());
+                    }
+                }
+            },
+
+            // Stop in front of the fault, so the next call runs into it at its exact offset
+            Some((offset, _)) => len = len.min((offset - self.position).try_into().unwrap_or(usize::MAX)),
+
+            None => {}
+        }
+
+        let bytes = transfer(len)?;
+        self.position += bytes as u64;
+
+        Ok(bytes)
+    }
+}
+
+/// A reader, writer or temporary file that runs into faults
+pub struct Faulty<T> {
+    inner: T,
+
+    /// The faults of reading or writing this stream
+    faults: Faults,
+
+    /// The faults of reading a temporary file, every time it is opened again
+    reads: Faults
+}
+
+impl<T> Faulty<T> {
+    pub fn new(inner: T, faults: Faults) -> Self {
+        Faulty { inner, faults, reads: Faults::default() }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Faulty<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.faults.transfer(buf.len(), |len| inner.read(&mut buf[..len]))
+    }
+}
+
+impl<W: Write> Write for Faulty<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.faults.transfer(buf.len(), |len| inner.write(&buf[..len]))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(fault) = self.faults.failed {
+            return Err(fault.error());
+        }
+
+        self.inner.flush()
+    }
+}
+
+impl<F: TmpFileOpened> TmpFileOpened for Faulty<F> {
+    type Closed = Faulty<F::Closed>;
+
+    fn close(self) -> io::Result<Self::Closed> {
+        Ok(Faulty { inner: self.inner.close()?, faults: Faults::default(), reads: self.reads })
+    }
+}
+
+impl<C: TmpFileClosed> TmpFileClosed for Faulty<C> {
+    type Reopened = Faulty<C::Reopened>;
+
+    fn reopen(self) -> io::Result<Self::Reopened> {
+        Ok(Faulty { inner: self.inner.reopen()?, faults: self.reads.clone(), reads: self.reads })
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn read_from(&self, offset: u64) -> io::Result<Self::Reopened> {
+        Ok(Faulty { inner: self.inner.read_from(offset)?, faults: self.reads.clone().starting_at(offset), reads: self.reads.clone() })
+    }
+
+    fn remove(self) {
+        self.inner.remove();
+    }
+}
+
+impl<W: TmpFileWrite> TmpFileWrite for Faulty<W> {}
+
+impl<R: TmpFileRead> TmpFileRead for Faulty<R> {
+    fn close_and_remove(self) {
+        self.inner.close_and_remove();
+    }
+}
+
+/// A storage whose files run into faults while they are written or read. It
+/// does not record the progress of a sort, so it cannot be resumed.
+pub struct FaultyStorage<S> {
+    inner: S,
+
+    /// The number of files that were created
+    files: usize,
+
+    /// The faults of writing and reading files, by the number of the file in order of creation
+    writes: HashMap<usize, Faults>,
+    reads: HashMap<usize, Faults>
+}
+
+impl<S: TmpStorage> FaultyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        FaultyStorage { inner, files: 0, writes: HashMap::new(), reads: HashMap::new() }
+    }
+
+    /// Injects faults in the writes of the given file, the first file that is created is file 0
+    pub fn with_write_faults(mut self, file: usize, faults: Faults) -> Self {
+        self.writes.insert(file, faults);
+        self
+    }
+
+    /// Injects faults in every read of the given file, the first file that is created is file 0
+    pub fn with_read_faults(mut self, file: usize, faults: Faults) -> Self {
+        self.reads.insert(file, faults);
+        self
+    }
+
+    /// Returns the number of files that were created
+    pub fn files(&self) -> usize {
+        self.files
+    }
+}
+
+impl<S: TmpStorage> TmpStorage for FaultyStorage<S> {
+    type Writer = Faulty<S::Writer>;
+    type Closed = Faulty<S::Closed>;
+    type Reader = Faulty<S::Reader>;
+
+    fn create_new_file(&mut self) -> io::Result<Self::Writer> {
+        let file = self.files;
+        self.files += 1;
+
+        Ok(Faulty {
+            inner: self.inner.create_new_file()?,
+            faults: self.writes.remove(&file).unwrap_or_default(),
+            reads: self.reads.remove(&file).unwrap_or_default()
+        })
+    }
+
+    fn file_buffer_size(&self) -> usize {
+        self.inner.file_buffer_size()
+    }
+}
+
+/// Runs a sort on a temporary directory in a new location and asserts that the
+/// sort returns the error of the given fault, without panicking or leaving any
+/// of its files behind
+///
+/// # Arguments
+///
+/// * `fault` - The fault that the sort runs into, which cannot be one that is retried
+/// * `sort` - Sorts on the given temporary directory, which it owns so it is dropped when the sort fails
+pub fn assert_fails_cleanly(fault: Fault, sort: impl FnOnce(TmpDir) -> io::Result<()>) {
+    assert!(matches!(fault, Fault::NoSpace | Fault::Io), "A sort does not fail on {:?} faults", fault);
+
+    let location = tempfile::tempdir().unwrap();
+    let location_path = location.path().to_path_buf();
+
+    let result = catch_unwind(AssertUnwindSafe(|| sort(TmpDirBuilder::new().with_location(&location_path).build())));
+
+    match result {
+        Ok(Err(err)) => assert_eq!(err.kind(), fault.error().kind(), "The sort failed with another error: {}", err),
+        Ok(Ok(()))   => panic!("The sort did not fail"),
+        Err(_)       => panic!("The sort panicked instead of returning an error")
+    }
+
+    assert_eq!(read_dir(location.path()).unwrap().count(), 0, "The sort left files behind");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tempfile::MemoryStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_interrupted_and_short_reads() {
+        let faults = Faults::new().at(0, Fault::Interrupted).at(3, Fault::Short).at(3, Fault::Interrupted);
+        let mut reader = Faulty::new(&b"AAALTER\n"[..], faults);
+        let mut buffer = [0; 8];
+
+        assert_eq!(reader.read(&mut buffer).unwrap_err().kind(), ErrorKind::Interrupted);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 3);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 1);
+        assert_eq!(reader.read(&mut buffer).unwrap_err().kind(), ErrorKind::Interrupted);
+
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"TER\n");
+    }
+
+    #[test]
+    fn test_full_disk_keeps_failing() {
+        let mut writer = Faulty::new(vec![], Faults::new().at(5, Fault::NoSpace));
+
+        assert_eq!(writer.write_all(b"AAALTER\n").unwrap_err().kind(), ErrorKind::StorageFull);
+        assert_eq!(writer.write(b"A").unwrap_err().kind(), ErrorKind::StorageFull);
+        assert!(writer.flush().is_err());
+
+        assert_eq!(writer.into_inner(), b"AAALT");
+    }
+
+    #[test]
+    fn test_storage_faults() {
+        let mut storage = FaultyStorage::new(MemoryStorage::new())
+            .with_write_faults(0, Faults::new().at(2, Fault::Interrupted))
+            .with_read_faults(1, Faults::new().at(4, Fault::Io));
+
+        let mut first = storage.create_new_file().unwrap();
+        let mut second = storage.create_new_file().unwrap();
+        first.write_all(b"AAALTER\n").unwrap();
+        second.write_all(b"AAALTER\n").unwrap();
+
+        let mut content = vec![];
+        first.close().unwrap().reopen().unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"AAALTER\n");
+
+        let second = second.close().unwrap();
+        assert_eq!(second.read_from(0).unwrap().read_to_end(&mut vec![]).unwrap_err().kind(), ErrorKind::Other);
+
+        // A part of the file after the failing offset can still be read
+        let mut content = vec![];
+        second.read_from(5).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"ER\n");
+    }
+}
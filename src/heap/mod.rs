@@ -46,12 +46,15 @@ pub struct WinnerHeap<T: Ord> {
 
     pub leaves: Vec<Leaf<T>>,
 
-    pub last_pop: Option<usize>
+    pub last_pop: Option<usize>,
+
+    /// The number of comparisons between items so far
+    pub comparisons: u64
 }
 
 impl<T: Ord> WinnerHeap<T> {
     pub fn new(items: Vec<T>) -> Self {
-        if items.len() == 0 {
+        if items.is_empty() {
             return Self::default();
         }
 
@@ -62,10 +65,13 @@ impl<T: Ord> WinnerHeap<T> {
         // Keep track of the amount of internal nodes.
         let internal_size = internal.len();
 
+        let mut comparisons = 0;
+
         let mut lower_bound = internal_size / 2;
         let mut upper_bound = internal_size;
 
         // Populate the lowest depth of the internal structure.
+        #[allow(clippy::needless_range_loop)]
         for i in lower_bound..upper_bound {
             let left_index = 2 * i + 1 - internal_size;
             let right_index = 2 * i + 2 - internal_size;
@@ -75,6 +81,7 @@ impl<T: Ord> WinnerHeap<T> {
 
             internal[i] = match (left_leaf, right_leaf) {
                 (Some(leaf1), Some(leaf2)) => {
+                    comparisons += 1;
                     if leaf1 > leaf2 { Some(left_index) } else { Some(right_index) }
                 },
                 (Some(_), None)            => Some(left_index),
@@ -85,7 +92,7 @@ impl<T: Ord> WinnerHeap<T> {
 
         // Keep track of the boundaries on each level of the internal structure.
         upper_bound = lower_bound;
-        lower_bound = lower_bound / 2;
+        lower_bound /= 2;
 
         // Populate the other levels of the internal structure.
         while upper_bound > 0 {
@@ -95,6 +102,7 @@ impl<T: Ord> WinnerHeap<T> {
 
                 internal[i] = match (left, right) {
                     (Some(leaf1), Some(leaf2)) => {
+                        comparisons += 1;
                         if leaves[leaf1] > leaves[leaf2] { left } else { right }
                     },
                     (Some(_), None)            => left,
@@ -104,10 +112,10 @@ impl<T: Ord> WinnerHeap<T> {
             }
 
             upper_bound = lower_bound;
-            lower_bound = lower_bound / 2;
+            lower_bound /= 2;
         }
 
-        Self { internal, internal_size, leaves, last_pop: None }
+        Self { internal, internal_size, leaves, last_pop: None, comparisons }
     }
 
     pub fn pop(&mut self) -> Option<T> {
@@ -115,7 +123,7 @@ impl<T: Ord> WinnerHeap<T> {
             self.update(pos);
         }
 
-        if let Some(pos) = self.internal[0] {
+        if let Some(Some(pos)) = self.internal.first().copied() {
             self.last_pop = Some(pos);
             return self.leaves[pos].take_value();
         }
@@ -142,6 +150,7 @@ impl<T: Ord> WinnerHeap<T> {
 
         self.internal[parent] = match (left, right) {
             (Some(leaf1), Some(leaf2)) => Some({
+                self.comparisons += 1;
                 if leaf1 > leaf2 { left_index } else { right_index }
             }),
             (Some(_), None)            => Some(left_index),
@@ -157,6 +166,7 @@ impl<T: Ord> WinnerHeap<T> {
 
             self.internal[parent] = match (left, right) {
                 (Some(leaf1), Some(leaf2)) => {
+                    self.comparisons += 1;
                     if self.leaves[leaf1] > self.leaves[leaf2] { left } else { right }
                 },
                 (Some(_), None)            => left,
@@ -175,7 +185,8 @@ impl<T: Ord> Default for WinnerHeap<T> {
             internal: Vec::new(),
             internal_size: 0,
             leaves: Vec::new(),
-            last_pop: None
+            last_pop: None,
+            comparisons: 0
         }
     }
 }
@@ -184,7 +195,7 @@ pub fn init_internal_structure(amount_of_items: usize) -> Vec<Option<usize>> {
     match amount_of_items {
         0 => vec![],
         1 => vec![None],
-        n => vec![None; n.next_power_of_two() as usize - 1]
+        n => vec![None; n.next_power_of_two() - 1]
     }
 }
 
@@ -320,6 +331,9 @@ mod tests {
     fn test_pop_8() {
         let mut winner_tree = WinnerHeap::new(vec![ 4, 7, 2, 8, 13, 1, 5, 23 ]);
 
+        // Every internal node compares two leaves
+        assert_eq!(winner_tree.comparisons, 7);
+
         assert_eq!(winner_tree.pop(), Some(23));
         assert_eq!(winner_tree.pop(), Some(13));
         assert_eq!(winner_tree.pop(), Some(8));
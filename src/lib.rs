@@ -1,41 +1,465 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
 use chunk::Chunks;
-use crate::tempfile::TmpDir;
+use plan::MergePlan;
 use threadpool::ThreadPool;
 
 mod config;
+mod budget;
 mod chunk;
 mod line;
 mod tempfile;
 mod sort;
+mod selection;
 mod merge;
-mod util;
+mod plan;
+mod partition;
+mod async_io;
 mod heap;
+mod system;
+mod progress;
+mod stats;
+mod reject;
+#[cfg(test)]
+mod fault;
 
-pub use crate::config::Configuration;
-pub use crate::tempfile::TmpDirBuilder;
+pub use crate::config::{Configuration, RunGeneration, MissingField};
+pub use crate::reject::RejectFile;
+pub use crate::budget::{MemoryBudget, Reservation};
+pub use crate::progress::{Progress, Reporter};
+pub use crate::stats::{SortStats, PhaseStats, StatsCollector, PhaseTimer};
+pub use crate::system::{available_threads, available_memory, total_memory, parse_buffer_size};
+pub use crate::tempfile::{TmpDir, TmpDirBuilder, MemoryStorage, IoBackend, IoOptions};
+pub use crate::tempfile::{TmpStorage, Recovered, InputRange, TmpFileOpened, TmpFileClosed, TmpFileWrite, TmpFileRead};
 
-pub fn external_sort(
+/// The building blocks of the sort, for the benchmarks and tests outside of this crate
+#[doc(hidden)]
+pub mod internals {
+    pub use crate::chunk::{Chunk, Chunks};
+    pub use crate::heap::WinnerHeap;
+    pub use crate::line::{Line, Lines};
+}
+
+/// Sorts the lines of the input into the output, using the temporary storage
+/// for the runs that do not fit in memory
+///
+/// # Arguments
+///
+/// * `input` - The lines to sort
+/// * `output` - The writer to write the sorted lines to
+/// * `tmp_dir` - The storage for the sorted runs
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The statistics of the sort, or the error of the input, the output, the temporary
+/// storage or the reject file. A resumed sort only counts the work it did itself. An
+/// invalid configuration is an `InvalidInput` error.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(threads = config.threads, buffer_size = config.buffer_size)))]
+pub fn external_sort<S: TmpStorage>(
     input: &mut impl Read,
     output: &mut impl Write,
-    tmp_dir: &mut TmpDir,
-    config: Configuration
-) {
+    tmp_dir: &mut S,
+    mut config: Configuration
+) -> io::Result<SortStats> {
+    config.validate().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    // All buffers of the sort share the buffer size
+    if config.memory.limit().is_none() {
+        config.memory = MemoryBudget::new(config.buffer_size);
+    }
+
+    let _reporter = config.progress.start_reporter();
+
     // Threadpool for sorting and mergin chunks
     let threadpool = ThreadPool::new(config.threads);
 
-    // Create a chunk iterator over the input stream
-    let mut input_chunks = Chunks::new(input, config.buffer_size / config.threads, config.clone());
+    // Pick up the work of an interrupted sort, if any
+    let recovered = tmp_dir.recover(&config)?;
+
+    let (chunk_size, chunk_config) = match config.run_generation {
+        RunGeneration::Chunks => (config.thread_buffer_size(), config.clone()),
+
+        // The input chunks only pass their lines on to the tournament tree, so they
+        // can be small. The tree reserves their memory as well.
+        RunGeneration::ReplacementSelection => (config.buffer_size / 16, Configuration { memory: MemoryBudget::unlimited(), ..config.clone() })
+    };
+
+    // Create a chunk iterator over the part of the input stream that is not sorted yet. The
+    // input of a sorted checkpoint is read to its end, so all of it is compared.
+    let skipped_input = if recovered.sorted { u64::MAX } else { recovered.input_offset };
+    let mut input_chunks = Chunks::new_at_offset(input, skipped_input, chunk_size, chunk_config)?;
+
+    // The runs of the interrupted sort are only valid for the input they were made from
+    if input_chunks.offset() != recovered.input_offset || input_chunks.checksum() != recovered.input_checksum {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The input differs from the input of the interrupted sort"));
+    }
+
+    // The rejects of the input that is read again are written again
+    if let (MissingField::Reject(rejects), Some(bytes)) = (&config.missing_field, recovered.reject_bytes) {
+        rejects.truncate(bytes)?;
+    }
+    let mut sorted_files = recovered.files;
+    let mut sorted_chunks = vec![];
+
+    if !recovered.sorted {
+        let _phase = config.stats.start_phase("sort");
+
+        let remaining_input = config.progress.input_size().map(|size| size.saturating_sub(recovered.input_offset));
+        config.progress.start_phase("Sorting", remaining_input);
+
+        match config.run_generation {
+            RunGeneration::Chunks => {
+                // Sort all chunks and write them to small temporary files, the
+                // last chunks are kept in memory
+                let (files, chunks) = sort::sort(&mut input_chunks, &threadpool, tmp_dir, &config)?;
+                sorted_files.extend(files);
+                sorted_chunks = chunks;
+            },
+            RunGeneration::ReplacementSelection => {
+                // Stream all lines through a tournament tree to create long runs
+                sorted_files.extend(selection::replacement_selection(&mut input_chunks, tmp_dir, &config)?);
+            }
+        }
+
+        // All lines that miss the sort field have been read
+        if let MissingField::Reject(rejects) = &config.missing_field {
+            rejects.flush()?;
+        }
+
+        tmp_dir.record_sorted(input_chunks.offset(), input_chunks.checksum())?;
+    }
+
+    let mut plan = MergePlan::new(&run_sizes::<S>(&sorted_files)?, tmp_dir.file_buffer_size(), &config);
+
+    // The runs that are kept in memory hold on to their part of the budget, which
+    // has to leave the smallest amount of memory for the merges of the files
+    if !sorted_chunks.is_empty() && config.memory.available() < min_merge_memory(&plan, tmp_dir.file_buffer_size(), &config) {
+        if config.verbose {
+            eprintln!("Writing {} sorted chunks to make room for the merge", sorted_chunks.len());
+        }
+
+        let spilled_files = sort::write_chunks(sorted_chunks, tmp_dir)?;
+        config.stats.add_tmp_bytes(run_sizes::<S>(&spilled_files)?.iter().sum());
+
+        sorted_files.extend(spilled_files);
+        sorted_chunks = vec![];
+
+        plan = MergePlan::new(&run_sizes::<S>(&sorted_files)?, tmp_dir.file_buffer_size(), &config);
+    }
+
+    if config.verbose && plan.runs > 0 {
+        eprintln!("{}", plan);
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::info!(runs = plan.runs, bytes = plan.bytes, fan_in = plan.fan_in, passes = plan.passes, "Planned the merge");
+
+    // Merge the smallest neighbouring files until the amount of files is small enough
+    let merge_phase = (!plan.steps.is_empty()).then(|| config.stats.start_phase("merge"));
+    let sorted_files = merge::merge(sorted_files, &plan.steps, &threadpool, tmp_dir, &config)?;
+    drop(merge_phase);
+
+    let total_size = run_sizes::<S>(&sorted_files)?.iter().sum::<u64>()
+        + sorted_chunks.iter().map(|chunk| chunk.bytes() as u64).sum::<u64>();
+    config.progress.start_phase("Merging into the output", Some(total_size));
+
+    let final_phase = config.stats.start_phase("final merge");
+    if total_size > 0 {
+        config.stats.add_pass();
+    }
+
+    let (stats, memory) = (config.stats.clone(), config.memory.clone());
+
+    // Merge all temporary files and in-memory chunks into the output stream
+    let mut output = config.progress.writer(output);
+    let merged_files = merge::parallel_merge_and_write(sorted_files, sorted_chunks, &mut output, &threadpool, tmp_dir, config)?;
+    output.flush()?;
+
+    for file in merged_files {
+        file.remove();
+    }
+
+    tmp_dir.finish();
+    drop(final_phase);
+
+    let stats = stats.snapshot(memory.peak() as u64);
+
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        input_bytes = stats.input_bytes,
+        input_lines = stats.input_lines,
+        runs = stats.initial_runs(),
+        passes = stats.merge_passes,
+        tmp_bytes_written = stats.tmp_bytes_written,
+        comparisons = stats.comparisons,
+        "Sorted the input"
+    );
+
+    Ok(stats)
+}
 
-    // Sort all chunks and write them to small temporary files
-    let mut sorted_files = sort::sort(&mut input_chunks, &threadpool, tmp_dir, &config);
+/// Returns the memory that the merges of a plan wait for: a merge before the final
+/// merge on a single thread, or the final merge, which writes to the output
+fn min_merge_memory(plan: &MergePlan, file_buffers: usize, config: &Configuration) -> usize {
+    let final_merge = merge::min_merge_memory(plan.fan_in.min(plan.runs), file_buffers, 0, config).min(config.buffer_size);
+
+    if plan.steps.is_empty() {
+        return final_merge;
+    }
+
+    let step = merge::min_merge_memory(plan.fan_in, file_buffers, file_buffers, config).min(config.thread_buffer_size());
+    final_merge.max(step)
+}
+
+fn run_sizes<S: TmpStorage>(files: &[S::Closed]) -> io::Result<Vec<u64>> {
+    files.iter().map(TmpFileClosed::size).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::read_dir, io::Cursor};
+
+    use crate::fault::{assert_fails_cleanly, Fault, Faults, Faulty, FaultyStorage};
+
+    use super::*;
+
+    /// Enough lines for many runs and several merge passes with tiny buffers
+    fn input() -> Vec<u8> {
+        (0..2000).flat_map(|i| format!("{:0>5}\n", (i * 7919) % 10007).into_bytes()).collect()
+    }
+
+    fn config() -> Configuration {
+        Configuration { threads: 2, buffer_size: 1024, chunk_size: Some(2), min_stream_buffer: 8, ..Configuration::default() }
+    }
 
-    // Keep merging until the amount of files is small enough
-    while sorted_files.len() > config.chunk_size {
-        sorted_files = merge::merge(sorted_files, &threadpool, tmp_dir, &config);
+    /// Faults that a sort has to retry, every few hundred bytes
+    fn retried_faults(bytes: u64) -> Faults {
+        (0..bytes).step_by(300).fold(Faults::new(), |faults, offset| {
+            faults.at(offset, Fault::Interrupted).at(offset + 100, Fault::Short)
+        })
     }
 
-    // Merge all temporary files into the output stream
-    merge::merge_and_write(sorted_files, output, config);
+    #[test]
+    fn test_sort_retries_interrupted_io() {
+        let input = input();
+        let location = ::tempfile::tempdir().unwrap();
+        let location_path = location.path().to_path_buf();
+
+        let mut storage = FaultyStorage::new(TmpDirBuilder::new().with_location(&location_path).build());
+        for file in 0..100 {
+            storage = storage.with_write_faults(file, retried_faults(1000)).with_read_faults(file, retried_faults(1000));
+        }
+
+        let mut output = Faulty::new(vec![], retried_faults(input.len() as u64));
+        let stats = external_sort(&mut Faulty::new(Cursor::new(&input), retried_faults(input.len() as u64)), &mut output, &mut storage, config()).unwrap();
+
+        let mut expected: Vec<&[u8]> = input.split_inclusive(|byte| *byte == b'\n').collect();
+        expected.sort();
+
+        assert!(stats.merge_passes > 1);
+        assert_eq!(output.into_inner(), expected.concat());
+
+        drop(storage);
+        assert_eq!(read_dir(location.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_sort_in_memory_with_large_file_buffers() {
+        // The buffers of a single file are larger than the whole budget, but the
+        // runs that stay in memory never wait for them
+        let input: Vec<u8> = (0..50).flat_map(|i| format!("{:0>5}\n", (i * 7919) % 10007).into_bytes()).collect();
+        let config = Configuration { threads: 4, buffer_size: 2400, ..config() };
+
+        let location = ::tempfile::tempdir().unwrap();
+        let location_path = location.path().to_path_buf();
+        let mut tmp_dir = TmpDirBuilder::new().with_location(&location_path).build();
+        assert!(tmp_dir.file_buffer_size() > config.buffer_size);
+
+        let mut output = vec![];
+        external_sort(&mut Cursor::new(&input), &mut output, &mut tmp_dir, config).unwrap();
+
+        let mut expected: Vec<&[u8]> = input.split_inclusive(|byte| *byte == b'\n').collect();
+        expected.sort();
+        assert_eq!(output, expected.concat());
+    }
+
+    #[test]
+    fn test_sort_fails_cleanly() {
+        let input = input();
+
+        // The input fails halfway
+        assert_fails_cleanly(Fault::Io, |tmp_dir| {
+            let mut input = Faulty::new(Cursor::new(&input), Faults::new().at(6000, Fault::Io));
+            external_sort(&mut input, &mut vec![], &mut FaultyStorage::new(tmp_dir), config()).map(drop)
+        });
+
+        // The input fails halfway while it streams through the tournament tree
+        assert_fails_cleanly(Fault::Io, |tmp_dir| {
+            let mut input = Faulty::new(Cursor::new(&input), Faults::new().at(6000, Fault::Io));
+            let config = Configuration { run_generation: RunGeneration::ReplacementSelection, ..config() };
+            external_sort(&mut input, &mut vec![], &mut FaultyStorage::new(tmp_dir), config).map(drop)
+        });
+
+        // The disk fills up while a run is written
+        assert_fails_cleanly(Fault::NoSpace, |tmp_dir| {
+            let mut storage = FaultyStorage::new(tmp_dir).with_write_faults(3, Faults::new().at(100, Fault::NoSpace));
+            external_sort(&mut Cursor::new(&input), &mut vec![], &mut storage, config()).map(drop)
+        });
+
+        // A run cannot be read while it is merged
+        assert_fails_cleanly(Fault::Io, |tmp_dir| {
+            let mut storage = FaultyStorage::new(tmp_dir).with_read_faults(0, Faults::new().at(100, Fault::Io));
+            external_sort(&mut Cursor::new(&input), &mut vec![], &mut storage, config()).map(drop)
+        });
+
+        // The output fails during the final merge
+        assert_fails_cleanly(Fault::NoSpace, |tmp_dir| {
+            let mut output = Faulty::new(vec![], Faults::new().at(100, Fault::NoSpace));
+            external_sort(&mut Cursor::new(&input), &mut output, &mut FaultyStorage::new(tmp_dir), config()).map(drop)
+        });
+    }
+
+    #[test]
+    fn test_sort_fails_cleanly_in_parallel_final_merge() {
+        // Enough input for the final merge to be split into partitions
+        let input: Vec<u8> = (0..300_000u64).flat_map(|i| format!("{:0>8}\n", (i * 7919) % 300_007).into_bytes()).collect();
+        let config = Configuration { threads: 4, buffer_size: 4 << 20, ..Configuration::default() };
+
+        // The output fails in the first partition, or in a partition that is copied from its file
+        for offset in [100, 2_000_000] {
+            assert_fails_cleanly(Fault::NoSpace, |tmp_dir| {
+                let mut output = Faulty::new(vec![], Faults::new().at(offset, Fault::NoSpace));
+                external_sort(&mut Cursor::new(&input), &mut output, &mut FaultyStorage::new(tmp_dir), config.clone()).map(drop)
+            });
+        }
+
+        // A run cannot be read while the partitions are split or merged
+        for offset in [100, 200_000] {
+            assert_fails_cleanly(Fault::Io, |tmp_dir| {
+                let mut storage = FaultyStorage::new(tmp_dir).with_read_faults(0, Faults::new().at(offset, Fault::Io));
+                external_sort(&mut Cursor::new(&input), &mut vec![], &mut storage, config.clone()).map(drop)
+            });
+        }
+    }
+
+    #[test]
+    fn test_resume_checks_input() {
+        let input = input();
+        let mut changed = input.clone();
+        changed[0] = b'9';
+
+        let mut expected: Vec<&[u8]> = input.split_inclusive(|byte| *byte == b'\n').collect();
+        expected.sort();
+
+        // The sort is interrupted while it reads the input, or once the whole input has been turned into runs
+        for (input_faults, output_faults) in [(Faults::new().at(6000, Fault::Io), Faults::new()), (Faults::new(), Faults::new().at(100, Fault::NoSpace))] {
+            let location = ::tempfile::tempdir().unwrap();
+            let work_dir = location.path().join("work");
+
+            let mut tmp_dir = TmpDirBuilder::new().with_work_dir(&work_dir).build();
+            let mut output = Faulty::new(vec![], output_faults);
+            assert!(external_sort(&mut Faulty::new(Cursor::new(&input), input_faults), &mut output, &mut tmp_dir, config()).is_err());
+            drop(tmp_dir);
+
+            // Another input cannot reuse the runs, and the checkpoint is kept for the right one
+            for other in [&changed[..], &input[..input.len() / 4]] {
+                let mut tmp_dir = TmpDirBuilder::new().resume(&work_dir).build();
+                let err = external_sort(&mut Cursor::new(other), &mut vec![], &mut tmp_dir, config()).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            }
+
+            let mut output = vec![];
+            let mut tmp_dir = TmpDirBuilder::new().resume(&work_dir).build();
+            external_sort(&mut Cursor::new(&input), &mut output, &mut tmp_dir, config()).unwrap();
+
+            assert_eq!(output, expected.concat());
+            assert!(!work_dir.exists());
+        }
+    }
+
+    #[test]
+    fn test_work_dir_refuses_existing_checkpoint() {
+        let location = ::tempfile::tempdir().unwrap();
+        let work_dir = location.path().join("work");
+
+        let mut output = Faulty::new(vec![], Faults::new().at(100, Fault::NoSpace));
+        let mut tmp_dir = TmpDirBuilder::new().with_work_dir(&work_dir).build();
+        assert!(external_sort(&mut Cursor::new(&input()), &mut output, &mut tmp_dir, config()).is_err());
+        drop(tmp_dir);
+
+        let mut tmp_dir = TmpDirBuilder::new().with_work_dir(&work_dir).build();
+        let err = external_sort(&mut Cursor::new(&input()), &mut vec![], &mut tmp_dir, config()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_rejects_are_marked_by_the_input() {
+        let input: Vec<u8> = (0..2000).flat_map(|i| if i % 3 == 0 { format!("{}\n", i) } else { format!("{}\t{}\n", i, i) }.into_bytes()).collect();
+
+        for run_generation in [RunGeneration::Chunks, RunGeneration::ReplacementSelection] {
+            let rejects = RejectFile::new(vec![]);
+            let config = Configuration { field: 2, missing_field: MissingField::Reject(rejects.clone()), run_generation, ..config() };
+            let stats = external_sort(&mut Cursor::new(&input), &mut vec![], &mut MemoryStorage::new(), config).unwrap();
+
+            // Only the chunks of the input mark the reject file, not the runs that are merged
+            let marks = rejects.marks();
+            assert!(stats.merge_passes > 1);
+            assert!(marks.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(marks.last(), Some(&(input.len() as u64)));
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = Configuration { field: 3, last_field: Some(2), ..config() };
+        let result = external_sort(&mut Cursor::new(&input()), &mut vec![], &mut MemoryStorage::new(), config);
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_reject_file_fails() {
+        let input: Vec<u8> = (0..2000).flat_map(|i| if i % 3 == 0 { format!("{}\n", i) } else { format!("{}\t{}\n", i, i) }.into_bytes()).collect();
+
+        let rejects = RejectFile::new(Faulty::new(vec![], Faults::new().at(100, Fault::NoSpace)));
+        let config = Configuration { field: 2, missing_field: MissingField::Reject(rejects), ..config() };
+        let result = external_sort(&mut Cursor::new(&input), &mut vec![], &mut MemoryStorage::new(), config);
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::StorageFull);
+    }
+
+    #[test]
+    fn test_missing_field_across_merges() {
+        // Every third line misses the second field
+        let input: Vec<u8> = (0..2000)
+            .flat_map(|i| if i % 3 == 0 { format!("{}\n", i) } else { format!("{}\t{:0>5}\n", i, (i * 7919) % 10007) }.into_bytes())
+            .collect();
+
+        let sort = |missing_field: MissingField| {
+            let mut output = vec![];
+            let config = Configuration { field: 2, missing_field, ..config() };
+            let stats = external_sort(&mut Cursor::new(&input), &mut output, &mut MemoryStorage::new(), config).unwrap();
+
+            assert!(stats.merge_passes > 1);
+            (String::from_utf8(output).unwrap(), stats.skipped_lines)
+        };
+
+        let missing = |line: &&str| !line.contains('\t');
+
+        // The merges keep the lines without the field after the other lines
+        let (output, _) = sort(MissingField::Last);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.iter().position(missing), Some(1333));
+        assert!(lines[1333..].iter().all(missing));
+
+        let (output, skipped) = sort(MissingField::Skip);
+        assert_eq!((output.lines().count(), skipped), (1333, 667));
+
+        let rejected = crate::reject::SharedBuffer::default();
+        let (output, _) = sort(MissingField::Reject(RejectFile::new(rejected.clone())));
+        let expected: String = (0..2000).step_by(3).map(|i| format!("{}\t{}\n", i + 1, i)).collect();
+        assert_eq!(output.lines().count(), 1333);
+        assert_eq!(String::from_utf8(rejected.content()).unwrap(), expected);
+    }
 }
@@ -1,4 +1,4 @@
-use std::io::Read;
+use std::io::{self, Read};
 
 use crate::{chunk::{Chunks, Chunk}, Configuration};
 
@@ -9,7 +9,7 @@ pub struct Lines<R: Read> {
     /// Chunked iterator over the lines of a file
     chunks: Chunks<R>,
 
-    /// The current chunk
+    /// The current chunk, which is read once the first line is needed
     chunk: Option<Chunk>
 }
 
@@ -26,43 +26,40 @@ impl<R: Read> Lines<R> {
     /// 
     /// A new (chunked) iterator over the lines of a file
     pub fn new(input: R, buffer_size: usize, config: Configuration) -> Self {
-        let mut chunks = Chunks::new(input, buffer_size, config);
-        let chunk = chunks.next();
-
-        Lines { chunks, chunk }
+        Lines { chunks: Chunks::new(input, buffer_size, config), chunk: None }
     }
 }
 
 impl<R: Read> Iterator for Lines<R> {
-    type Item = Line;
+    type Item = io::Result<Line>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // If there is a chunk, try to get the next line from it
-        if let Some(chunk) = &mut self.chunk {
-            if let Some(line) = chunk.next() {
-                return Some(line);
+        loop {
+            // If there is a chunk, try to get the next line from it
+            if let Some(line) = self.chunk.as_mut().and_then(Iterator::next) {
+                return Some(Ok(line));
             }
-        }
 
-        // If there is no chunk, try to get the next chunk
-        if let Some(next_chunk) = self.chunks.next() {
-            self.chunk = Some(next_chunk);
-            return self.next();
+            // If there is no chunk, try to get the next chunk. If there are no
+            // chunks left or the input fails, return that.
+            match self.chunks.next()? {
+                Ok(next_chunk) => self.chunk = Some(next_chunk),
+                Err(err) => return Some(Err(err))
+            }
         }
-
-        // If there are no chunks left, return None
-        None
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::rc::Rc;
+    use std::{io::ErrorKind, sync::Arc};
+
+    use crate::fault::{Fault, Faults, Faulty};
 
     use super::*;
 
-    fn construct_rc_buffer(content: &str) -> Rc<Vec<u8>> {
-        Rc::new(content.as_bytes().to_vec())
+    fn construct_rc_buffer(content: &str) -> Arc<Vec<u8>> {
+        Arc::new(content.as_bytes().to_vec())
     }
 
     #[test]
@@ -74,10 +71,10 @@ mod tests {
 
         let buffer = construct_rc_buffer("AAACLNNYAA\nAAAAAALTER\nKYYLMMAAFG\n");
 
-        assert_eq!(lines.next(), Some(Line::new(buffer.clone(), 0, 9)));
-        assert_eq!(lines.next(), Some(Line::new(buffer.clone(), 11, 20)));
-        assert_eq!(lines.next(), Some(Line::new(buffer.clone(), 22, 31)));
-        assert_eq!(lines.next(), None);
+        assert_eq!(lines.next().transpose().unwrap(), Some(Line::new(buffer.clone(), 0, 9)));
+        assert_eq!(lines.next().transpose().unwrap(), Some(Line::new(buffer.clone(), 11, 20)));
+        assert_eq!(lines.next().transpose().unwrap(), Some(Line::new(buffer.clone(), 22, 31)));
+        assert!(lines.next().is_none());
     }
 
     #[test]
@@ -89,9 +86,20 @@ mod tests {
 
         let buffer = construct_rc_buffer("AAACLNNYAA\nAAAAAALTER\nKYYLMMAAFG\n");
 
-        assert_eq!(lines.next(), Some(Line::new(buffer.clone(), 0, 9)));
-        assert_eq!(lines.next(), Some(Line::new(buffer.clone(), 11, 20)));
-        assert_eq!(lines.next(), Some(Line::new(buffer.clone(), 22, 31)));
-        assert_eq!(lines.next(), None);
+        assert_eq!(lines.next().transpose().unwrap(), Some(Line::new(buffer.clone(), 0, 9)));
+        assert_eq!(lines.next().transpose().unwrap(), Some(Line::new(buffer.clone(), 11, 20)));
+        assert_eq!(lines.next().transpose().unwrap(), Some(Line::new(buffer.clone(), 22, 31)));
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn test_read_error() {
+        let faults = Faults::new().at(0, Fault::Interrupted).at(15, Fault::Io);
+        let mut lines = Lines::new(Faulty::new(&b"AAACLNNYAA\nAAAAAALTER\nKYYLMMAAFG\n"[..], faults), 12, Configuration::default());
+
+        // An interrupted read is tried again, a failing read ends the lines
+        assert!(lines.next().unwrap().is_ok());
+        assert_eq!(lines.next().unwrap().unwrap_err().kind(), ErrorKind::Other);
+        assert!(lines.next().is_none());
     }
 }
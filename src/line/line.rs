@@ -1,11 +1,11 @@
-use std::{rc::Rc, io::Write};
+use std::{sync::Arc, io::{self, Write}};
 
 /// A struct representing a single line of bytes
 #[derive(Clone, Debug)]
 pub struct Line {
     /// The buffer containing the bytes of the line
     /// We use a Reference counting smart pointer here to avoid copying the buffer for each individual line
-    buffer: Rc<Vec<u8>>,
+    buffer: Arc<Vec<u8>>,
 
     /// The index of the first byte of this line in the buffer
     start: usize,
@@ -15,6 +15,9 @@ pub struct Line {
 
     /// The range of bytes in the line that should be used for sorting
     field: (usize, usize),
+
+    /// Whether the line misses its sort field and sorts after all lines that have it
+    sorts_last: bool
 }
 
 impl Line {
@@ -30,8 +33,8 @@ impl Line {
     /// # Returns
     /// 
     /// A new `Line` instance
-    pub fn new(buffer: Rc<Vec<u8>>, start: usize, end: usize) -> Self {
-        Line { buffer, start, end, field: (start, end) }
+    pub fn new(buffer: Arc<Vec<u8>>, start: usize, end: usize) -> Self {
+        Line { buffer, start, end, field: (start, end), sorts_last: false }
     }
 
     /// Creates a new `Line` instance with the given buffer, start and end indices and field range.
@@ -46,8 +49,45 @@ impl Line {
     /// # Returns
     /// 
     /// A new `Line` instance
-    pub fn new_with_field(buffer: Rc<Vec<u8>>, start: usize, end: usize, field: (usize, usize)) -> Self {
-        Line { buffer, start, end, field }
+    pub fn new_with_field(buffer: Arc<Vec<u8>>, start: usize, end: usize, field: (usize, usize)) -> Self {
+        Line { buffer, start, end, field, sorts_last: false }
+    }
+
+    /// Creates a new `Line` instance without a sort field, which sorts after all lines that have one.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `buffer` - A smart pointer to the buffer containing the bytes of the line
+    /// * `start` - The index of the first byte of this line in the buffer
+    /// * `end` - The index of the last byte of this line in the buffer
+    /// 
+    /// # Returns
+    /// 
+    /// A new `Line` instance
+    pub fn new_last(buffer: Arc<Vec<u8>>, start: usize, end: usize) -> Self {
+        Line { buffer, start, end, field: (end + 1, end), sorts_last: true }
+    }
+
+    /// Copies the line into a buffer of its own, so it no longer keeps the
+    /// (much larger) buffer it was read from alive
+    ///
+    /// # Returns
+    ///
+    /// A new `Line` instance with the same content and field
+    pub fn to_owned_line(&self) -> Self {
+        // Prefix the line with a single byte, so an empty line can be represented
+        let mut buffer = Vec::with_capacity(self.size() + 1);
+        buffer.push(b'\n');
+        buffer.extend_from_slice(self.as_bytes());
+
+        let field = (self.field.0 + 1 - self.start, self.field.1 + 1 - self.start);
+
+        Line { buffer: Arc::new(buffer), start: 1, end: self.size(), field, sorts_last: self.sorts_last }
+    }
+
+    /// Returns the number of bytes in the line
+    pub fn size(&self) -> usize {
+        self.end + 1 - self.start
     }
 
     /// Writes the line to the given writer
@@ -55,9 +95,9 @@ impl Line {
     /// # Arguments
     /// 
     /// * `writer` - The writer to write the line to
-    pub fn write(&self, writer: &mut impl Write) {
-        writer.write_all(self.as_bytes()).unwrap();
-        writer.write("\n".as_bytes()).unwrap();
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(self.as_bytes())?;
+        writer.write_all("\n".as_bytes())
     }
 
     /// Returns the bytes of the line
@@ -71,8 +111,6 @@ impl Line {
     }
 }
 
-unsafe impl Send for Line {}
-
 impl PartialEq for Line {
     fn eq(&self, other: &Self) -> bool {
         self.cmp(other) == std::cmp::Ordering::Equal
@@ -83,13 +121,13 @@ impl Eq for Line {}
 
 impl PartialOrd for Line {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        other.as_sort_bytes().partial_cmp(self.as_sort_bytes())
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Line {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        other.as_sort_bytes().cmp(self.as_sort_bytes())
+        other.sorts_last.cmp(&self.sorts_last).then_with(|| other.as_sort_bytes().cmp(self.as_sort_bytes()))
     }
 }
 
@@ -97,15 +135,15 @@ impl Ord for Line {
 mod tests {
     use super::*;
 
-    fn construct_rc_buffer(content: &str) -> Rc<Vec<u8>> {
-        Rc::new(content.as_bytes().to_vec())
+    fn construct_rc_buffer(content: &str) -> Arc<Vec<u8>> {
+        Arc::new(content.as_bytes().to_vec())
     }
 
     #[test]
     fn test_new() {
         let buffer = construct_rc_buffer("AAACLNNYAA");
 
-        let line = Line::new(Rc::clone(&buffer), 0, 9);
+        let line = Line::new(Arc::clone(&buffer), 0, 9);
 
         assert_eq!(line.as_bytes(), "AAACLNNYAA".as_bytes());
     }
@@ -114,20 +152,34 @@ mod tests {
     fn test_new_with_field() {
         let buffer = construct_rc_buffer("AAACLNNYAA");
 
-        let line = Line::new_with_field(Rc::clone(&buffer), 0, 9, (1, 8));
+        let line = Line::new_with_field(Arc::clone(&buffer), 0, 9, (1, 8));
 
         assert_eq!(line.as_bytes(), "AAACLNNYAA".as_bytes());
         assert_eq!(line.as_sort_bytes(), "AACLNNYA".as_bytes());
     }
 
+    #[test]
+    fn test_to_owned_line() {
+        let buffer = construct_rc_buffer("AAACL\nAAA\tCAAALTER\n\n");
+
+        let line = Line::new_with_field(Arc::clone(&buffer), 6, 17, (9, 17)).to_owned_line();
+        let empty_line = Line::new(Arc::clone(&buffer), 19, 18).to_owned_line();
+
+        assert_eq!(line.as_bytes(), "AAA\tCAAALTER".as_bytes());
+        assert_eq!(line.as_sort_bytes(), "\tCAAALTER".as_bytes());
+        assert_eq!(line.size(), 12);
+        assert_eq!(empty_line.as_bytes(), "".as_bytes());
+        assert_eq!(Arc::strong_count(&buffer), 1);
+    }
+
     #[test]
     fn test_write() {
         let buffer = construct_rc_buffer("AAACLNNYAA");
 
-        let line = Line::new(Rc::clone(&buffer), 0, 9);
+        let line = Line::new(Arc::clone(&buffer), 0, 9);
 
         let mut output = vec![];
-        line.write(&mut output);
+        line.write(&mut output).unwrap();
 
         assert_eq!(output, "AAACLNNYAA\n".as_bytes());
     }
@@ -136,8 +188,8 @@ mod tests {
     fn test_as_bytes() {
         let buffer = construct_rc_buffer("AAACLNNYAA");
 
-        let line = Line::new(Rc::clone(&buffer), 0, 9);
-        let line_with_field = Line::new_with_field(Rc::clone(&buffer), 0, 9, (1, 8));
+        let line = Line::new(Arc::clone(&buffer), 0, 9);
+        let line_with_field = Line::new_with_field(Arc::clone(&buffer), 0, 9, (1, 8));
 
         assert_eq!(line.as_bytes(), "AAACLNNYAA".as_bytes());
         assert_eq!(line_with_field.as_bytes(), "AAACLNNYAA".as_bytes());
@@ -147,25 +199,41 @@ mod tests {
     fn test_as_sort_bytes() {
         let buffer = construct_rc_buffer("AAACLNNYAA");
 
-        let line = Line::new(Rc::clone(&buffer), 0, 9);
-        let line_with_field = Line::new_with_field(Rc::clone(&buffer), 0, 9, (1, 8));
+        let line = Line::new(Arc::clone(&buffer), 0, 9);
+        let line_with_field = Line::new_with_field(Arc::clone(&buffer), 0, 9, (1, 8));
 
         assert_eq!(line.as_sort_bytes(), "AAACLNNYAA".as_bytes());
         assert_eq!(line_with_field.as_sort_bytes(), "AACLNNYA".as_bytes());
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_cmp() {
         let buffer = construct_rc_buffer("AAACL\nAAA\nCAAALTER\nAAA\n");
 
-        let line1 = Line::new(Rc::clone(&buffer), 0, 4);
-        let line2 = Line::new(Rc::clone(&buffer), 6, 8);
-        let line3 = Line::new(Rc::clone(&buffer), 10, 17);
-        let line4 = Line::new(Rc::clone(&buffer), 19, 21);
+        let line1 = Line::new(Arc::clone(&buffer), 0, 4);
+        let line2 = Line::new(Arc::clone(&buffer), 6, 8);
+        let line3 = Line::new(Arc::clone(&buffer), 10, 17);
+        let line4 = Line::new(Arc::clone(&buffer), 19, 21);
 
         assert_eq!(line1 < line2, true);
         assert_eq!(line1 > line3, true);
         assert_eq!(line1 < line4, true);
         assert_eq!(line2 == line4, true);
     }
+
+    #[test]
+    fn test_cmp_last() {
+        let buffer = construct_rc_buffer("AAACL\nAAA\nCAAALTER\n");
+
+        let line1 = Line::new(Arc::clone(&buffer), 0, 4);
+        let line2 = Line::new_last(Arc::clone(&buffer), 6, 8);
+        let line3 = Line::new_last(Arc::clone(&buffer), 10, 17);
+
+        // Lines are ordered in reverse
+        assert!(line1 > line2);
+        assert!(line2 == line3);
+        assert!(line2.to_owned_line() == line3);
+        assert_eq!(line2.as_sort_bytes(), "".as_bytes());
+    }
 }
@@ -1,4 +1,5 @@
 mod iter;
+#[allow(clippy::module_inception)]
 mod line;
 
 pub use line::Line;
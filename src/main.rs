@@ -1,11 +1,16 @@
-use std::{io::{self, BufReader, BufWriter}, path::PathBuf};
+use std::{fs::{metadata, write}, io::{self, BufReader, BufWriter}, path::PathBuf, process::exit};
 
-use sorter::{TmpDirBuilder, external_sort, Configuration};
+use bytesize::ByteSize;
+use sorter::{TmpDirBuilder, external_sort, parse_buffer_size, Configuration, Progress, RunGeneration, IoBackend, MissingField, RejectFile};
 use structopt::StructOpt;
 
 fn main() {
     let args = SortArgs::from_args();
 
+    if let Some(log_level) = &args.log_level {
+        init_logging(log_level);
+    }
+
     let stdin = io::stdin();
     let stdout = io::stdout();
 
@@ -13,22 +18,70 @@ fn main() {
     let mut output_writer = BufWriter::new(stdout.lock());
 
     let tmp_location = PathBuf::from("/tmp");
-    let mut tmp_dir = TmpDirBuilder::new().with_location(&tmp_location).build();
+    let mut tmp_dir_builder = TmpDirBuilder::new();
+    tmp_dir_builder.with_location(&tmp_location);
+
+    if let Some(resume_dir) = &args.resume {
+        tmp_dir_builder.resume(resume_dir);
+    } else if let Some(work_dir) = &args.work_dir {
+        tmp_dir_builder.with_work_dir(work_dir);
+    }
+
+    if let Some(queue_depth) = args.io_uring {
+        tmp_dir_builder.with_io_backend(IoBackend::IoUring { queue_depth });
+    }
+
+    tmp_dir_builder.with_direct_io(args.direct_io);
+
+    let mut tmp_dir = tmp_dir_builder.build();
+
+    // Fill in what was not given from the limits of the machine
+    let auto = Configuration::auto();
 
     let config = Configuration {
-        buffer_size: args.buffer_size,
-        threads: args.threads,
+        buffer_size: args.buffer_size.unwrap_or(auto.buffer_size),
+        threads: args.threads.unwrap_or(auto.threads),
         delimiter: args.delimiter,
-        field: args.field,
-        ..Configuration::default()
+        field: args.field.0,
+        last_field: args.field.1,
+        missing_field: missing_field(&args),
+        chunk_size: args.fan_in,
+        verbose: args.verbose,
+        progress: if args.progress { Progress::new(input_size()) } else { Progress::disabled() },
+        run_generation: if args.replacement_selection { RunGeneration::ReplacementSelection } else { RunGeneration::Chunks },
+        ..auto
+    };
+
+    if let Err(err) = config.validate() {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
+
+    if args.verbose {
+        eprintln!(
+            "Sorting with {} threads and a buffer of {}, chunks of {} per thread",
+            config.threads,
+            ByteSize(config.buffer_size as u64),
+            ByteSize(config.thread_buffer_size() as u64)
+        );
+    }
+
+    let stats = match external_sort(&mut input_reader, &mut output_writer, &mut tmp_dir, config) {
+        Ok(stats) => stats,
+        Err(err) => {
+            // The temporary directory is only removed when it is dropped
+            drop(tmp_dir);
+            eprintln!("error: {}", err);
+            exit(1);
+        }
     };
 
-    external_sort(
-        &mut input_reader,
-        &mut output_writer,
-        &mut tmp_dir,
-        config
-    );
+    if let Some(stats_file) = &args.stats {
+        if let Err(err) = write(stats_file, stats.to_json()) {
+            eprintln!("error: failed to write the statistics to {}: {}", stats_file.display(), err);
+            exit(1);
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -37,23 +90,144 @@ pub struct SortArgs {
     #[structopt(short = "t", long = "temp-dir", default_value = "/tmp/sort-rs", parse(from_os_str))]
     pub tmp_dir: PathBuf,
 
-    /// Buffer size in bytes
-    #[structopt(short = "b", long = "buffer-size", default_value = "400000000")]
-    pub buffer_size: usize,
+    /// Persistent work directory that records the progress of the sort, so it can be resumed
+    #[structopt(long = "work-dir", parse(from_os_str))]
+    pub work_dir: Option<PathBuf>,
 
-    /// Number of threads to use
-    #[structopt(short = "p", long = "parallel", default_value = "4")]
-    pub threads: usize,
+    /// Resume the interrupted sort recorded in this work directory (the same input has to be given again)
+    #[structopt(long = "resume", parse(from_os_str), conflicts_with = "work-dir")]
+    pub resume: Option<PathBuf>,
+
+    /// Buffer size in bytes, with a suffix like `500M`, `2G` or `512MiB`, or a percentage
+    /// of the memory like `50%` (half of the available memory by default)
+    #[structopt(short = "b", long = "buffer-size", parse(try_from_str = parse_buffer_size))]
+    pub buffer_size: Option<usize>,
+
+    /// Number of runs to merge at once, computed from the buffer size by default
+    #[structopt(long = "fan-in")]
+    pub fan_in: Option<usize>,
+
+    /// Explain what the sort does on stderr
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+
+    /// Show the progress of the sort on stderr
+    #[structopt(long = "progress")]
+    pub progress: bool,
+
+    /// Log the spans and events of the sort on stderr up to this level: error, warn, info,
+    /// debug or trace (needs the tracing feature)
+    #[structopt(long = "log-level")]
+    pub log_level: Option<String>,
+
+    /// Write statistics about the runs, merge passes, time and memory of the sort to this file as JSON
+    #[structopt(long = "stats", parse(from_os_str))]
+    pub stats: Option<PathBuf>,
+
+    /// Number of threads to use (as many as the CPUs the process can use by default)
+    #[structopt(short = "p", long = "parallel")]
+    pub threads: Option<usize>,
 
     /// Delimiter to use
     #[structopt(short = "d", long = "delimiter", default_value = "\t", parse(try_from_str = parse_delimiter))]
     pub delimiter: u8,
 
-    /// Field to sort on
-    #[structopt(short = "f", long = "field", default_value = "1")]
-    pub field: usize
+    /// Field to sort on, as `START` for the key to run until the end of the line, or as
+    /// `START,END` for the key to end with field END (`2,2` sorts on field 2 alone)
+    #[structopt(short = "f", long = "field", default_value = "1", parse(try_from_str = parse_field))]
+    pub field: (usize, Option<usize>),
+
+    /// What to do with lines that miss the field to sort on: sort them `first` (the default)
+    /// or `last`, or `skip` them
+    #[structopt(long = "missing-field", possible_values = &["first", "last", "skip"])]
+    pub missing_field: Option<String>,
+
+    /// Leave lines that miss the field to sort on out of the output, and write them to this
+    /// file after their line number and a tab
+    #[structopt(long = "reject-file", parse(from_os_str), conflicts_with = "missing-field")]
+    pub reject_file: Option<PathBuf>,
+
+    /// Create the initial runs with replacement selection instead of sorting chunks
+    #[structopt(long = "replacement-selection")]
+    pub replacement_selection: bool,
+
+    /// Read and write temporary files with io_uring, with this many requests in flight per file
+    /// (needs Linux and the io-uring feature, falls back to blocking I/O otherwise)
+    #[structopt(long = "io-uring", value_name = "queue-depth")]
+    pub io_uring: Option<u32>,
+
+    /// Keep temporary files out of the page cache with O_DIRECT, or with posix_fadvise hints
+    /// on filesystems that do not support it
+    #[structopt(long = "direct-io")]
+    pub direct_io: bool
+}
+
+/// Logs the spans and events of the sort on stderr, with the time every span took
+#[cfg(feature = "tracing")]
+fn init_logging(log_level: &str) {
+    use std::io::IsTerminal;
+
+    let Ok(level) = log_level.parse::<tracing::Level>() else {
+        eprintln!("error: invalid log level: {}", log_level);
+        exit(1);
+    };
+
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+}
+
+#[cfg(not(feature = "tracing"))]
+fn init_logging(_log_level: &str) {
+    eprintln!("warning: --log-level needs the tracing feature, nothing is logged");
+}
+
+/// Returns what happens to lines that miss the sort field. A resumed sort
+/// keeps the rejects of the input it does not read again.
+fn missing_field(args: &SortArgs) -> MissingField {
+    let Some(reject_file) = &args.reject_file else {
+        return match args.missing_field.as_deref() {
+            Some("last") => MissingField::Last,
+            Some("skip") => MissingField::Skip,
+            _            => MissingField::First
+        };
+    };
+
+    match RejectFile::open(reject_file, args.resume.is_some()) {
+        Ok(rejects) => MissingField::Reject(rejects),
+        Err(err) => {
+            eprintln!("error: failed to create the reject file {}: {}", reject_file.display(), err);
+            exit(1);
+        }
+    }
+}
+
+/// Returns the size of the input if it is a file
+fn input_size() -> Option<u64> {
+    metadata("/dev/stdin").ok().filter(|metadata| metadata.is_file()).map(|metadata| metadata.len())
 }
 
 fn parse_delimiter(s: &str) -> Result<u8, String> {
     s.chars().next().ok_or_else(|| "Invalid delimiter".to_string()).map(|c| c as u8)
 }
+
+/// Parses a field like `2`, or a range of fields like `2,3`
+fn parse_field(s: &str) -> Result<(usize, Option<usize>), String> {
+    let parse = |field: &str| field.parse::<usize>().ok().filter(|field| *field > 0).ok_or_else(|| format!("Invalid field: {}", field));
+
+    match s.split_once(',') {
+        Some((start, end)) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+
+            if end < start {
+                return Err(format!("Invalid field range: {}", s));
+            }
+
+            Ok((start, Some(end)))
+        },
+        None => Ok((parse(s)?, None))
+    }
+}
@@ -1,85 +1,740 @@
-use std::sync::mpsc::channel;
-use std::io::Write;
-use std::cmp::{min, max};
+use std::sync::mpsc::{channel, Receiver};
+use std::io::{self, Read, Write};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::cmp::{min, Reverse};
 
-use bytesize::MB;
+use bytesize::{KIB, MB};
 use threadpool::ThreadPool;
 
+use crate::async_io::{ReadAhead, write_behind};
+use crate::budget::{MemoryBudget, Reservation};
+use crate::progress::Progress;
+use crate::chunk::Chunk;
 use crate::heap::WinnerHeap;
-use crate::{tempfile::{ClosedTmpFile, TmpDir, TmpFileReader, TmpFileClosed, TmpFileRead, TmpFileOpened}, util::into_chunks, Configuration, line::{Lines, Line}};
+use crate::partition::{splitters, partition_point};
+use crate::{tempfile::{TmpStorage, TmpFileClosed, TmpFileOpened, TmpFileRead}, plan::{merge_depth, MergeStep}, Configuration, MissingField, StatsCollector, line::{Lines, Line}};
 
-pub fn merge(
-    files: Vec<ClosedTmpFile>, 
+/// Merges the files before the final merge, as planned by the merge steps. All
+/// steps whose inputs exist are merged at the same time on the threadpool, after
+/// which the steps that depend on their output follow.
+///
+/// # Arguments
+///
+/// * `files` - The sorted files to merge, in the order of the input
+/// * `steps` - The planned merges
+/// * `sorter_pool` - The threadpool to merge on
+/// * `tmp_dir` - The storage to write the merged files to
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The files that are left for the final merge, in the order of the input, or
+/// the error of the storage
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(files = files.len(), steps = steps.len())))]
+pub fn merge<S: TmpStorage>(
+    files: Vec<S::Closed>,
+    steps: &[MergeStep],
     sorter_pool: &ThreadPool,
-    tmp_dir: &mut TmpDir,
+    tmp_dir: &mut S,
     config: &Configuration
-) -> Vec<ClosedTmpFile> {
-    let (file_sender, file_reciever) = channel();
+) -> io::Result<Vec<S::Closed>> {
+    // The initial files, followed by the output of every step
+    let file_count = files.len();
+    let mut runs: Vec<Option<S::Closed>> = files.into_iter().map(Some).collect();
+    runs.resize_with(file_count + steps.len(), || None);
 
-    let mut tmp_files: Vec<ClosedTmpFile> = vec![];
+    // The runs that are left, in the order of the input
+    let mut order: Vec<usize> = (0..file_count).collect();
 
-    // If the amount of files is smaller than chunk_size * threads, then we can 
-    // use a smaller chunk size to better distribute the merging work
-    let chunk_size = if files.len() < config.chunk_size * config.threads {
-        min(config.chunk_size, max(2, files.len() / config.threads))
-    } else {
-        config.chunk_size
-    };
+    // Files of a persistent work directory can only be removed once the whole
+    // pass is recorded, otherwise an interrupted pass cannot be redone
+    let keep_merged_files = tmp_dir.is_persistent();
+
+    // Every worker gets its share of the memory budget
+    let config = &Configuration { buffer_size: config.thread_buffer_size(), ..config.clone() };
+    let file_buffers = tmp_dir.file_buffer_size();
+
+    let passes = merge_depth(file_count, steps);
+    let mut pass = 0;
+
+    let mut waiting: Vec<usize> = (0..steps.len()).collect();
+
+    while !waiting.is_empty() {
+        let (ready, not_ready): (Vec<usize>, Vec<usize>) = waiting
+            .into_iter()
+            .partition(|step| steps[*step].inputs.iter().all(|input| runs[*input].is_some()));
+
+        assert!(!ready.is_empty(), "Failed to merge the files of an earlier pass");
+        waiting = not_ready;
+
+        pass += 1;
+        config.stats.add_pass();
+
+        let merges = ready.len();
+        let pass_size = ready.iter().map(|step| steps[*step].bytes).sum();
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(pass, passes, merges, bytes = pass_size, "Starting a merge pass");
+
+        config.progress.start_phase(format!("Merging pass {}/{}", pass, passes), Some(pass_size));
+
+        let (file_sender, file_reciever) = channel();
+        let mut result = Ok(());
+
+        for step in ready.iter().copied() {
+            let file_batch: Vec<S::Closed> = steps[step].inputs.iter().map(|input| runs[*input].take().unwrap()).collect();
+
+            let tmp_file = match tmp_dir.create_new_file() {
+                Ok(tmp_file) => tmp_file,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+
+            let sender = file_sender.clone();
+            let config = config.clone();
+
+            sorter_pool.execute(move || {
+                let merged = merge_step::<S>(file_batch, tmp_file, file_buffers, keep_merged_files, config);
+                let _ = sender.send((file_count + step, merged));
+            });
+        }
+
+        drop(file_sender);
+
+        // While there is at least a single sender connected to this receiver. The
+        // other merges are waited for after an error, so none of them writes to the
+        // storage while it is removed.
+        let mut merged_files: Vec<S::Closed> = vec![];
+        let mut merged_runs = 0;
+        while let Ok((run, merged)) = file_reciever.recv() {
+            merged_runs += 1;
+
+            let (file, size, merged) = match merged {
+                Ok(merged) => merged,
+                Err(err) => {
+                    result = result.and(Err(err));
+                    continue;
+                }
+            };
+
+            #[cfg(feature = "tracing")]
+            tracing::debug!(run, inputs = steps[run - file_count].inputs.len(), bytes = size, "Merged a run");
+
+            config.stats.add_tmp_bytes(size);
+            runs[run] = Some(file);
+            merged_files.extend(merged);
+        }
+
+        result?;
+
+        // A merge that panicked dropped its sender without sending its run
+        if merged_runs < merges {
+            return Err(io::Error::other(format!("Failed to merge the files of pass {}", pass)));
+        }
+
+        // Every merged run takes the place of its inputs
+        for step in ready {
+            let inputs = &steps[step].inputs;
+            let start = order.iter().position(|run| *run == inputs[0]).unwrap();
+            order.splice(start..start + inputs.len(), [file_count + step]);
+        }
+
+        // Record all files that are left after this pass
+        let files: Vec<S::Closed> = order.iter().map(|run| runs[*run].take().unwrap()).collect();
+
+        tmp_dir.record_pass(&files)?;
+
+        for (run, file) in order.iter().zip(files) {
+            runs[*run] = Some(file);
+        }
+
+        // Remove the temporary files that were merged
+        for file in merged_files {
+            file.remove();
+        }
+    }
+
+    Ok(order.into_iter().filter_map(|run| runs[run].take()).collect())
+}
+
+/// Merges the files of a single merge step into a new file
+///
+/// # Returns
+///
+/// The new file and its size, and the merged files unless they were removed, or
+/// the error of the storage
+fn merge_step<S: TmpStorage>(
+    files: Vec<S::Closed>,
+    mut tmp_file: S::Writer,
+    file_buffers: usize,
+    keep_merged_files: bool,
+    config: Configuration
+) -> io::Result<(S::Closed, u64, Vec<S::Closed>)> {
+    let mut output = config.progress.writer(&mut tmp_file);
+    let mut merged = merge_and_write::<S>(files, vec![], &mut output, file_buffers, file_buffers, config)?;
+    drop(output);
+
+    if !keep_merged_files {
+        merged.drain(..).for_each(TmpFileClosed::remove);
+    }
+
+    let file = tmp_file.close()?;
+    let size = file.size()?;
+
+    Ok((file, size, merged))
+}
+
+/// Merges sorted files and sorted in-memory chunks and writes the result to the given writer
+///
+/// # Arguments
+///
+/// * `files` - The sorted files to merge, in the order of the input
+/// * `chunks` - The sorted chunks to merge, which come after the files in the order of the input
+/// * `file` - The writer to write the merged lines to
+/// * `file_buffers` - The memory of the buffers of every file that is read, see `TmpStorage::file_buffer_size`
+/// * `output_buffers` - The memory of the buffers of the writer, if it is a temporary file
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The merged files, which can be removed once they are no longer needed, or the
+/// error of the writer or the files
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(files = files.len(), chunks = chunks.len(), memory = tracing::field::Empty))
+)]
+pub fn merge_and_write<S: TmpStorage>(
+    files: Vec<S::Closed>,
+    chunks: Vec<Chunk>,
+    file: &mut impl Write,
+    file_buffers: usize,
+    output_buffers: usize,
+    config: Configuration
+) -> io::Result<Vec<S::Closed>> {
+    let reservation = reserve_streams(files.len(), file_buffers, output_buffers, config.buffer_size, &config);
 
-    let mut file_batches = into_chunks(files, chunk_size).into_iter();
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("memory", reservation.bytes());
 
-    while let Some(file_batch) = file_batches.next() {
-        let mut tmp_file = tmp_dir.create_new_file();
-        let sender = file_sender.clone();
+    let opened_files: Vec<S::Reader> = files
+        .into_iter()
+        .map(TmpFileClosed::reopen)
+        .collect::<io::Result<_>>()?;
+
+    let readers = merge_streams(opened_files, chunks, file, reservation.bytes(), file_buffers, output_buffers, &config)?;
+
+    readers.into_iter().map(TmpFileOpened::close).collect()
+}
+
+/// The smallest amount of data that is worth merging on a separate thread
+const MIN_PARTITION_SIZE: u64 = MB;
+
+/// The bounds of the buffers that are read ahead or written behind
+const MIN_IO_BUFFER_SIZE: usize = 16 * KIB as usize;
+const MAX_IO_BUFFER_SIZE: usize = 8 * MB as usize;
+
+/// Merges sorted files and sorted in-memory chunks and writes the result to the
+/// given writer, using all threads of the threadpool. The lines are split into
+/// key ranges that are merged at the same time. The first range is merged straight
+/// into the writer, the other ranges are merged into temporary files that are
+/// copied to the writer in order.
+///
+/// # Arguments
+///
+/// * `files` - The sorted files to merge, in the order of the input
+/// * `chunks` - The sorted chunks to merge, which come after the files in the order of the input
+/// * `file` - The writer to write the merged lines to
+/// * `sorter_pool` - The threadpool to merge on
+/// * `tmp_dir` - The storage for the merged ranges
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The merged files, which can be removed once they are no longer needed, or the
+/// error of the writer or the storage
+pub fn parallel_merge_and_write<S: TmpStorage>(
+    files: Vec<S::Closed>,
+    chunks: Vec<Chunk>,
+    file: &mut impl Write,
+    sorter_pool: &ThreadPool,
+    tmp_dir: &mut S,
+    config: Configuration
+) -> io::Result<Vec<S::Closed>> {
+    let total_size: u64 = files.iter().map(TmpFileClosed::size).sum::<io::Result<u64>>()?
+        + chunks.iter().map(|chunk| chunk.bytes() as u64).sum::<u64>();
+
+    let partitions = min(config.threads as u64, total_size / MIN_PARTITION_SIZE) as usize;
+
+    if partitions < 2 {
+        return merge_and_write::<S>(files, chunks, file, tmp_dir.file_buffer_size(), 0, config);
+    }
+
+    let splitters = splitters::<S>(&files, &chunks, partitions, &config)?;
+
+    merge_partitions(files, chunks, &splitters, file, sorter_pool, tmp_dir, config)
+}
+
+/// Merges the lines between consecutive splitters at the same time. When the
+/// writer or the storage fails, the partitions on the threadpool are still merged
+/// before the error is returned, so none of their files are left behind.
+fn merge_partitions<S: TmpStorage>(
+    files: Vec<S::Closed>,
+    chunks: Vec<Chunk>,
+    splitters: &[Line],
+    file: &mut impl Write,
+    sorter_pool: &ThreadPool,
+    tmp_dir: &mut S,
+    config: Configuration
+) -> io::Result<Vec<S::Closed>> {
+    let partitions = splitters.len() + 1;
+
+    // Every partition gets its share of the memory budget
+    let budget = config.buffer_size / partitions;
+    let file_buffers = tmp_dir.file_buffer_size();
+
+    // The byte ranges of the partitions in every file
+    let bounds: Vec<Vec<u64>> = files
+        .iter()
+        .map(|file| {
+            let mut bounds = vec![0];
+            for splitter in splitters {
+                bounds.push(partition_point::<S>(file, splitter, &config)?);
+            }
+            bounds.push(file.size()?);
+
+            Ok(bounds)
+        })
+        .collect::<io::Result<_>>()?;
+
+    // The lines of the partitions in every chunk, the first partition stays in the original chunk
+    let mut chunk_parts: Vec<Vec<Chunk>> = (0..partitions).map(|_| vec![]).collect();
+    let mut chunks = chunks;
+
+    for chunk in &mut chunks {
+        let points: Vec<usize> = splitters.iter().map(|splitter| chunk.partition_point(splitter)).collect();
+
+        for (partition, point) in points.into_iter().enumerate().rev() {
+            chunk_parts[partition + 1].push(chunk.split_off(point));
+        }
+    }
+
+    let (partition_sender, partition_receiver) = channel();
+    let mut dispatched = Ok(());
+
+    for (partition, parts) in chunk_parts.into_iter().enumerate().skip(1) {
+        let opened = open_partition::<S>(&files, &bounds, partition)
+            .and_then(|readers| Ok((readers, tmp_dir.create_new_file()?)));
+
+        let (readers, mut tmp_file) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                dispatched = Err(err);
+                break;
+            }
+        };
+
+        let sender = partition_sender.clone();
         let config = config.clone();
 
         sorter_pool.execute(move || {
-            merge_and_write(file_batch, &mut tmp_file, config);
-            let _ = sender.send(tmp_file.close());
+            let reservation = reserve_streams(readers.len(), file_buffers, file_buffers, budget, &config);
+            let merged = merge_streams(readers, parts, &mut tmp_file, reservation.bytes(), file_buffers, file_buffers, &config)
+                .and_then(|_| tmp_file.close());
+            drop(reservation);
+
+            let _ = sender.send((partition, merged));
         });
     }
 
-    drop(file_sender);
+    drop(partition_sender);
+
+    // The first partition is merged on this thread, while the others are merged on the threadpool
+    let first_partition = catch_unwind(AssertUnwindSafe(|| {
+        let opened_files = dispatched.and_then(|()| open_partition::<S>(&files, &bounds, 0))?;
+
+        let reservation = reserve_streams(opened_files.len(), file_buffers, 0, budget, &config);
+        merge_streams(opened_files, chunks, file, reservation.bytes(), file_buffers, 0, &config)
+    }));
 
-    // While there is at least a single sender connected to this receiver
-    while let Ok(file) = file_reciever.recv() {
-        tmp_files.push(file);
+    // Copy the other partitions to the writer in order, as soon as they are merged
+    let mut merged_partitions: Vec<Option<io::Result<S::Closed>>> = (0..partitions).map(|_| None).collect();
+
+    let mut written = match first_partition {
+        Ok(written) => written.map(drop),
+        Err(panic) => {
+            discard_partitions(merged_partitions, partition_receiver);
+            resume_unwind(panic);
+        }
+    };
+
+    for partition in 1..partitions {
+        if written.is_err() {
+            break;
+        }
+
+        while merged_partitions[partition].is_none() {
+            match partition_receiver.recv() {
+                Ok((index, merged)) => merged_partitions[index] = Some(merged),
+
+                // A merge that panicked dropped its sender without sending its partition
+                Err(_) => merged_partitions[partition] = Some(Err(io::Error::other("Failed to merge a partition")))
+            }
+        }
+
+        written = merged_partitions[partition].take().unwrap().and_then(|merged| {
+            config.stats.add_tmp_bytes(merged.size()?);
+
+            let mut reader = merged.reopen()?;
+            let copied = io::copy(&mut reader, file).map(drop);
+            reader.close_and_remove();
+
+            copied
+        });
     }
 
-    return tmp_files;
+    if let Err(error) = written {
+        discard_partitions(merged_partitions, partition_receiver);
+        return Err(error);
+    }
+
+    Ok(files)
 }
 
-pub fn merge_and_write(files: Vec<ClosedTmpFile>, file: &mut impl Write, config: Configuration) {
-    let buffer_size = min(40 * MB as usize, config.buffer_size / files.len());
+/// Opens the byte ranges of a partition in all files, leaving out the files in
+/// which it is empty
+fn open_partition<S: TmpStorage>(files: &[S::Closed], bounds: &[Vec<u64>], partition: usize) -> io::Result<Vec<io::Take<S::Reader>>> {
+    files
+        .iter()
+        .zip(bounds)
+        .filter(|(_, bounds)| bounds[partition] < bounds[partition + 1])
+        .map(|(file, bounds)| Ok(file.read_from(bounds[partition])?.take(bounds[partition + 1] - bounds[partition])))
+        .collect()
+}
 
-    let mut opened_files: Vec<TmpFileReader> = files
+/// Waits until the partitions that are merged on the threadpool are done and
+/// removes all partitions that were merged, but not copied to the writer
+fn discard_partitions<C: TmpFileClosed>(
+    merged_partitions: Vec<Option<io::Result<C>>>,
+    partition_receiver: Receiver<(usize, io::Result<C>)>
+) {
+    merged_partitions
         .into_iter()
-        .map(|file| file.reopen())
-        .collect();
+        .flatten()
+        .chain(partition_receiver.into_iter().map(|(_, merged)| merged))
+        .flatten()
+        .for_each(TmpFileClosed::remove);
+}
 
-    let mut lines_iterators: Vec<Lines<&mut TmpFileReader>> = opened_files
-        .iter_mut()
-        .map(|file| Lines::new(file, buffer_size, config.clone()))
+/// Merges sorted readers and sorted in-memory chunks and writes the result to
+/// the given writer. The readers are read ahead and the output is written behind
+/// on separate threads, so reading and writing overlap with the merging itself.
+///
+/// # Arguments
+///
+/// * `readers` - The sorted readers to merge, in the order of the input
+/// * `chunks` - The sorted chunks to merge, which come after the readers in the order of the input
+/// * `file` - The writer to write the merged lines to
+/// * `budget` - The amount of memory to use for buffers
+/// * `file_buffers` - The memory of the buffers of every reader themselves, which is part of the budget
+/// * `output_buffers` - The memory of the buffers of the writer itself, which is part of the budget
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The readers, once they are exhausted, or the error of the writer or the readers
+fn merge_streams<R: Read + Send + 'static>(
+    readers: Vec<R>,
+    chunks: Vec<Chunk>,
+    file: &mut impl Write,
+    budget: usize,
+    file_buffers: usize,
+    output_buffers: usize,
+    config: &Configuration
+) -> io::Result<Vec<R>> {
+    // The output is a stream as well
+    let streams = readers.len() + 1;
+    let stream_budget = budget.saturating_sub(readers.len() * file_buffers + output_buffers) / streams;
+
+    // A quarter of the memory of a stream holds the three buffers that are read ahead
+    // or written behind. The rest holds the lines that are merged, which are read in
+    // chunks. The next chunk is read before the previous one is dropped, and lines
+    // take about as much memory next to a chunk as its bytes, unless they are short.
+    let io_buffer_size = (stream_budget / 12).clamp(MIN_IO_BUFFER_SIZE, MAX_IO_BUFFER_SIZE);
+    let buffer_size = min(40 * MB as usize, stream_budget.saturating_sub(3 * io_buffer_size) / 4).max(1);
+
+    let readers: Vec<ReadAhead<R>> = readers
+        .into_iter()
+        .map(|reader| ReadAhead::new(reader, io_buffer_size))
         .collect();
 
-    let mut heap: WinnerHeap<(Line, usize)> = WinnerHeap::new(
-        lines_iterators
+    // The lines are part of the memory that the caller reserved, their buffers
+    // are reused by the streams of this merge. Their bytes are not new input, so
+    // they do not mark the reject file either, the runs hold no rejected lines.
+    let stats = config.stats.clone();
+    let config = Configuration {
+        memory: MemoryBudget::unlimited(),
+        progress: Progress::disabled(),
+        stats: StatsCollector::default(),
+        missing_field: match &config.missing_field {
+            MissingField::Reject(_) => MissingField::Skip,
+            missing_field           => missing_field.clone()
+        },
+        ..config.clone()
+    };
+
+    let (readers, comparisons) = write_behind(file, io_buffer_size, move |writer| {
+        let mut readers = readers;
+
+        let lines_iterators: Vec<Box<dyn Iterator<Item = io::Result<Line>> + '_>> = readers
             .iter_mut()
-            .enumerate()
-            .map(|(i, lines)| (lines.next().unwrap(), i))
-            .collect::<Vec<(Line, usize)>>()
-    );
+            .map(|reader| Box::new(Lines::new(reader, buffer_size, config.clone())) as Box<dyn Iterator<Item = io::Result<Line>>>)
+            .chain(chunks.into_iter().map(|chunk| Box::new(chunk.map(Ok)) as Box<dyn Iterator<Item = io::Result<Line>>>))
+            .collect();
+
+        let comparisons = merge_lines(lines_iterators, writer)?;
+
+        Ok((readers, comparisons))
+    })?;
 
-    while let Some((line, lines_index)) = heap.pop() {
-        line.write(file);
+    stats.add_comparisons(comparisons);
 
-        if let Some(new_line) = lines_iterators[lines_index].next() {
-            heap.push((new_line, lines_index));
+    Ok(readers.into_iter().map(ReadAhead::into_inner).collect())
+}
+
+/// Reserves the memory of a merge, which waits until the smallest amount of
+/// memory it can run with is available
+///
+/// # Arguments
+///
+/// * `files` - The number of files that are merged
+/// * `file_buffers` - The memory of the buffers of every file that is read
+/// * `output_buffers` - The memory of the buffers of the output, if it is a temporary file
+/// * `budget` - The amount of memory the merge can use
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The reservation, which is released once the merge is done
+fn reserve_streams(files: usize, file_buffers: usize, output_buffers: usize, budget: usize, config: &Configuration) -> Reservation {
+    config.memory.reserve_between(min_merge_memory(files, file_buffers, output_buffers, config), budget)
+}
+
+/// Returns the smallest amount of memory a merge can run with. Every file needs
+/// the smallest stream buffer next to its own buffers, and so does an output
+/// that is a temporary file.
+pub fn min_merge_memory(files: usize, file_buffers: usize, output_buffers: usize, config: &Configuration) -> usize {
+    files * (config.min_stream_buffer + file_buffers) + output_buffers
+}
+
+/// Merges the lines of sorted iterators and writes them to the given writer,
+/// returns the number of comparisons or the error of the writer or the iterators.
+/// The iterators are in the order of the input, so equal lines are written in the
+/// order of the iterators they come from.
+fn merge_lines(mut lines_iterators: Vec<Box<dyn Iterator<Item = io::Result<Line>> + '_>>, file: &mut impl Write) -> io::Result<u64> {
+    let mut first_lines = vec![];
+    for (i, lines) in lines_iterators.iter_mut().enumerate() {
+        if let Some(line) = lines.next().transpose()? {
+            first_lines.push((line, Reverse(i)));
         }
     }
 
-    // Remove the temporary files that were merged
-    for file in opened_files {
-       file.close_and_remove();
+    // Of equal lines, the line of the first iterator wins
+    let mut heap: WinnerHeap<(Line, Reverse<usize>)> = WinnerHeap::new(first_lines);
+
+    while let Some((line, Reverse(lines_index))) = heap.pop() {
+        line.write(file)?;
+
+        if let Some(new_line) = lines_iterators[lines_index].next().transpose()? {
+            heap.push((new_line, Reverse(lines_index)));
+        }
+    }
+
+    Ok(heap.comparisons)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::read_dir, io::{Cursor, ErrorKind}};
+
+    use crate::{chunk::Chunks, fault::{assert_fails_cleanly, Fault, Faults, Faulty, FaultyStorage}, plan::MergePlan, tempfile::{MemoryStorage, TmpDir, TmpDirBuilder}};
+
+    use super::*;
+
+    fn write_run<S: TmpStorage>(storage: &mut S, lines: &[String]) -> S::Closed {
+        let mut file = storage.create_new_file().unwrap();
+        for line in lines {
+            writeln!(file, "{}", line).unwrap();
+        }
+
+        file.close().unwrap()
+    }
+
+    fn read_chunk(lines: &[String]) -> Chunk {
+        let input: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+
+        Chunks::new(Cursor::new(input.into_bytes()), 1 << 20, Configuration::default()).next().unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_merge_partitions() {
+        let config = Configuration { threads: 3, buffer_size: 1 << 20, ..Configuration::default() };
+        let mut storage = MemoryStorage::new();
+
+        // Interleaved runs with duplicate keys
+        let runs: Vec<Vec<String>> = (0..4)
+            .map(|run| (0..3000).map(|i| format!("{:0>5}", (i * 7 + run * 3) % 2000)).collect())
+            .map(|mut lines: Vec<String>| { lines.sort(); lines })
+            .collect();
+
+        let files: Vec<_> = runs[..3].iter().map(|lines| write_run(&mut storage, lines)).collect();
+        let chunks = vec![read_chunk(&runs[3])];
+
+        let mut expected: Vec<String> = runs.concat();
+        expected.sort();
+
+        for partitions in [2, 3, 5] {
+            let splitters = splitters::<MemoryStorage>(&files, &chunks, partitions, &config).unwrap();
+            let chunks = vec![read_chunk(&runs[3])];
+
+            let mut output = vec![];
+            let files = files.iter().map(|file| file.read_from(0).unwrap().close().unwrap()).collect();
+            merge_partitions(files, chunks, &splitters, &mut output, &ThreadPool::new(3), &mut storage, config.clone()).unwrap();
+
+            let merged: Vec<String> = String::from_utf8(output).unwrap().lines().map(str::to_string).collect();
+            assert_eq!(merged, expected);
+        }
+    }
+
+    #[test]
+    fn test_merge_partitions_output_fails() {
+        let config = Configuration { threads: 3, buffer_size: 1 << 20, ..Configuration::default() };
+
+        let runs: Vec<Vec<String>> = (0..3)
+            .map(|run| (0..3000).map(|i| format!("{:0>5}", (i * 7 + run * 3) % 2000)).collect())
+            .map(|mut lines: Vec<String>| { lines.sort(); lines })
+            .collect();
+
+        // The output fails in the first partition, which is merged on this thread,
+        // or in the last one, which is copied from its file
+        for offset in [100, 40_000] {
+            let location = ::tempfile::tempdir().unwrap();
+            let location_path = location.path().to_path_buf();
+            let mut tmp_dir = TmpDirBuilder::new().with_location(&location_path).build();
+
+            let files: Vec<_> = runs.iter().map(|lines| write_run(&mut tmp_dir, lines)).collect();
+            let splitters = splitters::<TmpDir>(&files, &[], 3, &config).unwrap();
+
+            let mut output = Faulty::new(vec![], Faults::new().at(offset, Fault::NoSpace));
+            let result = merge_partitions(files, vec![], &splitters, &mut output, &ThreadPool::new(3), &mut tmp_dir, config.clone());
+            assert_eq!(result.err().map(|error| error.kind()), Some(ErrorKind::StorageFull));
+
+            // The other partitions were merged and removed before the error was returned
+            assert_eq!(read_dir(tmp_dir.path()).unwrap().count(), runs.len());
+        }
+    }
+
+    #[test]
+    fn test_parallel_merge_small_input() {
+        let config = Configuration { threads: 4, ..Configuration::default() };
+        let mut storage = MemoryStorage::new();
+
+        let files = vec![write_run(&mut storage, &["B".to_string(), "D".to_string()])];
+        let chunks = vec![read_chunk(&["A".to_string(), "C".to_string()])];
+
+        let mut output = vec![];
+        parallel_merge_and_write(files, chunks, &mut output, &ThreadPool::new(4), &mut storage, config).unwrap();
+
+        assert_eq!(output, b"A\nB\nC\nD\n");
+    }
+
+    #[test]
+    fn test_merge_steps() {
+        let config = Configuration { threads: 2, buffer_size: 1 << 20, ..Configuration::default() };
+        let mut storage = MemoryStorage::new();
+
+        let runs: Vec<Vec<String>> = (0..6)
+            .map(|run| (0..100 * (run + 1)).map(|i| format!("{:0>5}", i * 6 + run)).collect())
+            .collect();
+
+        let files: Vec<_> = runs.iter().map(|lines| write_run(&mut storage, lines)).collect();
+        let run_sizes: Vec<u64> = files.iter().map(|file| file.size().unwrap()).collect();
+
+        let plan = MergePlan::new(&run_sizes, 0, &Configuration { chunk_size: Some(3), ..config.clone() });
+        assert_eq!(plan.steps.len(), 2);
+
+        // The two merges leave three files
+        let files = merge(files, &plan.steps, &ThreadPool::new(2), &mut storage, &config).unwrap();
+        assert_eq!(files.len(), 3);
+
+        let mut output = vec![];
+        merge_and_write::<MemoryStorage>(files, vec![], &mut output, 0, 0, config).unwrap();
+
+        let mut expected: Vec<String> = runs.concat();
+        expected.sort();
+
+        let merged: Vec<String> = String::from_utf8(output).unwrap().lines().map(str::to_string).collect();
+        assert_eq!(merged, expected);
+    }
+
+    /// Six runs of different sizes and the plan that merges them into three files
+    fn runs_and_plan(config: &Configuration) -> (Vec<Vec<String>>, MergePlan) {
+        let runs: Vec<Vec<String>> = (0..6)
+            .map(|run| (0..100 * (run + 1)).map(|i| format!("{:0>5}", i * 6 + run)).collect())
+            .collect();
+
+        let run_sizes: Vec<u64> = runs.iter().map(|lines| lines.len() as u64 * 6).collect();
+        let plan = MergePlan::new(&run_sizes, 0, &Configuration { chunk_size: Some(3), ..config.clone() });
+
+        (runs, plan)
+    }
+
+    #[test]
+    fn test_merge_retries_interrupted_io() {
+        let config = Configuration { threads: 2, buffer_size: 1 << 20, ..Configuration::default() };
+        let (runs, plan) = runs_and_plan(&config);
+
+        let faults = Faults::new().at(0, Fault::Interrupted).at(100, Fault::Short).at(101, Fault::Interrupted);
+
+        // The runs are read with faults, and the merged files are written with faults
+        let mut storage = FaultyStorage::new(MemoryStorage::new());
+        for file in 0..8 {
+            storage = storage.with_read_faults(file, faults.clone()).with_write_faults(file, faults.clone());
+        }
+
+        let files: Vec<_> = runs.iter().map(|lines| write_run(&mut storage, lines)).collect();
+        let files = merge(files, &plan.steps, &ThreadPool::new(2), &mut storage, &config).unwrap();
+        assert_eq!(storage.files(), 8);
+
+        let mut output = vec![];
+        merge_and_write::<FaultyStorage<MemoryStorage>>(files, vec![], &mut output, 0, 0, config).unwrap();
+
+        let mut expected: Vec<String> = runs.concat();
+        expected.sort();
+
+        let merged: Vec<String> = String::from_utf8(output).unwrap().lines().map(str::to_string).collect();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn test_merge_fails_cleanly() {
+        let config = Configuration { threads: 2, buffer_size: 1 << 20, ..Configuration::default() };
+        let (runs, plan) = runs_and_plan(&config);
+
+        // A run cannot be read, or the disk fills up while a merged file is written
+        let failing_storages: [fn(TmpDir) -> FaultyStorage<TmpDir>; 2] = [
+            |tmp_dir| FaultyStorage::new(tmp_dir).with_read_faults(1, Faults::new().at(300, Fault::Io)),
+            |tmp_dir| FaultyStorage::new(tmp_dir).with_write_faults(7, Faults::new().at(1000, Fault::NoSpace))
+        ];
+
+        for (fault, failing_storage) in [Fault::Io, Fault::NoSpace].into_iter().zip(failing_storages) {
+            assert_fails_cleanly(fault, |tmp_dir| {
+                let mut storage = failing_storage(tmp_dir);
+
+                let files: Vec<_> = runs.iter().map(|lines| write_run(&mut storage, lines)).collect();
+                merge(files, &plan.steps, &ThreadPool::new(2), &mut storage, &config).map(drop)
+            });
+        }
     }
 }
@@ -0,0 +1,243 @@
+use std::{io::{self, BufRead, BufReader, Read}, sync::Arc};
+
+use bytesize::KIB;
+
+use crate::{chunk::{Chunk, parse_line}, line::Line, tempfile::{TmpStorage, TmpFileClosed}, Configuration};
+
+/// The number of samples taken from every run for every partition
+const SAMPLES_PER_PARTITION: usize = 8;
+
+/// The distance between the offsets that are compared while searching a file,
+/// the last step of a search reads the lines in between
+const SEARCH_STEP: u64 = 64 * KIB;
+
+/// Picks the keys that split the lines of the runs into partitions of about
+/// the same size. Every run is sampled at evenly spaced positions and every
+/// sample is weighted by the number of bytes it stands for.
+///
+/// # Arguments
+///
+/// * `files` - The sorted files
+/// * `chunks` - The sorted in-memory chunks
+/// * `partitions` - The number of partitions to create
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// At most `partitions - 1` splitters, in sorted order, or the error of reading a file
+pub fn splitters<S: TmpStorage>(
+    files: &[S::Closed],
+    chunks: &[Chunk],
+    partitions: usize,
+    config: &Configuration
+) -> io::Result<Vec<Line>> {
+    let sample_count = SAMPLES_PER_PARTITION * partitions;
+    let mut samples: Vec<(Line, u64)> = vec![];
+
+    for file in files {
+        let size = file.size()?;
+        let weight = size / sample_count as u64;
+
+        for i in 0..sample_count as u64 {
+            if let Some((_, line)) = lines_from::<S>(file, size * i / sample_count as u64, config)?.next().transpose()? {
+                samples.push((line, weight));
+            }
+        }
+    }
+
+    for chunk in chunks {
+        let weight = (chunk.bytes() / sample_count) as u64;
+
+        for i in 0..sample_count {
+            if let Some(line) = chunk.line(chunk.len() * i / sample_count) {
+                samples.push((line.to_owned_line(), weight));
+            }
+        }
+    }
+
+    // Lines are ordered in reverse
+    samples.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+    let total: u64 = samples.iter().map(|(_, weight)| weight).sum();
+    let mut cumulative = 0;
+    let mut splitters = vec![];
+
+    for (line, weight) in samples {
+        if splitters.len() + 1 == partitions {
+            break;
+        }
+
+        cumulative += weight;
+
+        if cumulative * partitions as u64 >= total * (splitters.len() + 1) as u64 {
+            splitters.push(line);
+        }
+    }
+
+    Ok(splitters)
+}
+
+/// Finds the offset of the first line of a sorted file that does not sort before
+/// the given line. The file is searched in steps, only the lines of the last
+/// step are read one by one.
+///
+/// # Arguments
+///
+/// * `file` - The sorted file
+/// * `splitter` - The line to search for
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The offset of the line, or the size of the file if all lines sort before the
+/// given line, or the error of reading the file
+pub fn partition_point<S: TmpStorage>(file: &S::Closed, splitter: &Line, config: &Configuration) -> io::Result<u64> {
+    let size = file.size()?;
+
+    // Lines are ordered in reverse
+    let sorts_before = |line: &Line| line > splitter;
+
+    // Find the first step whose first line does not sort before the splitter
+    let mut low = 0;
+    let mut high = size.div_ceil(SEARCH_STEP);
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+
+        match lines_from::<S>(file, mid * SEARCH_STEP, config)?.next().transpose()? {
+            Some((_, line)) if sorts_before(&line) => low = mid + 1,
+            _ => high = mid
+        }
+    }
+
+    // The line starts after the beginning of the previous step
+    for line in lines_from::<S>(file, low.saturating_sub(1) * SEARCH_STEP, config)? {
+        let (offset, line) = line?;
+
+        if !sorts_before(&line) {
+            return Ok(offset);
+        }
+    }
+
+    Ok(size)
+}
+
+/// Returns an iterator over the lines of a file that start at or after the given offset
+fn lines_from<S: TmpStorage>(file: &S::Closed, offset: u64, config: &Configuration) -> io::Result<OffsetLines<S::Reader>> {
+    // Start one byte early to find out if a line starts at the offset itself
+    let start = offset.saturating_sub(1);
+    let mut lines = OffsetLines { input: BufReader::new(file.read_from(start)?), offset: start, config: config.clone() };
+
+    if offset > 0 {
+        lines.skip_line()?;
+    }
+
+    Ok(lines)
+}
+
+/// Iterator over the lines of a file and the offsets at which they start
+struct OffsetLines<R: Read> {
+    input: BufReader<R>,
+    offset: u64,
+    config: Configuration
+}
+
+impl<R: Read> OffsetLines<R> {
+    /// Skips the rest of the current line
+    fn skip_line(&mut self) -> io::Result<()> {
+        let mut bytes = vec![];
+        self.offset += self.input.read_until(b'\n', &mut bytes)? as u64;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for OffsetLines<R> {
+    type Item = io::Result<(u64, Line)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut bytes = vec![];
+            let bytes_read = match self.input.read_until(b'\n', &mut bytes) {
+                Ok(0) => return None,
+                Ok(bytes_read) => bytes_read,
+                Err(err) => return Some(Err(err))
+            };
+
+            let offset = self.offset;
+            self.offset += bytes_read as u64;
+
+            if bytes.last() != Some(&b'\n') {
+                bytes.push(b'\n');
+            }
+
+            let end_index = bytes.len() - 1;
+
+            // Lines that are left out because they miss the sort field are left out here as well
+            if let Some(line) = parse_line(&Arc::new(bytes), 0, end_index, &self.config) {
+                return Some(Ok((offset, line)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::tempfile::{MemoryStorage, TmpFileOpened};
+
+    use super::*;
+
+    fn write_file(lines: &[String]) -> <MemoryStorage as TmpStorage>::Closed {
+        let mut file = MemoryStorage::new().create_new_file().unwrap();
+        for line in lines {
+            writeln!(file, "{}", line).unwrap();
+        }
+
+        file.close().unwrap()
+    }
+
+    fn owned_line(content: &str) -> Line {
+        let bytes = format!("{}\n", content).into_bytes();
+        let end_index = bytes.len() - 1;
+
+        parse_line(&Arc::new(bytes), 0, end_index, &Configuration::default()).unwrap()
+    }
+
+    #[test]
+    fn test_partition_point() {
+        let lines: Vec<String> = (0..20000).map(|i| format!("{:0>6}", i * 2)).collect();
+        let file = write_file(&lines);
+        let config = Configuration::default();
+
+        for key in [0, 1, 2, 9999, 10000, 24000, 39998, 39999, 50000] {
+            let expected = lines.iter().take_while(|line| **line < format!("{:0>6}", key)).count() as u64 * 7;
+
+            assert_eq!(partition_point::<MemoryStorage>(&file, &owned_line(&format!("{:0>6}", key)), &config).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_lines_from() {
+        let file = write_file(&["AA".to_string(), "BBB".to_string(), "C".to_string()]);
+        let config = Configuration::default();
+
+        let offsets: Vec<u64> = lines_from::<MemoryStorage>(&file, 3, &config).unwrap().map(|line| line.unwrap().0).collect();
+        assert_eq!(offsets, vec![3, 7]);
+
+        let offsets: Vec<u64> = lines_from::<MemoryStorage>(&file, 4, &config).unwrap().map(|line| line.unwrap().0).collect();
+        assert_eq!(offsets, vec![7]);
+    }
+
+    #[test]
+    fn test_splitters() {
+        let lines: Vec<String> = (0..10000).map(|i| format!("{:0>6}", i)).collect();
+        let files = vec![write_file(&lines[..5000]), write_file(&lines[5000..])];
+
+        let splitters = splitters::<MemoryStorage>(&files, &[], 4, &Configuration::default()).unwrap();
+
+        assert_eq!(splitters.len(), 3);
+        assert!(splitters.windows(2).all(|pair| pair[0] >= pair[1]));
+    }
+}
@@ -0,0 +1,326 @@
+use std::{cmp::{max, min}, fmt};
+
+use bytesize::ByteSize;
+
+use crate::Configuration;
+
+/// How the sorted runs are merged into the output
+#[derive(Debug, PartialEq, Eq)]
+pub struct MergePlan {
+    /// The number of runs
+    pub runs: usize,
+
+    /// The total size of the runs
+    pub bytes: u64,
+
+    /// The number of runs that are merged at once
+    pub fan_in: usize,
+
+    /// The number of passes over the data, including the final merge
+    pub passes: u32,
+
+    /// The buffer size of a single stream when `fan_in` runs are merged
+    pub stream_buffer: usize,
+
+    /// The merges that happen before the final merge, in an order in which
+    /// every merge comes after the merges that produce its inputs
+    pub steps: Vec<MergeStep>,
+
+    /// The number of bytes the merges before the final merge would write if
+    /// every pass merged all runs
+    pub full_pass_bytes: u64
+}
+
+/// A merge of several runs into a new run
+#[derive(Debug, PartialEq, Eq)]
+pub struct MergeStep {
+    /// The runs to merge, which are neighbours in the order of the input. The
+    /// initial runs are numbered by their position in the list of runs, the run
+    /// produced by step `i` is numbered `runs + i` and takes the place of its inputs.
+    pub inputs: Vec<usize>,
+
+    /// The size of the new run
+    pub bytes: u64
+}
+
+impl MergePlan {
+    /// Picks the smallest fan-in that merges the runs in as few passes as the
+    /// memory allows. Every merge runs next to `threads - 1` others, so it gets
+    /// its share of the buffer, which is divided over the runs it reads and its
+    /// output. A run that is smaller than the minimum stream buffer only needs
+    /// a buffer of its own size, next to the buffers of its file.
+    ///
+    /// # Arguments
+    ///
+    /// * `run_sizes` - The sizes of the sorted runs
+    /// * `file_buffers` - The memory of the buffers of every open file, see `TmpStorage::file_buffer_size`
+    /// * `config` - Some additional configuration options, `chunk_size` fixes the fan-in
+    ///
+    /// # Returns
+    ///
+    /// The merge plan
+    pub fn new(run_sizes: &[u64], file_buffers: usize, config: &Configuration) -> Self {
+        let runs = run_sizes.len();
+        let budget = config.thread_buffer_size();
+        let min_stream_buffer = config.min_stream_buffer.max(1);
+
+        // Every run and the output are files with buffers of their own
+        let min_stream_memory = min_stream_buffer + file_buffers;
+
+        let max_fan_in = match config.chunk_size {
+            Some(fan_in) => fan_in.max(2),
+            None => {
+                // Small runs can all be merged at once, even if there are many of them
+                let needed: u64 = run_sizes.iter().map(|size| (*size).min(min_stream_buffer as u64) + file_buffers as u64).sum();
+
+                if needed + min_stream_memory as u64 <= budget as u64 {
+                    runs.max(2)
+                } else {
+                    (budget / min_stream_memory).saturating_sub(1).max(2)
+                }
+            }
+        };
+
+        // The number of passes with the largest fan-in, if every pass merged all runs
+        let mut full_passes = 1;
+        let mut capacity = max_fan_in;
+        while capacity < runs {
+            capacity = capacity.saturating_mul(max_fan_in);
+            full_passes += 1;
+        }
+
+        // A smaller fan-in with the same number of passes leaves more memory per stream
+        let fan_in = match config.chunk_size {
+            Some(_) => max_fan_in,
+            None => (2..=max_fan_in)
+                .find(|fan_in| fan_in.checked_pow(full_passes).is_none_or(|capacity| capacity >= runs))
+                .unwrap_or(max_fan_in)
+        };
+
+        // The smallest neighbouring runs are merged first, so they can go through more merges
+        let steps = merge_steps(run_sizes, fan_in);
+
+        MergePlan {
+            runs,
+            bytes: run_sizes.iter().sum(),
+            fan_in,
+            passes: merge_depth(runs, &steps) + 1,
+            stream_buffer: (budget / (fan_in.min(runs.max(1)) + 1)).saturating_sub(file_buffers),
+            steps,
+            full_pass_bytes: full_pass_bytes(run_sizes, fan_in, config.threads)
+        }
+    }
+
+    /// Returns the number of bytes the merges before the final merge write
+    pub fn rewritten_bytes(&self) -> u64 {
+        self.steps.iter().map(|step| step.bytes).sum()
+    }
+}
+
+/// Plans the merges that reduce the runs to at most `fan_in`, which keeps the
+/// number of bytes that are written low. Like a Huffman code, the smallest runs
+/// are merged first, but only neighbouring runs are merged, so the lines with
+/// equal keys keep the order of the input. The first merge takes just enough runs
+/// for every other merge to take `fan_in` runs, so the smallest runs are the ones
+/// that are merged more often.
+fn merge_steps(run_sizes: &[u64], fan_in: usize) -> Vec<MergeStep> {
+    let mut steps = vec![];
+
+    if run_sizes.len() <= fan_in {
+        return steps;
+    }
+
+    // The size and number of the runs that are left, in the order of the input
+    let mut runs: Vec<(u64, usize)> = run_sizes.iter().copied().zip(0..).collect();
+
+    let mut merge_size = (runs.len() - 2) % (fan_in - 1) + 2;
+
+    while runs.len() > fan_in {
+        // The first of the neighbouring runs with the smallest size
+        let mut window: u64 = runs[..merge_size].iter().map(|(size, _)| size).sum();
+        let (mut start, mut bytes) = (0, window);
+
+        for next in merge_size..runs.len() {
+            window = window + runs[next].0 - runs[next - merge_size].0;
+
+            if window < bytes {
+                (start, bytes) = (next + 1 - merge_size, window);
+            }
+        }
+
+        let inputs = runs
+            .splice(start..start + merge_size, [(bytes, run_sizes.len() + steps.len())])
+            .map(|(_, run)| run)
+            .collect();
+
+        steps.push(MergeStep { inputs, bytes });
+        merge_size = fan_in;
+    }
+
+    steps
+}
+
+/// Returns the number of passes of the merges before the final merge. A merge
+/// happens in the pass after the last of its inputs.
+///
+/// # Arguments
+///
+/// * `runs` - The number of initial runs
+/// * `steps` - The merges, after the merges that produce their inputs
+///
+/// # Returns
+///
+/// The number of passes, 0 if there are no merges
+pub fn merge_depth(runs: usize, steps: &[MergeStep]) -> u32 {
+    let mut step_passes = vec![0; runs + steps.len()];
+
+    for (step, merge_step) in steps.iter().enumerate() {
+        step_passes[runs + step] = 1 + merge_step.inputs.iter().map(|input| step_passes[*input]).max().unwrap_or(0);
+    }
+
+    step_passes.into_iter().max().unwrap_or(0)
+}
+
+/// Returns the number of bytes that are written when every pass merges all
+/// runs in batches, until at most `fan_in` runs are left
+fn full_pass_bytes(run_sizes: &[u64], fan_in: usize, threads: usize) -> u64 {
+    let total: u64 = run_sizes.iter().sum();
+    let threads = threads.max(1);
+
+    let mut runs = run_sizes.len();
+    let mut bytes = 0;
+
+    while runs > fan_in {
+        // Smaller batches spread a pass over all threads
+        let batch = if runs < fan_in * threads { min(fan_in, max(2, runs / threads)) } else { fan_in };
+
+        runs = runs.div_ceil(batch);
+        bytes += total;
+    }
+
+    bytes
+}
+
+impl fmt::Display for MergePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Merging {} runs ({}) with a fan-in of {} in {} pass{}, with {} per stream",
+            self.runs,
+            ByteSize(self.bytes),
+            self.fan_in,
+            self.passes,
+            if self.passes == 1 { "" } else { "es" },
+            ByteSize(self.stream_buffer as u64)
+        )?;
+
+        if !self.steps.is_empty() {
+            write!(
+                f,
+                "\n{} merges before the final merge write {}, {} less than merging all runs in every pass",
+                self.steps.len(),
+                ByteSize(self.rewritten_bytes()),
+                ByteSize(self.full_pass_bytes.saturating_sub(self.rewritten_bytes()))
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytesize::MB;
+
+    use super::*;
+
+    fn config(buffer_size: usize) -> Configuration {
+        Configuration { threads: 1, buffer_size, min_stream_buffer: MB as usize, ..Configuration::default() }
+    }
+
+    #[test]
+    fn test_single_pass() {
+        let plan = MergePlan::new(&[10 * MB; 300], 0, &config(400 * MB as usize));
+
+        assert_eq!(plan.fan_in, 300);
+        assert_eq!(plan.passes, 1);
+    }
+
+    #[test]
+    fn test_multiple_passes() {
+        // 99 streams fit in memory, so 1000 runs need two passes of 32
+        let plan = MergePlan::new(&[10 * MB; 1000], 0, &config(100 * MB as usize));
+
+        assert_eq!(plan.fan_in, 32);
+        assert_eq!(plan.passes, 2);
+    }
+
+    #[test]
+    fn test_file_buffers() {
+        // The buffers of the files leave room for 199 streams instead of 399
+        let plan = MergePlan::new(&[10 * MB; 300], MB as usize, &config(400 * MB as usize));
+
+        assert_eq!(plan.fan_in, 18);
+        assert_eq!(plan.passes, 2);
+        assert_eq!(plan.stream_buffer, 400 * MB as usize / 19 - MB as usize);
+    }
+
+    #[test]
+    fn test_small_runs() {
+        // Runs smaller than the minimum stream buffer only need their own size
+        let plan = MergePlan::new(&[MB / 10; 500], 0, &config(100 * MB as usize));
+
+        assert_eq!(plan.fan_in, 500);
+        assert_eq!(plan.passes, 1);
+    }
+
+    #[test]
+    fn test_merge_steps() {
+        let steps = merge_steps(&[100, 1, 2, 3, 4, 5], 3);
+
+        // The first merge takes two runs, so the second one can take three
+        assert_eq!(steps, vec![
+            MergeStep { inputs: vec![1, 2], bytes: 3 },
+            MergeStep { inputs: vec![6, 3, 4], bytes: 10 }
+        ]);
+    }
+
+    #[test]
+    fn test_merge_steps_merge_neighbours() {
+        // The two smallest runs are apart, so the smallest neighbours are merged instead
+        let steps = merge_steps(&[1, 50, 2, 60, 3, 70], 5);
+
+        assert_eq!(steps, vec![MergeStep { inputs: vec![0, 1], bytes: 51 }]);
+    }
+
+    #[test]
+    fn test_passes_follow_merge_steps() {
+        // Three runs of three would take two passes, but the two smallest runs are
+        // merged first and their run is merged again before the final merge
+        let config = Configuration { chunk_size: Some(3), ..config(400 * MB as usize) };
+        let plan = MergePlan::new(&[100, 1, 2, 3, 4, 5], 0, &config);
+
+        assert_eq!(merge_depth(6, &plan.steps), 2);
+        assert_eq!(plan.passes, 3);
+        assert_eq!(merge_depth(6, &[]), 0);
+    }
+
+    #[test]
+    fn test_merge_steps_save_bytes() {
+        let run_sizes: Vec<u64> = (0..100).map(|run| if run % 10 == 0 { 100 * MB } else { MB }).collect();
+        let plan = MergePlan::new(&run_sizes, 0, &Configuration { chunk_size: Some(8), ..config(400 * MB as usize) });
+
+        assert!(plan.steps.len() > 1);
+        assert!(plan.rewritten_bytes() < plan.full_pass_bytes);
+        assert_eq!(plan.runs - plan.steps.iter().map(|step| step.inputs.len() - 1).sum::<usize>(), 8);
+    }
+
+    #[test]
+    fn test_fixed_fan_in() {
+        let config = Configuration { chunk_size: Some(16), ..config(400 * MB as usize) };
+        let plan = MergePlan::new(&[10 * MB; 300], 0, &config);
+
+        assert_eq!(plan.fan_in, 16);
+        assert_eq!(plan.passes, 3);
+    }
+}
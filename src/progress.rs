@@ -0,0 +1,288 @@
+use std::{io::{self, IsTerminal, Write}, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, mpsc::{channel, RecvTimeoutError, Sender}, Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use bytesize::{ByteSize, MIB};
+
+/// How often the progress bar is redrawn on a terminal
+const BAR_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often a progress line is logged when stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The width of the progress bar
+const BAR_WIDTH: usize = 30;
+
+/// Writers only report their bytes once they wrote this many
+const REPORT_STEP: u64 = MIB;
+
+/// Keeps track of the progress of a sort. Clones share the same progress, a
+/// disabled progress ignores everything.
+#[derive(Clone, Default)]
+pub struct Progress {
+    state: Option<Arc<ProgressState>>
+}
+
+struct ProgressState {
+    /// The size of the input, if it is known
+    input_size: Option<u64>,
+
+    /// The current phase
+    phase: Mutex<Phase>,
+
+    /// The number of bytes that the current phase processed
+    bytes: AtomicU64,
+
+    /// The number of sorted runs that were created
+    runs: AtomicUsize
+}
+
+struct Phase {
+    name: String,
+
+    /// The number of bytes that the phase processes, if it is known
+    total: Option<u64>,
+
+    started: Instant
+}
+
+impl Progress {
+    /// Creates a progress that is reported while the sort runs
+    ///
+    /// # Arguments
+    ///
+    /// * `input_size` - The size of the input, if it is known
+    pub fn new(input_size: Option<u64>) -> Self {
+        let phase = Phase { name: "Starting".to_string(), total: None, started: Instant::now() };
+
+        Progress {
+            state: Some(Arc::new(ProgressState {
+                input_size,
+                phase: Mutex::new(phase),
+                bytes: AtomicU64::new(0),
+                runs: AtomicUsize::new(0)
+            }))
+        }
+    }
+
+    /// Creates a progress that is not reported
+    pub fn disabled() -> Self {
+        Progress { state: None }
+    }
+
+    /// Returns the size of the input, if it is known
+    pub fn input_size(&self) -> Option<u64> {
+        self.state.as_ref().and_then(|state| state.input_size)
+    }
+
+    /// Starts a new phase of the sort
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the phase
+    /// * `total` - The number of bytes that the phase processes, if it is known
+    pub fn start_phase(&self, name: impl Into<String>, total: Option<u64>) {
+        if let Some(state) = &self.state {
+            *state.phase.lock().unwrap() = Phase { name: name.into(), total, started: Instant::now() };
+            state.bytes.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds bytes that the current phase processed
+    pub fn add_bytes(&self, bytes: u64) {
+        if let Some(state) = &self.state {
+            state.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a sorted run that was created
+    pub fn add_run(&self) {
+        if let Some(state) = &self.state {
+            state.runs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wraps a writer, so the bytes that are written to it count as progress
+    pub fn writer<W: Write>(&self, writer: W) -> ProgressWriter<W> {
+        ProgressWriter { writer, progress: self.clone(), unreported: 0 }
+    }
+
+    /// Describes the progress of the current phase in a single line, with its
+    /// throughput and the time it still needs if its size is known
+    pub fn describe(&self) -> Option<String> {
+        let state = self.state.as_ref()?;
+        let phase = state.phase.lock().unwrap();
+
+        let bytes = state.bytes.load(Ordering::Relaxed);
+        let elapsed = phase.started.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0.0 { bytes as f64 / elapsed } else { 0.0 };
+
+        let mut line = phase.name.clone();
+
+        if let Some(total) = phase.total.filter(|total| *total > 0) {
+            let fraction = (bytes as f64 / total as f64).min(1.0);
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+
+            line += &format!(" [{}{}] {:>3.0}%", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled), fraction * 100.0);
+
+            if throughput > 0.0 && bytes < total {
+                let remaining = total.saturating_sub(bytes) as f64 / throughput;
+                line += &format!(", ETA {}", format_duration(remaining));
+            }
+        } else {
+            line += &format!(", {}", ByteSize(bytes));
+        }
+
+        line += &format!(", {}/s", ByteSize(throughput as u64));
+
+        let runs = state.runs.load(Ordering::Relaxed);
+        if runs > 0 {
+            line += &format!(", {} runs", runs);
+        }
+
+        Some(line)
+    }
+
+    /// Starts reporting the progress on stderr. A terminal shows a progress bar,
+    /// otherwise a line is logged every few seconds.
+    ///
+    /// # Returns
+    ///
+    /// The reporter, which stops once it is dropped
+    pub fn start_reporter(&self) -> Option<Reporter> {
+        self.state.as_ref()?;
+
+        let progress = self.clone();
+        let terminal = io::stderr().is_terminal();
+        let interval = if terminal { BAR_INTERVAL } else { LOG_INTERVAL };
+
+        let (stop, stopped) = channel::<()>();
+
+        let thread = thread::spawn(move || {
+            loop {
+                // The reporter is stopped by dropping the sender
+                let finished = !matches!(stopped.recv_timeout(interval), Err(RecvTimeoutError::Timeout));
+
+                if let Some(line) = progress.describe() {
+                    let mut stderr = io::stderr().lock();
+
+                    // Redraw the same line on a terminal
+                    let _ = if terminal {
+                        write!(stderr, "\r\x1b[K{}{}", line, if finished { "\n" } else { "" })
+                    } else {
+                        writeln!(stderr, "{}", line)
+                    };
+                }
+
+                if finished {
+                    break;
+                }
+            }
+        });
+
+        Some(Reporter { stop: Some(stop), thread: Some(thread) })
+    }
+}
+
+/// Reports the progress on a separate thread until it is dropped
+pub struct Reporter {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A writer that counts the bytes that are written to it as progress
+pub struct ProgressWriter<W: Write> {
+    writer: W,
+    progress: Progress,
+
+    /// The bytes that are written, but not added to the progress yet
+    unreported: u64
+}
+
+impl<W: Write> ProgressWriter<W> {
+    fn report(&mut self) {
+        self.progress.add_bytes(self.unreported);
+        self.unreported = 0;
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.writer.write(buf)?;
+
+        // The progress is shared by all threads, so it is only updated once in a while
+        self.unreported += bytes_written as u64;
+        if self.unreported >= REPORT_STEP {
+            self.report();
+        }
+
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.report();
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for ProgressWriter<W> {
+    fn drop(&mut self) {
+        self.report();
+    }
+}
+
+/// Formats a number of seconds like `1h02m`, `3m20s` or `12s`
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+
+    match seconds {
+        0..=59    => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _         => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let progress = Progress::new(Some(1000));
+        progress.start_phase("Sorting", Some(1000));
+        progress.add_bytes(500);
+        progress.add_run();
+
+        let line = progress.describe().unwrap();
+        assert!(line.starts_with("Sorting [###############---------------]  50%, ETA "));
+        assert!(line.ends_with(", 1 runs"));
+
+        assert_eq!(Progress::disabled().describe(), None);
+    }
+
+    #[test]
+    fn test_progress_writer() {
+        let progress = Progress::new(None);
+
+        let mut writer = progress.writer(vec![]);
+        writer.write_all(b"0123456789").unwrap();
+        drop(writer);
+
+        assert_eq!(progress.state.unwrap().bytes.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(12.3), "12s");
+        assert_eq!(format_duration(200.0), "3m20s");
+        assert_eq!(format_duration(3720.0), "1h02m");
+    }
+}
@@ -0,0 +1,238 @@
+use std::{fs::{File, OpenOptions}, io::{self, BufWriter, Write}, path::Path, sync::{Arc, Mutex}};
+
+/// The file that lines without the sort field are written to, each after its
+/// line number and a tab. Clones share the same writer.
+#[derive(Clone)]
+pub struct RejectFile {
+    inner: Arc<Mutex<Rejects>>
+}
+
+struct Rejects {
+    writer: Box<dyn Write + Send>,
+
+    /// The file behind the writer, which can be truncated when a sort is resumed
+    file: Option<File>,
+
+    /// The number of bytes that were written
+    bytes: u64,
+
+    /// The first error of the writer, after which nothing is written
+    error: Option<io::Error>,
+
+    /// The number of bytes that were written once the input was read up to an
+    /// offset, for every offset at which a chunk of the input ended
+    marks: Vec<(u64, u64)>
+}
+
+impl RejectFile {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        RejectFile {
+            inner: Arc::new(Mutex::new(Rejects { writer: Box::new(writer), file: None, bytes: 0, error: None, marks: vec![] }))
+        }
+    }
+
+    /// Opens a reject file on disk
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file, which is created if it does not exist
+    /// * `resume` - Whether the file belongs to a resumed sort, which truncates it to the
+    ///   rejects of the runs it recovers. The file is emptied otherwise.
+    pub fn open(path: &Path, resume: bool) -> io::Result<Self> {
+        // Appending keeps writing at the end once the file is truncated
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        if !resume {
+            file.set_len(0)?;
+        }
+
+        let bytes = file.metadata()?.len();
+        let writer = Box::new(BufWriter::new(file.try_clone()?));
+
+        Ok(RejectFile { inner: Arc::new(Mutex::new(Rejects { writer, file: Some(file), bytes, error: None, marks: vec![] })) })
+    }
+
+    /// Writes a rejected line. Lines are read on many threads, so an error is
+    /// kept until the file is flushed and the lines after it are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `line_number` - The number of the line in the input, starting at 1
+    /// * `line` - The bytes of the line, without its newline
+    pub fn write(&self, line_number: u64, line: &[u8]) {
+        let mut rejects = self.inner.lock().unwrap();
+
+        if rejects.error.is_some() {
+            return;
+        }
+
+        let prefix = format!("{}\t", line_number);
+        let written = rejects.writer.write_all(prefix.as_bytes())
+            .and_then(|_| rejects.writer.write_all(line))
+            .and_then(|_| rejects.writer.write_all(b"\n"));
+
+        match written {
+            Ok(()) => rejects.bytes += (prefix.len() + line.len() + 1) as u64,
+            Err(error) => rejects.error = Some(error)
+        }
+    }
+
+    /// Returns true if a rejected line could not be written
+    pub fn failed(&self) -> bool {
+        self.inner.lock().unwrap().error.is_some()
+    }
+
+    /// Flushes the rejected lines, or returns the first error of the writer
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+
+    /// Records that the input was read up to the given offset, so `bytes_at`
+    /// knows which rejects belong to the input before it
+    pub fn mark(&self, input_offset: u64) {
+        let mut rejects = self.inner.lock().unwrap();
+        let bytes = rejects.bytes;
+
+        rejects.marks.push((input_offset, bytes));
+    }
+
+    /// Returns the input offsets that were marked
+    #[cfg(test)]
+    pub fn marks(&self) -> Vec<u64> {
+        self.inner.lock().unwrap().marks.iter().map(|(offset, _)| *offset).collect()
+    }
+
+    /// Returns the number of bytes of the rejects of the input before the
+    /// given offset, which has to be marked
+    pub fn bytes_at(&self, input_offset: u64) -> u64 {
+        let rejects = self.inner.lock().unwrap();
+
+        // The input is read in order, so the marks are sorted
+        let marks = rejects.marks.partition_point(|(offset, _)| *offset <= input_offset);
+        marks.checked_sub(1).map_or(0, |mark| rejects.marks[mark].1)
+    }
+
+    /// Throws away the rejects after the given number of bytes, which were
+    /// written by an interrupted sort for input that is read again
+    pub fn truncate(&self, bytes: u64) -> io::Result<()> {
+        let mut rejects = self.inner.lock().unwrap();
+
+        if rejects.bytes == bytes {
+            return Ok(());
+        }
+
+        rejects.flush()?;
+        rejects.file.as_ref()
+            .expect("Only a reject file on disk can be truncated")
+            .set_len(bytes)?;
+        rejects.bytes = bytes;
+
+        Ok(())
+    }
+}
+
+impl Rejects {
+    fn flush(&mut self) -> io::Result<()> {
+        // The error stays, so every flush after it fails as well
+        if let Some(error) = &self.error {
+            return Err(io::Error::new(error.kind(), error.to_string()));
+        }
+
+        self.writer.flush()
+    }
+}
+
+/// A writer whose content can still be read after it is moved into a `RejectFile`
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuffer {
+    pub fn content(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use crate::fault::{Fault, Faults, Faulty};
+
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let buffer = SharedBuffer::default();
+        let rejects = RejectFile::new(buffer.clone());
+
+        rejects.write(3, b"AAALTER");
+        rejects.clone().write(12, b"");
+        rejects.flush().unwrap();
+
+        assert_eq!(buffer.content(), b"3\tAAALTER\n12\t\n");
+    }
+
+    #[test]
+    fn test_bytes_at() {
+        let rejects = RejectFile::new(SharedBuffer::default());
+
+        rejects.mark(10);
+        rejects.write(12, b"AAALTER");
+        rejects.mark(20);
+        rejects.mark(30);
+        rejects.write(31, b"");
+
+        assert_eq!(rejects.bytes_at(10), 0);
+        assert_eq!(rejects.bytes_at(20), 11);
+        assert_eq!(rejects.bytes_at(30), 11);
+    }
+
+    #[test]
+    fn test_write_error() {
+        let rejects = RejectFile::new(Faulty::new(vec![], Faults::new().at(6, Fault::NoSpace)));
+
+        rejects.write(1, b"A");
+        assert!(!rejects.failed());
+
+        // The lines after the error are dropped, and every flush fails
+        rejects.write(2, b"B");
+        rejects.write(3, b"C");
+        assert!(rejects.failed());
+        assert_eq!(rejects.flush().unwrap_err().kind(), io::ErrorKind::StorageFull);
+        assert_eq!(rejects.flush().unwrap_err().kind(), io::ErrorKind::StorageFull);
+    }
+
+    #[test]
+    fn test_open_and_truncate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rejects");
+
+        let rejects = RejectFile::open(&path, false).unwrap();
+        rejects.write(1, b"A");
+        rejects.write(2, b"B");
+        rejects.truncate(4).unwrap();
+        rejects.write(3, b"C");
+        rejects.flush().unwrap();
+        assert_eq!(read(&path).unwrap(), b"1\tA\n3\tC\n");
+
+        // A resumed sort keeps the rejects, a new one starts over
+        RejectFile::open(&path, true).unwrap().flush().unwrap();
+        assert_eq!(read(&path).unwrap(), b"1\tA\n3\tC\n");
+
+        RejectFile::open(&path, false).unwrap().flush().unwrap();
+        assert_eq!(read(&path).unwrap(), b"");
+    }
+}
@@ -0,0 +1,215 @@
+use std::{cmp::Reverse, io::{self, Read}, mem::size_of};
+
+use crate::{budget::Reservation, chunk::Chunks, heap::WinnerHeap, line::Line, tempfile::{TmpStorage, TmpFileOpened, TmpFileClosed, InputRange}, Configuration};
+
+/// Turns the input into sorted runs with replacement selection. All lines that
+/// fit in the buffer are kept in a tournament tree. The smallest line is written
+/// to the current run and replaced by the next line of the input once it fits,
+/// which joins the current run if it does not sort before the line that was
+/// just written.
+///
+/// # Arguments
+///
+/// * `input_chunks` - The chunks of the input
+/// * `tmp_dir` - The storage to write the runs to
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The sorted runs, or the error of the input or the storage
+pub fn replacement_selection<S: TmpStorage>(
+    input_chunks: &mut Chunks<impl Read>,
+    tmp_dir: &mut S,
+    config: &Configuration
+) -> io::Result<Vec<S::Closed>> {
+    let start = input_chunks.offset();
+
+    // The buffer holds the tree and the input chunk that is read, with its lines
+    let mut reservation = config.memory.reserve(config.buffer_size);
+    let tree_size = config.buffer_size.saturating_sub(2 * input_chunks.buffer_size());
+
+    // Lines are copied, otherwise a single line keeps the buffer of a whole chunk
+    // alive. A chunk that cannot be read ends the lines with its error.
+    let mut input_lines = input_chunks.flat_map(|chunk| {
+        let (chunk, error) = match chunk {
+            Ok(chunk) => (Some(chunk), None),
+            Err(err) => (None, Some(Err(err)))
+        };
+
+        chunk.into_iter().flatten().map(|line| Ok(line.to_owned_line())).chain(error)
+    });
+
+    // Fill the tree with the first lines of the input that fit in it
+    let mut memory_used = 0;
+    let mut leaves: Vec<(Reverse<usize>, Line, Reverse<u64>)> = vec![];
+    let mut next_line = input_lines.next().transpose()?;
+
+    // The number of lines that went into the tree
+    let mut line_count = 0;
+
+    while let Some(line) = next_line.take() {
+        if !leaves.is_empty() && memory_used + line_memory(&line) > tree_size {
+            next_line = Some(line);
+            break;
+        }
+
+        memory_used += line_memory(&line);
+        leaves.push((Reverse(0), line, Reverse(line_count)));
+        line_count += 1;
+        next_line = input_lines.next().transpose()?;
+    }
+
+    reserve_excess(&mut reservation, memory_used.saturating_sub(tree_size), config);
+
+    // Lines of an earlier run win from lines of a later run, and equal lines
+    // of the same run keep the order of the input
+    let mut heap = WinnerHeap::new(leaves);
+
+    let mut tmp_files: Vec<S::Closed> = vec![];
+    let mut tmp_file = tmp_dir.create_new_file()?;
+    let mut current_run = 0;
+
+    while let Some((Reverse(run), line, _)) = heap.pop() {
+        memory_used -= line_memory(&line);
+
+        if run != current_run {
+            close_run(tmp_file, &mut tmp_files, config)?;
+            tmp_file = tmp_dir.create_new_file()?;
+            current_run = run;
+        }
+
+        line.write(&mut tmp_file)?;
+
+        // A line that does not fit waits until more lines are written, which gives up
+        // the place of this line in the tree. A line that is larger than the whole tree
+        // goes in once the tree is empty.
+        if let Some(next) = next_line.take_if(|next| memory_used == 0 || memory_used + line_memory(next) <= tree_size) {
+            memory_used += line_memory(&next);
+            next_line = input_lines.next().transpose()?;
+
+            // Lines are ordered in reverse, so a greater line sorts before the line
+            // that was just written and has to wait for the next run
+            let next_run = if next > line { run + 1 } else { run };
+
+            heap.push((Reverse(next_run), next, Reverse(line_count)));
+            line_count += 1;
+        }
+
+        reserve_excess(&mut reservation, memory_used.saturating_sub(tree_size), config);
+    }
+
+    close_run(tmp_file, &mut tmp_files, config)?;
+    config.stats.add_comparisons(heap.comparisons);
+
+    // Every run can hold lines from anywhere in the input, so the runs are
+    // only complete once the whole input has been read
+    let range = InputRange { start, end: input_chunks.offset(), checksum: input_chunks.checksum() };
+    for file in &tmp_files {
+        tmp_dir.record_run(range, file)?;
+    }
+
+    Ok(tmp_files)
+}
+
+/// Returns the memory of a line in the tree: its leaf and node, and the copy of
+/// its bytes with the counters and vector of the `Arc` it is copied into
+fn line_memory(line: &Line) -> usize {
+    size_of::<Option<(Reverse<usize>, Line, Reverse<u64>)>>() + size_of::<Option<usize>>() + 2 * size_of::<usize>() + size_of::<Vec<u8>>() + line.size() + 1
+}
+
+/// Reserves the memory that the tree takes on top of the buffer, which only a
+/// line that is larger than the whole tree can take
+fn reserve_excess(reservation: &mut Reservation, excess: usize, config: &Configuration) {
+    let bytes = config.buffer_size + excess;
+
+    if bytes > reservation.bytes() {
+        reservation.grow(bytes - reservation.bytes());
+    } else {
+        reservation.shrink_to(bytes);
+    }
+}
+
+/// Closes a finished run and counts it
+fn close_run<W: TmpFileOpened>(tmp_file: W, tmp_files: &mut Vec<W::Closed>, config: &Configuration) -> io::Result<()> {
+    let file = tmp_file.close()?;
+    let size = file.size()?;
+
+    config.progress.add_run();
+    config.stats.add_run(size);
+    config.stats.add_tmp_bytes(size);
+
+    tmp_files.push(file);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use crate::{line::Lines, tempfile::MemoryStorage};
+
+    use super::*;
+
+    /// Generates runs with a tree that holds the given number of lines of a single byte
+    fn generate_runs(input: &str, tree_lines: usize) -> Vec<Vec<String>> {
+        generate_runs_on_field(input, tree_lines, 1)
+    }
+
+    /// Generates runs of lines that are sorted on the given field
+    fn generate_runs_on_field(input: &str, tree_lines: usize, field: usize) -> Vec<Vec<String>> {
+        // The input chunks take twice their buffer next to the tree
+        let line = Line::new(Arc::new(b"A".to_vec()), 0, 0);
+        let config = Configuration { buffer_size: 2 * 16 + tree_lines * line_memory(&line), field, ..Configuration::default() };
+        let mut input_chunks = Chunks::new(Cursor::new(input.as_bytes().to_vec()), 16, config.clone());
+
+        replacement_selection(&mut input_chunks, &mut MemoryStorage::new(), &config)
+            .unwrap()
+            .into_iter()
+            .map(|file| {
+                let mut output = vec![];
+                Lines::new(file.reopen().unwrap(), 16, config.clone()).for_each(|line| line.unwrap().write(&mut output).unwrap());
+
+                String::from_utf8(output).unwrap().lines().map(str::to_string).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sorted_input() {
+        let runs = generate_runs("A\nB\nC\nD\nE\nF\nG\n", 2);
+
+        assert_eq!(runs, vec![vec!["A", "B", "C", "D", "E", "F", "G"]]);
+    }
+
+    #[test]
+    fn test_random_input() {
+        let runs = generate_runs("D\nB\nF\nA\nC\nG\nE\n\n", 2);
+
+        assert_eq!(runs, vec![vec!["B", "D", "F"], vec!["A", "C", "E", "G"], vec![""]]);
+    }
+
+    #[test]
+    fn test_reversed_input() {
+        let runs = generate_runs("D\nC\nB\nA\n", 2);
+
+        assert_eq!(runs, vec![vec!["C", "D"], vec!["A", "B"]]);
+    }
+
+    #[test]
+    fn test_equal_keys_keep_input_order() {
+        let runs = generate_runs_on_field("b\t1\na\t1\nb\t2\na\t2\nc\t0\n", 2, 2);
+
+        assert_eq!(runs, vec![vec!["b\t1", "a\t1", "b\t2", "a\t2"], vec!["c\t0"]]);
+    }
+
+    #[test]
+    fn test_long_line_waits() {
+        // The long line only fits once both short lines are written, so the tree holds
+        // a single line afterwards and A cannot join the run of the long line
+        let long_line = "B".repeat(64);
+        let runs = generate_runs(&format!("C\nE\n{}\nA\nD\n", long_line), 2);
+
+        assert_eq!(runs, vec![vec!["C".to_string(), "E".to_string()], vec![long_line], vec!["A".to_string(), "D".to_string()]]);
+    }
+}
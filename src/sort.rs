@@ -1,60 +1,461 @@
-use std::{io::{Read, Write}, sync::mpsc::channel};
+use std::{io::{self, Read, Write}, panic::{catch_unwind, AssertUnwindSafe}, sync::mpsc::{channel, Sender}};
 
 use threadpool::ThreadPool;
 
-use crate::{chunk::{Chunks, Chunk}, tempfile::{TmpDir, ClosedTmpFile, TmpFileOpened}, Configuration};
+use crate::{chunk::{Chunks, Chunk}, line::Line, tempfile::{TmpStorage, TmpFileOpened, TmpFileClosed, InputRange}, Configuration, StatsCollector};
 
-pub fn sort(
+/// A sorted run, either still in memory or written to a temporary file
+enum SortedRun<F> {
+    Memory(InputRange, Chunk),
+    File(InputRange, F),
+
+    /// A file with consecutive presorted chunks, which was written without using the threadpool
+    Coalesced(InputRange, F),
+
+    /// A chunk that could not be sorted or written
+    Failed(io::Error)
+}
+
+/// A run that is built from consecutive chunks of the input that are already sorted
+struct CoalescedRun<W> {
+    file: W,
+    range: InputRange,
+
+    /// A copy of the last line of the run, which is `None` as long as the run
+    /// only holds chunks whose lines were all left out
+    last_line: Option<Line>
+}
+
+impl<W: Write> CoalescedRun<W> {
+    fn new(file: W, chunk: Chunk, range: InputRange) -> io::Result<Self> {
+        let mut run = CoalescedRun { file, range, last_line: None };
+        run.append(chunk, range)?;
+
+        Ok(run)
+    }
+
+    /// Returns true if the chunk can be appended without breaking the order of the run
+    fn continues_with(&self, chunk: &Chunk) -> bool {
+        // Lines are ordered in reverse
+        match (chunk.first_line(), &self.last_line) {
+            (Some(line), Some(last_line)) => line <= last_line,
+            _ => true
+        }
+    }
+
+    fn append(&mut self, chunk: Chunk, range: InputRange) -> io::Result<()> {
+        chunk.write(&mut self.file)?;
+
+        if let Some(line) = chunk.last_line() {
+            self.last_line = Some(line.to_owned_line());
+        }
+        self.range.end = range.end;
+        self.range.checksum = range.checksum;
+
+        Ok(())
+    }
+}
+
+/// Sorts all chunks of the input. Runs are written to temporary files, except
+/// for the runs that are still being sorted when the end of the input is reached.
+/// Those are kept in memory, so small inputs never touch the temporary directory.
+/// Consecutive chunks that are already sorted are written to a single run.
+///
+/// # Arguments
+///
+/// * `input_chunks` - The chunks of the input
+/// * `sorter_pool` - The threadpool to sort on
+/// * `tmp_dir` - The storage to write the runs to
+/// * `config` - Some additional configuration options
+///
+/// # Returns
+///
+/// The sorted runs that were written to temporary files and the sorted runs that
+/// were kept in memory, in the order of the input with the files first, or the
+/// error of the input or the storage
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(chunk_size = input_chunks.buffer_size())))]
+pub fn sort<S: TmpStorage>(
     input_chunks: &mut Chunks<impl Read>,
     sorter_pool: &ThreadPool,
-    tmp_dir: &mut TmpDir,
+    tmp_dir: &mut S,
     config: &Configuration
-) -> Vec<ClosedTmpFile> {
-    let (file_sender, file_receiver) = channel();
+) -> io::Result<(Vec<S::Closed>, Vec<Chunk>)> {
+    let mut tmp_files: Vec<(u64, S::Closed)> = vec![];
+    let mut sorted_chunks: Vec<(u64, Chunk)> = vec![];
+
+    let sorted = sort_runs(input_chunks, sorter_pool, tmp_dir, &mut tmp_files, &mut sorted_chunks, config);
 
-    let mut tmp_files: Vec<ClosedTmpFile> = vec![];
+    // The other chunks are finished first, so none of them writes to the storage while it is removed
+    if sorted.is_err() {
+        sorter_pool.join();
+    }
 
-    // Create new chunks while inside limits
-    for _ in 0..config.threads {
-        if let Some(unsorted_chunk) = input_chunks.next() {
-            let mut tmp_file = tmp_dir.create_new_file();
-            let sender = file_sender.clone();
+    sorted?;
 
-            sorter_pool.execute(move || {
-                sort_and_write(unsorted_chunk, &mut tmp_file);
-                let _ = sender.send(tmp_file.close());
-            });
+    order_runs(tmp_files, sorted_chunks, tmp_dir, config)
+}
+
+/// Puts the runs in the order of the input, by the offset at which their input
+/// starts. Runs are finished out of order, so a chunk that is kept in memory can
+/// come before a file, which it cannot be merged after. Such chunks are written
+/// to files as well.
+fn order_runs<S: TmpStorage>(
+    mut tmp_files: Vec<(u64, S::Closed)>,
+    mut sorted_chunks: Vec<(u64, Chunk)>,
+    tmp_dir: &mut S,
+    config: &Configuration
+) -> io::Result<(Vec<S::Closed>, Vec<Chunk>)> {
+    sorted_chunks.sort_by_key(|(start, _)| *start);
+
+    let last_file = tmp_files.iter().map(|(start, _)| *start).max();
+    let kept = sorted_chunks.partition_point(|(start, _)| last_file.is_some_and(|last_file| *start < last_file));
+    let kept_chunks = sorted_chunks.split_off(kept);
+
+    for (start, chunk) in sorted_chunks {
+        let mut tmp_file = tmp_dir.create_new_file()?;
+        chunk.write(&mut tmp_file)?;
+
+        let file = tmp_file.close()?;
+        config.stats.add_tmp_bytes(file.size()?);
+        tmp_files.push((start, file));
+    }
+
+    tmp_files.sort_by_key(|(start, _)| *start);
+
+    Ok((
+        tmp_files.into_iter().map(|(_, file)| file).collect(),
+        kept_chunks.into_iter().map(|(_, chunk)| chunk).collect()
+    ))
+}
+
+/// Sorts all chunks of the input into the given runs, see `sort`. Returns at
+/// the first error, while other chunks can still be sorted on the threadpool.
+fn sort_runs<S: TmpStorage>(
+    input_chunks: &mut Chunks<impl Read>,
+    sorter_pool: &ThreadPool,
+    tmp_dir: &mut S,
+    tmp_files: &mut Vec<(u64, S::Closed)>,
+    sorted_chunks: &mut Vec<(u64, Chunk)>,
+    config: &Configuration
+) -> io::Result<()> {
+    let (run_sender, run_receiver) = channel();
+
+    // The runs of a persistent work directory have to be written to disk,
+    // because the recorded progress would be lost otherwise
+    let spill_all = tmp_dir.is_persistent();
+
+    let mut open_run = None;
+
+    // Use an option in order to drop the sender once the input is exhausted
+    let mut option_sender = Some(run_sender);
+
+    // The number of chunks that are read, but not written or kept yet
+    let mut pending = 0;
+
+    loop {
+        // Create new chunks while inside limits. Reading a chunk waits for its memory,
+        // which the runs that this loop still has to handle can hold, so a chunk is only
+        // read if its memory is available or no other chunk is pending.
+        while let Some(sender) = &option_sender {
+            if pending > 0 && (pending >= config.threads || config.memory.available() < input_chunks.buffer_size()) {
+                break;
+            }
+
+            if sort_next_chunk(input_chunks, sorter_pool, tmp_dir, spill_all, &mut open_run, sender, &config.stats)? {
+                pending += 1;
+            }
+
+            if input_chunks.is_exhausted() {
+                option_sender = None;
+            }
+        }
+
+        // While there is at least a single sender connected to this receiver
+        let Ok(run) = run_receiver.recv() else {
+            break;
+        };
+
+        match (run, &option_sender) {
+            // More input follows, so this run has to make room for it
+            (SortedRun::Memory(range, chunk), Some(sender)) => {
+                let mut tmp_file = tmp_dir.create_new_file()?;
+
+                execute(sorter_pool, sender.clone(), move || {
+                    chunk.write(&mut tmp_file)?;
+
+                    // Release the memory of the chunk before the next one is read
+                    drop(chunk);
+
+                    Ok(SortedRun::File(range, tmp_file.close()?))
+                });
+            },
+
+            // The input is exhausted, so this run can stay in memory
+            (SortedRun::Memory(range, chunk), None) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(run = tmp_files.len() + sorted_chunks.len(), bytes = chunk.bytes(), "Kept a sorted run in memory");
+
+                config.progress.add_run();
+                config.stats.add_run(chunk.bytes() as u64);
+                sorted_chunks.push((range.start, chunk));
+                pending -= 1;
+            },
+
+            (SortedRun::Coalesced(range, file), _) => {
+                let size = file.size()?;
+
+                #[cfg(feature = "tracing")]
+                tracing::debug!(run = tmp_files.len() + sorted_chunks.len(), bytes = size, "Wrote a run of presorted chunks");
+
+                config.progress.add_run();
+                config.stats.add_run(size);
+                config.stats.add_tmp_bytes(size);
+                tmp_dir.record_run(range, &file)?;
+                tmp_files.push((range.start, file));
+            },
+
+            (SortedRun::File(range, file), _) => {
+                let size = file.size()?;
+
+                #[cfg(feature = "tracing")]
+                tracing::debug!(run = tmp_files.len() + sorted_chunks.len(), bytes = size, "Wrote a sorted run");
+
+                config.progress.add_run();
+                config.stats.add_run(size);
+                config.stats.add_tmp_bytes(size);
+                tmp_dir.record_run(range, &file)?;
+                tmp_files.push((range.start, file));
+                pending -= 1;
+            },
+
+            (SortedRun::Failed(err), _) => return Err(err)
         }
     }
 
-    // Use an option in order to drop the sender inside the loop
-    let mut option_sender = Some(file_sender);
+    Ok(())
+}
+
+/// Reads the next chunk and sorts it on the threadpool. Chunks that are
+/// already sorted are written to the open coalesced run instead, after which
+/// the next chunk is read. Returns false if no chunk was sent to the threadpool,
+/// or the error of the input or the storage.
+fn sort_next_chunk<S: TmpStorage>(
+    input_chunks: &mut Chunks<impl Read>,
+    sorter_pool: &ThreadPool,
+    tmp_dir: &mut S,
+    spill: bool,
+    open_run: &mut Option<CoalescedRun<S::Writer>>,
+    sender: &Sender<SortedRun<S::Closed>>,
+    stats: &StatsCollector
+) -> io::Result<bool> {
+    let dispatched = loop {
+        let start = input_chunks.offset();
 
-    // While there is at least a single sender connected to this receiver
-    while let Ok(file) = file_receiver.recv() {
-        tmp_files.push(file);
+        let Some(mut unsorted_chunk) = input_chunks.next().transpose()? else {
+            break false;
+        };
 
-        if let Some(sender) = &option_sender {
-            match input_chunks.next() {
-                Some(unsorted_chunk) => {
-                    let mut tmp_file = tmp_dir.create_new_file();
-                    let sender = sender.clone();
-                    
-                    sorter_pool.execute(move || {
-                        sort_and_write(unsorted_chunk, &mut tmp_file);
-                        let _ = sender.send(tmp_file.close());
-                    });
-                },
-                None => option_sender = None
+        let range = InputRange { start, end: input_chunks.offset(), checksum: input_chunks.checksum() };
+
+        // The last chunk can stay in memory, unless it continues the open run
+        let coalesce = unsorted_chunk.is_presorted()
+            && (open_run.is_some() || spill || !input_chunks.is_exhausted());
+
+        if coalesce {
+            match open_run {
+                Some(run) if run.continues_with(&unsorted_chunk) => run.append(unsorted_chunk, range)?,
+                _ => {
+                    close_open_run(open_run, sender)?;
+                    *open_run = Some(CoalescedRun::new(tmp_dir.create_new_file()?, unsorted_chunk, range)?);
+                }
             }
+
+            continue;
+        }
+
+        // A run can only cover consecutive chunks of the input
+        close_open_run(open_run, sender)?;
+
+        let stats = stats.clone();
+
+        if spill {
+            let mut tmp_file = tmp_dir.create_new_file()?;
+
+            execute(sorter_pool, sender.clone(), move || {
+                stats.add_comparisons(sort_and_write(unsorted_chunk, &mut tmp_file)?);
+                Ok(SortedRun::File(range, tmp_file.close()?))
+            });
+        } else {
+            execute(sorter_pool, sender.clone(), move || {
+                stats.add_comparisons(unsorted_chunk.sort());
+                Ok(SortedRun::Memory(range, unsorted_chunk))
+            });
         }
+
+        break true;
+    };
+
+    if input_chunks.is_exhausted() {
+        close_open_run(open_run, sender)?;
     }
 
-    return tmp_files;
+    Ok(dispatched)
 }
 
-/// Sorts a chunk and writes it to a file
-pub fn sort_and_write(mut chunk: Chunk, file: &mut impl Write) {
-    chunk.sort_unstable();
-    chunk.write(file);
+/// Runs a job on the threadpool and sends its run. A job that fails or panics sends
+/// `SortedRun::Failed` instead, otherwise the sort would wait for its run forever.
+fn execute<F: Send + 'static>(
+    sorter_pool: &ThreadPool,
+    sender: Sender<SortedRun<F>>,
+    job: impl FnOnce() -> io::Result<SortedRun<F>> + Send + 'static
+) {
+    sorter_pool.execute(move || {
+        let run = catch_unwind(AssertUnwindSafe(job))
+            .unwrap_or_else(|_| Err(io::Error::other("Failed to sort a chunk")))
+            .unwrap_or_else(SortedRun::Failed);
+
+        let _ = sender.send(run);
+    });
+}
+
+/// Closes the open coalesced run, if any
+fn close_open_run<W: TmpFileOpened>(open_run: &mut Option<CoalescedRun<W>>, sender: &Sender<SortedRun<W::Closed>>) -> io::Result<()> {
+    if let Some(run) = open_run.take() {
+        let _ = sender.send(SortedRun::Coalesced(run.range, run.file.close()?));
+    }
+
+    Ok(())
+}
+
+/// Writes sorted chunks to temporary files, one file per chunk
+///
+/// # Arguments
+///
+/// * `chunks` - The sorted chunks
+/// * `tmp_dir` - The storage to write the chunks to
+///
+/// # Returns
+///
+/// The files, in the order of the chunks, or the error of the storage
+pub fn write_chunks<S: TmpStorage>(chunks: Vec<Chunk>, tmp_dir: &mut S) -> io::Result<Vec<S::Closed>> {
+    chunks
+        .into_iter()
+        .map(|chunk| {
+            let mut tmp_file = tmp_dir.create_new_file()?;
+            chunk.write(&mut tmp_file)?;
+            tmp_file.close()
+        })
+        .collect()
+}
+
+/// Sorts a chunk and writes it to a file, returns the number of comparisons or
+/// the error of the file
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(lines = chunk.len(), bytes = chunk.bytes())))]
+pub fn sort_and_write(mut chunk: Chunk, file: &mut impl Write) -> io::Result<u64> {
+    let comparisons = chunk.sort();
+    chunk.write(file)?;
+
+    Ok(comparisons)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{fault::{assert_fails_cleanly, Fault, Faults, Faulty, FaultyStorage}, tempfile::MemoryStorage, MemoryBudget};
+
+    use super::*;
+
+    fn sort_runs(input: &str, buffer_size: usize) -> (usize, usize) {
+        let config = Configuration { threads: 2, ..Configuration::default() };
+        let mut input_chunks = Chunks::new(Cursor::new(input.as_bytes().to_vec()), buffer_size, config.clone());
+
+        let (files, chunks) = sort(&mut input_chunks, &ThreadPool::new(2), &mut MemoryStorage::new(), &config).unwrap();
+
+        (files.len(), chunks.len())
+    }
+
+    #[test]
+    fn test_sort_in_memory() {
+        assert_eq!(sort_runs("D\nB\nC\nA\n", 16), (0, 1));
+        assert_eq!(sort_runs("D\nB\nC\nA\n", 5), (0, 2));
+    }
+
+    #[test]
+    fn test_sort_coalesces_presorted_chunks() {
+        assert_eq!(sort_runs("A\nB\nC\nD\nE\nF\nG\nH\nI\nJ\n", 5), (1, 0));
+        assert_eq!(sort_runs("A\nC\nE\nG\nB\nD\nF\nH\n", 5), (2, 0));
+    }
+
+    #[test]
+    fn test_sort_spills_unsorted_chunks() {
+        let (files, chunks) = sort_runs("J\nI\nH\nG\nF\nE\nD\nC\nB\nA\n", 5);
+
+        assert_eq!(files + chunks, 5);
+        assert!(chunks <= 2);
+    }
+
+    #[test]
+    fn test_sort_within_budget() {
+        // Every chunk takes more memory than the budget, because of its lines
+        let memory = MemoryBudget::new(16);
+        let config = Configuration { threads: 2, memory: memory.clone(), ..Configuration::default() };
+        let mut input_chunks = Chunks::new(Cursor::new(b"J\nI\nH\nG\nF\nE\nD\nC\nB\nA\n".to_vec()), 5, config.clone());
+
+        let (files, chunks) = sort(&mut input_chunks, &ThreadPool::new(2), &mut MemoryStorage::new(), &config).unwrap();
+        assert_eq!(files.len() + chunks.len(), 5);
+
+        drop(chunks);
+        assert_eq!(memory.reserved(), 0);
+    }
+
+    #[test]
+    fn test_sort_retries_interrupted_io() {
+        let input = b"J\nI\nH\nG\nF\nE\nD\nC\nB\nA\n";
+        let input_faults = Faults::new().at(0, Fault::Interrupted).at(3, Fault::Short).at(7, Fault::Interrupted);
+        let write_faults = Faults::new().at(0, Fault::Short).at(1, Fault::Interrupted);
+
+        let mut storage = FaultyStorage::new(MemoryStorage::new())
+            .with_write_faults(0, write_faults.clone())
+            .with_write_faults(1, write_faults);
+
+        let config = Configuration { threads: 2, ..Configuration::default() };
+        let mut input_chunks = Chunks::new(Faulty::new(Cursor::new(input.to_vec()), input_faults), 5, config.clone());
+
+        let (files, chunks) = sort(&mut input_chunks, &ThreadPool::new(2), &mut storage, &config).unwrap();
+
+        let mut lines = vec![];
+        for file in files {
+            file.reopen().unwrap().read_to_end(&mut lines).unwrap();
+        }
+        for chunk in chunks {
+            chunk.write(&mut lines).unwrap();
+        }
+
+        assert!(storage.files() >= 2);
+        assert_eq!(lines.len(), input.len());
+    }
+
+    #[test]
+    fn test_sort_fails_cleanly() {
+        let input = b"J\nI\nH\nG\nF\nE\nD\nC\nB\nA\n";
+        let config = Configuration { threads: 2, ..Configuration::default() };
+
+        // The disk fills up while a sorted chunk is written
+        assert_fails_cleanly(Fault::NoSpace, |tmp_dir| {
+            let mut storage = FaultyStorage::new(tmp_dir).with_write_faults(1, Faults::new().at(1, Fault::NoSpace));
+            let mut input_chunks = Chunks::new(Cursor::new(input.to_vec()), 5, config.clone());
+
+            sort(&mut input_chunks, &ThreadPool::new(2), &mut storage, &config).map(drop)
+        });
+
+        // The input fails after some chunks are written
+        assert_fails_cleanly(Fault::Io, |tmp_dir| {
+            let mut storage = FaultyStorage::new(tmp_dir);
+            let input = Faulty::new(Cursor::new(input.to_vec()), Faults::new().at(14, Fault::Io));
+            let mut input_chunks = Chunks::new(input, 5, config.clone());
+
+            sort(&mut input_chunks, &ThreadPool::new(2), &mut storage, &config).map(drop)
+        });
+    }
 }
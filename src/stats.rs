@@ -0,0 +1,245 @@
+use std::{fmt::Write, sync::{atomic::{AtomicU32, AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::system;
+
+/// The statistics of a sort, to tune the buffer size and the number of threads
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortStats {
+    /// The number of input bytes that were read
+    pub input_bytes: u64,
+
+    /// The number of input lines that were read
+    pub input_lines: u64,
+
+    /// The number of input lines that were left out of the output, because they miss the sort field
+    pub skipped_lines: u64,
+
+    /// The sizes of the sorted runs that were created from the input, in the
+    /// order in which they were finished
+    pub run_sizes: Vec<u64>,
+
+    /// The number of passes over the data, including the final merge
+    pub merge_passes: u32,
+
+    /// The number of bytes that were written to temporary files
+    pub tmp_bytes_written: u64,
+
+    /// The time that every phase of the sort took
+    pub phases: Vec<PhaseStats>,
+
+    /// The largest amount of memory that the buffers of the sort held at once
+    pub peak_memory: u64,
+
+    /// The peak resident memory of the process, if it is known
+    pub peak_rss: Option<u64>,
+
+    /// The number of comparisons between lines while sorting and merging
+    pub comparisons: u64
+}
+
+/// The time that a phase of the sort took
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhaseStats {
+    pub name: &'static str,
+
+    /// The wall clock time of the phase
+    pub wall_time: Duration,
+
+    /// The CPU time that all threads of the process spent in the phase, if it is known
+    pub cpu_time: Option<Duration>
+}
+
+impl SortStats {
+    /// Returns the number of sorted runs that were created from the input
+    pub fn initial_runs(&self) -> usize {
+        self.run_sizes.len()
+    }
+
+    /// Formats the statistics as a JSON object
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n");
+
+        let _ = writeln!(json, "  \"input_bytes\": {},", self.input_bytes);
+        let _ = writeln!(json, "  \"input_lines\": {},", self.input_lines);
+        let _ = writeln!(json, "  \"skipped_lines\": {},", self.skipped_lines);
+        let _ = writeln!(json, "  \"initial_runs\": {},", self.initial_runs());
+        let _ = writeln!(json, "  \"run_sizes\": [{}],", join(self.run_sizes.iter().map(u64::to_string)));
+        let _ = writeln!(json, "  \"merge_passes\": {},", self.merge_passes);
+        let _ = writeln!(json, "  \"tmp_bytes_written\": {},", self.tmp_bytes_written);
+
+        let phases = self.phases.iter().map(|phase| format!(
+            "\n    {{ \"name\": \"{}\", \"wall_seconds\": {:.6}, \"cpu_seconds\": {} }}",
+            phase.name,
+            phase.wall_time.as_secs_f64(),
+            phase.cpu_time.map_or("null".to_string(), |cpu_time| format!("{:.6}", cpu_time.as_secs_f64()))
+        ));
+        let _ = writeln!(json, "  \"phases\": [{}{}],", join(phases), if self.phases.is_empty() { "" } else { "\n  " });
+
+        let _ = writeln!(json, "  \"peak_memory\": {},", self.peak_memory);
+        let _ = writeln!(json, "  \"peak_rss\": {},", self.peak_rss.map_or("null".to_string(), |rss| rss.to_string()));
+        let _ = writeln!(json, "  \"comparisons\": {}", self.comparisons);
+
+        json.push_str("}\n");
+        json
+    }
+}
+
+fn join(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<String>>().join(", ")
+}
+
+/// Collects the statistics of a sort while it runs. Clones share the same counters.
+#[derive(Clone, Default)]
+pub struct StatsCollector {
+    counters: Arc<Counters>
+}
+
+#[derive(Default)]
+struct Counters {
+    input_bytes: AtomicU64,
+    input_lines: AtomicU64,
+    skipped_lines: AtomicU64,
+    run_sizes: Mutex<Vec<u64>>,
+    merge_passes: AtomicU32,
+    tmp_bytes_written: AtomicU64,
+    phases: Mutex<Vec<PhaseStats>>,
+    comparisons: AtomicU64
+}
+
+impl StatsCollector {
+    /// Counts a chunk of the input that was read
+    pub fn add_input(&self, bytes: u64, lines: u64) {
+        self.counters.input_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.counters.input_lines.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    /// Counts input lines that were left out of the output
+    pub fn add_skipped(&self, lines: u64) {
+        self.counters.skipped_lines.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    /// Counts a sorted run that was created from the input
+    pub fn add_run(&self, size: u64) {
+        self.counters.run_sizes.lock().unwrap().push(size);
+    }
+
+    /// Counts a pass over the data
+    pub fn add_pass(&self) {
+        self.counters.merge_passes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts bytes that were written to a temporary file
+    pub fn add_tmp_bytes(&self, bytes: u64) {
+        self.counters.tmp_bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts comparisons between lines
+    pub fn add_comparisons(&self, comparisons: u64) {
+        self.counters.comparisons.fetch_add(comparisons, Ordering::Relaxed);
+    }
+
+    /// Starts timing a phase of the sort, which is recorded once the timer is dropped
+    pub fn start_phase(&self, name: &'static str) -> PhaseTimer {
+        PhaseTimer { stats: self.clone(), name, started: Instant::now(), cpu_started: system::process_cpu_time() }
+    }
+
+    /// Returns the statistics that were collected so far
+    ///
+    /// # Arguments
+    ///
+    /// * `peak_memory` - The largest amount of memory that the buffers of the sort held at once
+    pub fn snapshot(&self, peak_memory: u64) -> SortStats {
+        SortStats {
+            input_bytes: self.counters.input_bytes.load(Ordering::Relaxed),
+            input_lines: self.counters.input_lines.load(Ordering::Relaxed),
+            skipped_lines: self.counters.skipped_lines.load(Ordering::Relaxed),
+            run_sizes: self.counters.run_sizes.lock().unwrap().clone(),
+            merge_passes: self.counters.merge_passes.load(Ordering::Relaxed),
+            tmp_bytes_written: self.counters.tmp_bytes_written.load(Ordering::Relaxed),
+            phases: self.counters.phases.lock().unwrap().clone(),
+            peak_memory,
+            peak_rss: system::peak_rss(),
+            comparisons: self.counters.comparisons.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Times a phase of the sort until it is dropped
+pub struct PhaseTimer {
+    stats: StatsCollector,
+    name: &'static str,
+    started: Instant,
+    cpu_started: Option<Duration>
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        let cpu_time = system::process_cpu_time()
+            .zip(self.cpu_started)
+            .map(|(cpu_time, cpu_started)| cpu_time.saturating_sub(cpu_started));
+
+        self.stats.counters.phases.lock().unwrap().push(PhaseStats { name: self.name, wall_time: self.started.elapsed(), cpu_time });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect() {
+        let stats = StatsCollector::default();
+
+        stats.clone().add_input(10, 3);
+        stats.add_skipped(1);
+        stats.add_run(6);
+        stats.add_run(4);
+        stats.add_tmp_bytes(6);
+        stats.add_comparisons(5);
+        drop(stats.start_phase("sort"));
+
+        let snapshot = stats.snapshot(100);
+
+        assert_eq!((snapshot.input_bytes, snapshot.input_lines, snapshot.skipped_lines), (10, 3, 1));
+        assert_eq!(snapshot.initial_runs(), 2);
+        assert_eq!(snapshot.tmp_bytes_written, 6);
+        assert_eq!(snapshot.comparisons, 5);
+        assert_eq!(snapshot.phases[0].name, "sort");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let stats = SortStats {
+            input_bytes: 10,
+            input_lines: 3,
+            skipped_lines: 1,
+            run_sizes: vec![6, 4],
+            merge_passes: 1,
+            tmp_bytes_written: 6,
+            phases: vec![PhaseStats { name: "sort", wall_time: Duration::from_millis(1500), cpu_time: None }],
+            peak_memory: 100,
+            peak_rss: Some(4096),
+            comparisons: 5
+        };
+
+        assert_eq!(stats.to_json(), concat!(
+            "{\n",
+            "  \"input_bytes\": 10,\n",
+            "  \"input_lines\": 3,\n",
+            "  \"skipped_lines\": 1,\n",
+            "  \"initial_runs\": 2,\n",
+            "  \"run_sizes\": [6, 4],\n",
+            "  \"merge_passes\": 1,\n",
+            "  \"tmp_bytes_written\": 6,\n",
+            "  \"phases\": [\n",
+            "    { \"name\": \"sort\", \"wall_seconds\": 1.500000, \"cpu_seconds\": null }\n",
+            "  ],\n",
+            "  \"peak_memory\": 100,\n",
+            "  \"peak_rss\": 4096,\n",
+            "  \"comparisons\": 5\n",
+            "}\n"
+        ));
+
+        assert!(SortStats::default().to_json().contains("\"phases\": [],"));
+    }
+}
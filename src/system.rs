@@ -0,0 +1,222 @@
+use std::{fs::read_to_string, path::Path, thread::available_parallelism, time::Duration};
+
+use bytesize::ByteSize;
+
+/// The buffer size when the available memory is unknown
+pub const DEFAULT_BUFFER_SIZE: usize = 400_000_000;
+
+/// The part of the available memory that the buffer uses by default
+const DEFAULT_MEMORY_PERCENTAGE: f64 = 50.0;
+
+/// Returns the number of threads that can run at the same time. This respects
+/// the CPU affinity of the process and the CPU quota of its cgroup.
+pub fn available_threads() -> usize {
+    available_parallelism().map(|threads| threads.get()).unwrap_or(1)
+}
+
+/// Returns the physical memory of the machine, or the memory limit of the
+/// cgroup of the process if that is lower
+pub fn total_memory() -> Option<u64> {
+    let total = read_to_string("/proc/meminfo").ok().and_then(|meminfo| parse_meminfo(&meminfo, "MemTotal"));
+
+    lowest(total, cgroup_memory().map(|(limit, _)| limit))
+}
+
+/// Returns the memory that can be used without swapping, which is limited by
+/// the memory that the cgroup of the process has left
+pub fn available_memory() -> Option<u64> {
+    let available = read_to_string("/proc/meminfo").ok().and_then(|meminfo| parse_meminfo(&meminfo, "MemAvailable"));
+
+    lowest(available, cgroup_memory().map(|(limit, usage)| limit.saturating_sub(usage)))
+}
+
+/// Returns the buffer size that is used by default, half of the available memory
+pub fn default_buffer_size() -> usize {
+    available_memory()
+        .map(|memory| percentage_of(memory, DEFAULT_MEMORY_PERCENTAGE))
+        .unwrap_or(DEFAULT_BUFFER_SIZE)
+}
+
+/// Parses a buffer size in bytes, with an optional suffix like `500M`, `2G` or
+/// `512MiB`, or as a percentage of the total memory like `50%`
+///
+/// # Arguments
+///
+/// * `value` - The buffer size
+///
+/// # Returns
+///
+/// The buffer size in bytes
+pub fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+
+    if let Some(percentage) = value.strip_suffix('%') {
+        let percentage: f64 = percentage.trim().parse().map_err(|_| format!("Invalid percentage: {}", value))?;
+
+        if !(0.0..=100.0).contains(&percentage) {
+            return Err(format!("Percentage out of range: {}", value));
+        }
+
+        let total = total_memory().ok_or_else(|| "The total memory is unknown, give the size in bytes".to_string())?;
+
+        return Ok(percentage_of(total, percentage));
+    }
+
+    // Suffixes like K and KB are powers of 1000, suffixes like Ki and KiB are powers of 1024
+    value
+        .parse::<ByteSize>()
+        .map(|size| size.as_u64() as usize)
+        .map_err(|_| format!("Invalid buffer size: {}", value))
+}
+
+/// Returns the CPU time that all threads of the process used so far
+pub fn process_cpu_time() -> Option<Duration> {
+    resource_usage().map(|(cpu_time, _)| cpu_time)
+}
+
+/// Returns the peak resident memory of the process
+pub fn peak_rss() -> Option<u64> {
+    resource_usage().map(|(_, peak_rss)| peak_rss)
+}
+
+/// Returns the CPU time and the peak resident memory of the process
+#[cfg(target_os = "linux")]
+fn resource_usage() -> Option<(Duration, u64)> {
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+
+    let time = |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+
+    // The peak is given in kB
+    Some((time(usage.ru_utime) + time(usage.ru_stime), usage.ru_maxrss as u64 * 1024))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resource_usage() -> Option<(Duration, u64)> {
+    None
+}
+
+fn percentage_of(bytes: u64, percentage: f64) -> usize {
+    (bytes as f64 * percentage / 100.0) as usize
+}
+
+fn lowest(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b)             => a.or(b)
+    }
+}
+
+/// Returns the memory limit and usage of the cgroup of the process, if it has a limit
+fn cgroup_memory() -> Option<(u64, u64)> {
+    let cgroups = read_to_string("/proc/self/cgroup").ok()?;
+
+    for (version, path) in parse_cgroups(&cgroups) {
+        let (directory, limit_file, usage_file) = match version {
+            CgroupVersion::V2 => ("/sys/fs/cgroup", "memory.max", "memory.current"),
+            CgroupVersion::V1 => ("/sys/fs/cgroup/memory", "memory.limit_in_bytes", "memory.usage_in_bytes")
+        };
+
+        // Inside a container, the cgroup of the process is often mounted as the root
+        for directory in [Path::new(directory).join(path.trim_start_matches('/')), Path::new(directory).to_path_buf()] {
+            let Ok(limit) = read_to_string(directory.join(limit_file)) else {
+                continue;
+            };
+
+            let Some(limit) = parse_limit(&limit) else {
+                break;
+            };
+
+            let usage = read_to_string(directory.join(usage_file)).ok().and_then(|usage| parse_limit(&usage)).unwrap_or(0);
+
+            return Some((limit, usage));
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum CgroupVersion {
+    V1,
+    V2
+}
+
+/// Returns the cgroups of `/proc/self/cgroup` that control the memory
+fn parse_cgroups(content: &str) -> Vec<(CgroupVersion, &str)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+
+            if controllers.is_empty() {
+                Some((CgroupVersion::V2, path))
+            } else if controllers.split(',').any(|controller| controller == "memory") {
+                Some((CgroupVersion::V1, path))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Parses the content of a cgroup memory file. No limit is written as `max`
+/// in cgroup v2 and as a value close to `i64::MAX` in cgroup v1.
+fn parse_limit(content: &str) -> Option<u64> {
+    content.trim().parse().ok().filter(|limit| *limit < (1 << 62))
+}
+
+/// Parses a value of `/proc/meminfo`, which is given in kB
+fn parse_meminfo(content: &str, key: &str) -> Option<u64> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kilobytes| kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16303884 kB\nMemFree:         1101428 kB\nMemAvailable:    9567172 kB\n";
+
+        assert_eq!(parse_meminfo(meminfo, "MemTotal"), Some(16303884 * 1024));
+        assert_eq!(parse_meminfo(meminfo, "MemAvailable"), Some(9567172 * 1024));
+        assert_eq!(parse_meminfo(meminfo, "SwapTotal"), None);
+    }
+
+    #[test]
+    fn test_parse_cgroups() {
+        let cgroups = "12:cpu,cpuacct:/user.slice\n11:memory:/user.slice/session-1.scope\n0::/user.slice/session-1.scope\n";
+
+        assert_eq!(parse_cgroups(cgroups), vec![
+            (CgroupVersion::V1, "/user.slice/session-1.scope"),
+            (CgroupVersion::V2, "/user.slice/session-1.scope")
+        ]);
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit("536870912\n"), Some(536870912));
+        assert_eq!(parse_limit("max\n"), None);
+        assert_eq!(parse_limit("9223372036854771712\n"), None);
+    }
+
+    #[test]
+    fn test_parse_buffer_size() {
+        assert_eq!(parse_buffer_size("400000000"), Ok(400000000));
+        assert_eq!(parse_buffer_size("400M"), Ok(400000000));
+        assert_eq!(parse_buffer_size("1.5 GB"), Ok(1500000000));
+        assert_eq!(parse_buffer_size("512MiB"), Ok(512 << 20));
+        assert_eq!(parse_buffer_size("2ki"), Ok(2048));
+        assert!(parse_buffer_size("150%").is_err());
+        assert!(parse_buffer_size("lots").is_err());
+    }
+}
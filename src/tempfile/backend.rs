@@ -0,0 +1,210 @@
+use std::{fs::File, io::{self, BufReader, Read, Seek, SeekFrom, Write}, path::Path};
+
+use bytesize::KIB;
+
+use super::block::BLOCK_BUFFER_SIZE;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use super::uring::{UringReader, UringWriter};
+#[cfg(target_os = "linux")]
+use super::direct::{open_direct, DirectReader, DirectWriter, UncachedReader, UncachedWriter};
+
+/// The way run files are read and written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoBackend {
+    /// Blocking reads and writes
+    #[default]
+    Standard,
+
+    /// Reads and writes are queued with io_uring, with up to `queue_depth` of them
+    /// in flight per file. This needs Linux and the `io-uring` feature, the standard
+    /// backend is used when io_uring is not available.
+    IoUring { queue_depth: u32 }
+}
+
+impl IoBackend {
+    /// Returns the backend that will actually be used on this system
+    pub fn resolve(self) -> Self {
+        match self {
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::IoUring { queue_depth } if queue_depth > 0 && super::uring::is_supported() => self,
+            _ => IoBackend::Standard
+        }
+    }
+}
+
+/// How run files are opened
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoOptions {
+    pub backend: IoBackend,
+
+    /// Keep run files out of the page cache. Files are opened with `O_DIRECT`,
+    /// or read and written with `posix_fadvise` hints on filesystems that do not
+    /// support it. Direct I/O replaces the io_uring backend. Only used on Linux.
+    pub direct: bool
+}
+
+impl IoOptions {
+    /// Returns the memory of the buffers of a run file that is open for reading or
+    /// writing: its checksummed block and the buffers of the backend
+    pub fn buffer_size(&self) -> usize {
+        #[cfg(target_os = "linux")]
+        if self.direct {
+            return BLOCK_BUFFER_SIZE + super::direct::BUFFER_SIZE;
+        }
+
+        match self.backend {
+            // The queued buffers and the one that is being filled
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::IoUring { queue_depth } => BLOCK_BUFFER_SIZE + (queue_depth as usize + 1) * super::uring::BUFFER_SIZE,
+
+            // The buffer of a `BufReader`
+            _ => BLOCK_BUFFER_SIZE + 8 * KIB as usize
+        }
+    }
+}
+
+/// The file a run is written to
+pub enum RunWriter {
+    Standard(File),
+
+    #[cfg(target_os = "linux")]
+    Direct(Box<DirectWriter>),
+
+    #[cfg(target_os = "linux")]
+    Uncached(UncachedWriter),
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    IoUring(Box<UringWriter>)
+}
+
+impl RunWriter {
+    /// Creates a new file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file
+    /// * `options` - The way to write the file
+    ///
+    /// # Returns
+    ///
+    /// A writer for the file
+    pub fn create(path: &Path, options: IoOptions) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        if options.direct {
+            return match open_direct(path, true) {
+                Ok(file) => Ok(RunWriter::Direct(Box::new(DirectWriter::new(file)))),
+                Err(err) if err.kind() == io::ErrorKind::InvalidInput => Ok(RunWriter::Uncached(UncachedWriter::new(File::create(path)?))),
+                Err(err) => Err(err)
+            };
+        }
+
+        let file = File::create(path)?;
+
+        match options.backend {
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::IoUring { queue_depth } => match UringWriter::new(file.try_clone()?, queue_depth) {
+                Ok(writer) => Ok(RunWriter::IoUring(Box::new(writer))),
+                // A ring can fail to be created when the locked memory limit is reached
+                Err(_) => Ok(RunWriter::Standard(file))
+            },
+            _ => Ok(RunWriter::Standard(file))
+        }
+    }
+}
+
+impl Write for RunWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RunWriter::Standard(file) => file.write(buf),
+            #[cfg(target_os = "linux")]
+            RunWriter::Direct(writer) => writer.write(buf),
+            #[cfg(target_os = "linux")]
+            RunWriter::Uncached(writer) => writer.write(buf),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RunWriter::IoUring(writer) => writer.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RunWriter::Standard(file) => file.flush(),
+            #[cfg(target_os = "linux")]
+            RunWriter::Direct(writer) => writer.flush(),
+            #[cfg(target_os = "linux")]
+            RunWriter::Uncached(writer) => writer.flush(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RunWriter::IoUring(writer) => writer.flush()
+        }
+    }
+}
+
+/// The file a run is read from
+pub enum RunReader {
+    Standard(BufReader<File>),
+
+    #[cfg(target_os = "linux")]
+    Direct(Box<DirectReader>),
+
+    #[cfg(target_os = "linux")]
+    Uncached(UncachedReader),
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    IoUring(Box<UringReader>)
+}
+
+impl RunReader {
+    /// Opens a file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file
+    /// * `position` - The position in the file to start reading at
+    /// * `options` - The way to read the file
+    ///
+    /// # Returns
+    ///
+    /// A reader for the file
+    pub fn open(path: &Path, position: u64, options: IoOptions) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        if options.direct {
+            return match open_direct(path, false) {
+                Ok(file) => Ok(RunReader::Direct(Box::new(DirectReader::new(file, position)?))),
+                Err(err) if err.kind() == io::ErrorKind::InvalidInput => Ok(RunReader::Uncached(UncachedReader::new(File::open(path)?, position)?)),
+                Err(err) => Err(err)
+            };
+        }
+
+        let file = File::open(path)?;
+
+        match options.backend {
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::IoUring { queue_depth } => match UringReader::new(file.try_clone()?, position, queue_depth) {
+                Ok(reader) => Ok(RunReader::IoUring(Box::new(reader))),
+                // A ring can fail to be created when the locked memory limit is reached
+                Err(_) => RunReader::standard(file, position)
+            },
+            _ => RunReader::standard(file, position)
+        }
+    }
+
+    fn standard(mut file: File, position: u64) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(position))?;
+
+        Ok(RunReader::Standard(BufReader::new(file)))
+    }
+}
+
+impl Read for RunReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            RunReader::Standard(file) => file.read(buf),
+            #[cfg(target_os = "linux")]
+            RunReader::Direct(reader) => reader.read(buf),
+            #[cfg(target_os = "linux")]
+            RunReader::Uncached(reader) => reader.read(buf),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RunReader::IoUring(reader) => reader.read(buf)
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::{io::{self, Read, Write, ErrorKind}, path::{Path, PathBuf}};
+
+use bytesize::KIB;
+use crc32fast::hash;
+
+/// The maximum number of data bytes in a single block
+pub const BLOCK_SIZE: usize = 64 * KIB as usize;
+
+/// Every block starts with the length of its data and the CRC32 of its data
+const HEADER_SIZE: usize = 8;
+
+/// The memory of the block that a reader or writer holds
+pub const BLOCK_BUFFER_SIZE: usize = HEADER_SIZE + BLOCK_SIZE;
+
+/// A writer that splits its output into checksummed blocks
+pub struct BlockWriter<W: Write> {
+    /// The underlying writer
+    inner: W,
+
+    /// The block that is being filled, including space for its header
+    block: Vec<u8>
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(inner: W) -> Self {
+        let mut block = Vec::with_capacity(HEADER_SIZE + BLOCK_SIZE);
+        block.resize(HEADER_SIZE, 0);
+
+        BlockWriter { inner, block }
+    }
+
+    /// Writes the last, possibly partial, block and flushes the underlying writer
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+
+    /// Writes the block that is being filled to the underlying writer
+    fn write_block(&mut self) -> io::Result<()> {
+        let data_size = self.block.len() - HEADER_SIZE;
+
+        if data_size == 0 {
+            return Ok(());
+        }
+
+        let checksum = hash(&self.block[HEADER_SIZE..]);
+        self.block[0..4].copy_from_slice(&(data_size as u32).to_le_bytes());
+        self.block[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        self.inner.write_all(&self.block)?;
+        self.block.truncate(HEADER_SIZE);
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let free_space = HEADER_SIZE + BLOCK_SIZE - self.block.len();
+        let bytes_written = free_space.min(buf.len());
+
+        self.block.extend_from_slice(&buf[..bytes_written]);
+
+        if self.block.len() == HEADER_SIZE + BLOCK_SIZE {
+            self.write_block()?;
+        }
+
+        Ok(bytes_written)
+    }
+
+    /// Only full blocks are written, so the position of every byte in the
+    /// file can be computed. Use `finish` to write the last block.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that verifies the checksums of the blocks written by a `BlockWriter`
+pub struct BlockReader<R: Read> {
+    /// The underlying reader
+    inner: R,
+
+    /// The path of the file that is read, used in error messages
+    path: PathBuf,
+
+    /// The data of the current block
+    block: Vec<u8>,
+
+    /// The position of the next unread byte in the current block
+    position: usize,
+
+    /// The number of blocks that were read
+    block_count: usize
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(inner: R, path: &Path) -> Self {
+        BlockReader { inner, path: path.to_owned(), block: vec![], position: 0, block_count: 0 }
+    }
+
+    /// Skips the given number of bytes of the first block. Together with
+    /// `block_position` this allows reading from any offset in the data.
+    pub fn skip(&mut self, bytes: usize) -> io::Result<()> {
+        if bytes > 0 && self.read_block()? {
+            self.position = bytes.min(self.block.len());
+        }
+
+        Ok(())
+    }
+
+    /// Reads and verifies the next block. Returns false at the end of the input.
+    fn read_block(&mut self) -> io::Result<bool> {
+        let mut header = [0; HEADER_SIZE];
+
+        match read_full(&mut self.inner, &mut header)? {
+            0 => return Ok(false),
+            HEADER_SIZE => {},
+            _ => return Err(self.corrupted("truncated block header"))
+        }
+
+        let data_size = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        if data_size == 0 || data_size > BLOCK_SIZE {
+            return Err(self.corrupted("invalid block length"));
+        }
+
+        self.block.resize(data_size, 0);
+        if read_full(&mut self.inner, &mut self.block)? != data_size {
+            return Err(self.corrupted("truncated block"));
+        }
+
+        if hash(&self.block) != checksum {
+            return Err(self.corrupted("checksum mismatch"));
+        }
+
+        self.position = 0;
+        self.block_count += 1;
+
+        Ok(true)
+    }
+
+    fn corrupted(&self, reason: &str) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("corrupted run file {} ({} in block {})", self.path.display(), reason, self.block_count)
+        )
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.block.len() && !self.read_block()? {
+            return Ok(0);
+        }
+
+        let bytes_read = buf.len().min(self.block.len() - self.position);
+        buf[..bytes_read].copy_from_slice(&self.block[self.position..self.position + bytes_read]);
+        self.position += bytes_read;
+
+        Ok(bytes_read)
+    }
+}
+
+/// Returns the position in the file of the block that holds the given data
+/// offset, and the offset inside that block. All blocks but the last are full.
+pub fn block_position(offset: u64) -> (u64, usize) {
+    let block = offset / BLOCK_SIZE as u64;
+
+    (block * (HEADER_SIZE + BLOCK_SIZE) as u64, (offset % BLOCK_SIZE as u64) as usize)
+}
+
+/// Returns the number of data bytes in a file of the given length
+pub fn data_size(file_size: u64) -> u64 {
+    let blocks = file_size.div_ceil((HEADER_SIZE + BLOCK_SIZE) as u64);
+
+    file_size - blocks * HEADER_SIZE as u64
+}
+
+/// Reads until the buffer is full or the end of the input is reached
+fn read_full(input: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut bytes_read = 0;
+
+    while bytes_read < buffer.len() {
+        match input.read(&mut buffer[bytes_read..]) {
+            Ok(0) => break,
+            Ok(n) => bytes_read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err) => return Err(err)
+        }
+    }
+
+    Ok(bytes_read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_blocks(content: &[u8]) -> Vec<u8> {
+        let mut writer = BlockWriter::new(vec![]);
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap();
+
+        writer.inner
+    }
+
+    fn read_blocks(blocks: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = BlockReader::new(blocks, Path::new("run"));
+        let mut content = vec![];
+        reader.read_to_end(&mut content)?;
+
+        Ok(content)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let content: Vec<u8> = (0..3 * BLOCK_SIZE + 17).map(|i| (i % 251) as u8).collect();
+        let blocks = write_blocks(&content);
+
+        assert_eq!(blocks.len(), content.len() + 4 * HEADER_SIZE);
+        assert_eq!(read_blocks(&blocks).unwrap(), content);
+    }
+
+    #[test]
+    fn test_read_from_offset() {
+        let content: Vec<u8> = (0..3 * BLOCK_SIZE + 17).map(|i| (i % 251) as u8).collect();
+        let blocks = write_blocks(&content);
+
+        assert_eq!(data_size(blocks.len() as u64), content.len() as u64);
+
+        for offset in [0, 5, BLOCK_SIZE, 2 * BLOCK_SIZE + 1, content.len()] {
+            let (position, skip) = block_position(offset as u64);
+
+            let mut reader = BlockReader::new(&blocks[position as usize..], Path::new("run"));
+            reader.skip(skip).unwrap();
+
+            let mut rest = vec![];
+            reader.read_to_end(&mut rest).unwrap();
+
+            assert_eq!(rest, &content[offset..]);
+        }
+    }
+
+    #[test]
+    fn test_flush_keeps_partial_block() {
+        let mut writer = BlockWriter::new(vec![]);
+        writer.write_all(b"AAACLNNYAA\n").unwrap();
+        writer.flush().unwrap();
+
+        assert!(writer.inner.is_empty());
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(write_blocks(&[]).is_empty());
+        assert!(read_blocks(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_corrupted_data() {
+        let mut blocks = write_blocks("AAACLNNYAA\nAAAAAALTER\n".as_bytes());
+        blocks[HEADER_SIZE + 3] ^= 1;
+
+        let err = read_blocks(&blocks).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("corrupted run file run"));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let blocks = write_blocks("AAACLNNYAA\nAAAAAALTER\n".as_bytes());
+
+        assert!(read_blocks(&blocks[..blocks.len() - 1]).is_err());
+        assert!(read_blocks(&blocks[..HEADER_SIZE - 1]).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use std::{fs::{File, OpenOptions, read_to_string}, io::{self, ErrorKind, Write}, path::{Path, PathBuf}};
+
+use crate::{Configuration, MissingField, RejectFile};
+
+use super::storage::InputRange;
+
+/// The name of the journal inside a persistent work directory
+pub const MANIFEST_NAME: &str = "MANIFEST";
+
+/// An append-only journal that records the progress of a sort in a persistent
+/// work directory, so an interrupted sort can be resumed.
+///
+/// Every line of the journal is one of
+///
+/// * `config <delimiter> <field> <missing field> [<last field>]` - The configuration the sort was started with
+/// * `run <start> <end> <file> <checksum> [<rejects>]` - Input bytes `start..end` were written to run `file`,
+///   the input up to `end` has the CRC32 `checksum`, and the reject file held `rejects` bytes once the
+///   input was read up to `end`
+/// * `sorted <size> <checksum>` - The whole input, of `size` bytes with the CRC32 `checksum`, has been turned into runs
+/// * `pass <file>...` - A merge pass completed and left these files, in the order of the input
+///
+/// A resumed sort has to read the same input, which the checksums make sure of.
+pub struct Checkpoint {
+    /// The journal file, opened in append mode
+    journal: File,
+
+    /// The file that lines without the sort field are written to, if any
+    rejects: Option<RejectFile>,
+
+    /// The state recorded in the journal so far
+    state: CheckpointState
+}
+
+/// The state of a sort as recorded in the journal
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct CheckpointState {
+    /// The sorted runs and the input byte range each of them covers
+    pub runs: Vec<((u64, u64), String)>,
+
+    /// The end of the input range of every run with the size of the reject file at that end
+    pub rejects: Vec<(u64, u64)>,
+
+    /// The end of the input range of every run with the checksum of the input up to that end
+    pub checksums: Vec<(u64, u32)>,
+
+    /// The size and checksum of the whole input, once it has been turned into sorted runs
+    pub sorted: Option<(u64, u32)>,
+
+    /// The number of completed merge passes
+    pub passes: usize,
+
+    /// The files produced by the last completed merge pass
+    pub files: Vec<String>
+}
+
+impl Checkpoint {
+    /// Creates a new journal inside the given directory
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The persistent work directory
+    /// * `config` - The configuration of the sort
+    ///
+    /// # Returns
+    ///
+    /// An empty checkpoint, or an error if the directory already holds one or
+    /// the journal cannot be written
+    pub fn create(dir: &Path, config: &Configuration) -> io::Result<Self> {
+        let path = dir.join(MANIFEST_NAME);
+
+        if path.exists() {
+            let message = format!("{} already contains a checkpoint, resume it instead", dir.display());
+            return Err(io::Error::new(ErrorKind::AlreadyExists, message));
+        }
+
+        let journal = open_journal(&path)?;
+        let mut checkpoint = Checkpoint { journal, rejects: rejects(config), state: CheckpointState::default() };
+        match config.last_field {
+            Some(last_field) => checkpoint.append(&format!("config {} {} {} {}", config.delimiter, config.field, config.missing_field.name(), last_field))?,
+            None             => checkpoint.append(&format!("config {} {} {}", config.delimiter, config.field, config.missing_field.name()))?
+        }
+
+        Ok(checkpoint)
+    }
+
+    /// Loads the journal of an interrupted sort
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The persistent work directory
+    /// * `config` - The configuration of the resumed sort, which has to match the original one
+    ///
+    /// # Returns
+    ///
+    /// The checkpoint with the recorded state, or an error if there is no
+    /// checkpoint, it is corrupted or it was created with another configuration
+    pub fn load(dir: &Path, config: &Configuration) -> io::Result<Self> {
+        let path = dir.join(MANIFEST_NAME);
+
+        let content = read_to_string(&path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => io::Error::new(ErrorKind::NotFound, format!("{} does not contain a checkpoint", dir.display())),
+            _                   => err
+        })?;
+
+        let state = replay(&content, config)?;
+
+        Ok(Checkpoint { journal: open_journal(&path)?, rejects: rejects(config), state })
+    }
+
+    pub fn state(&self) -> &CheckpointState {
+        &self.state
+    }
+
+    /// Records that the given input range was written to a sorted run
+    pub fn record_run(&mut self, range: InputRange, file: &Path) -> io::Result<()> {
+        let name = file_name(file);
+
+        // The run has to be on disk before we claim it exists
+        sync(file)?;
+
+        let entry = format!("run {} {} {} {}", range.start, range.end, name, range.checksum);
+
+        match &self.rejects {
+            Some(rejects) => {
+                // So do the rejects of the input it covers, which a resumed sort keeps
+                rejects.flush()?;
+                let bytes = rejects.bytes_at(range.end);
+
+                self.append(&format!("{} {}", entry, bytes))?;
+                self.state.rejects.push((range.end, bytes));
+            },
+            None => self.append(&entry)?
+        }
+
+        self.state.runs.push(((range.start, range.end), name));
+        self.state.checksums.push((range.end, range.checksum));
+
+        Ok(())
+    }
+
+    /// Records that the whole input, of the given size and checksum, has been turned into sorted runs
+    pub fn record_sorted(&mut self, size: u64, checksum: u32) -> io::Result<()> {
+        if let Some(rejects) = &self.rejects {
+            rejects.flush()?;
+        }
+
+        self.append(&format!("sorted {} {}", size, checksum))?;
+        self.state.sorted = Some((size, checksum));
+
+        Ok(())
+    }
+
+    /// Records that a merge pass completed and produced the given files
+    pub fn record_pass(&mut self, files: &[&Path]) -> io::Result<()> {
+        for file in files {
+            sync(file)?;
+        }
+
+        let names: Vec<String> = files.iter().map(|file| file_name(file)).collect();
+
+        self.append(&format!("pass {}", names.join(" ")))?;
+        self.state.passes += 1;
+        self.state.files = names;
+
+        Ok(())
+    }
+
+    fn append(&mut self, entry: &str) -> io::Result<()> {
+        writeln!(self.journal, "{}", entry)?;
+        self.journal.sync_data()
+    }
+}
+
+impl CheckpointState {
+    /// Returns the end of the longest prefix of the input that is covered by runs
+    pub fn input_offset(&self) -> u64 {
+        let mut ranges: Vec<(u64, u64)> = self.runs.iter().map(|(range, _)| *range).collect();
+        ranges.sort_unstable();
+
+        // Ranges can overlap, because several runs can cover the same range
+        let mut offset = 0;
+        for (start, end) in ranges {
+            if start > offset {
+                break;
+            }
+            offset = offset.max(end);
+        }
+
+        offset
+    }
+
+    /// Returns the number of input bytes that are covered by runs and their
+    /// checksum. Once the input is sorted, these are the size and checksum of
+    /// the whole input.
+    pub fn input_prefix(&self) -> (u64, u32) {
+        if let Some(input) = self.sorted {
+            return input;
+        }
+
+        let offset = self.input_offset();
+        let checksum = self.checksums
+            .iter()
+            .find(|(end, _)| *end == offset)
+            .map_or(0, |(_, checksum)| *checksum);
+
+        (offset, checksum)
+    }
+
+    /// Returns the size of the reject file once the input that is covered by
+    /// runs was read, the rejects after it are written again
+    pub fn reject_bytes(&self) -> u64 {
+        let offset = self.input_offset();
+
+        self.rejects
+            .iter()
+            .filter(|(end, _)| *end <= offset)
+            .map(|(_, bytes)| *bytes)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the files that still hold valid sorted data, in the order of the
+    /// input. Runs that were written after a gap in the input are not valid,
+    /// because the input will be read again from the end of the contiguous prefix.
+    pub fn valid_files(&self) -> Vec<String> {
+        if self.passes > 0 {
+            return self.files.clone();
+        }
+
+        let offset = self.input_offset();
+
+        // Runs can finish out of order, runs of the same range are recorded in order
+        let mut runs: Vec<&((u64, u64), String)> = self.runs
+            .iter()
+            .filter(|((_, end), _)| *end <= offset)
+            .collect();
+        runs.sort_by_key(|((start, _), _)| *start);
+
+        runs.into_iter().map(|(_, name)| name.clone()).collect()
+    }
+}
+
+fn replay(content: &str, config: &Configuration) -> io::Result<CheckpointState> {
+    let mut state = CheckpointState::default();
+
+    // An interrupted write can leave a partial last line, which is left out
+    let complete = content.rfind('\n').map_or("", |newline| &content[..newline]);
+
+    for line in complete.lines() {
+        let mut parts = line.split(' ');
+
+        match parts.next() {
+            Some("config") => {
+                let delimiter: u8 = parse_entry(parts.next(), line)?;
+                let field: usize = parse_entry(parts.next(), line)?;
+                let missing_field: String = parse_entry(parts.next(), line)?;
+                let last_field: Option<usize> = parts.next().map(|part| parse_entry(Some(part), line)).transpose()?;
+
+                if delimiter != config.delimiter || field != config.field || last_field != config.last_field {
+                    let fields = format!("{}{}", field, last_field.map_or(String::new(), |last_field| format!(",{}", last_field)));
+                    return Err(invalid_data(format!("The checkpoint was created with delimiter {} and field {}", delimiter, fields)));
+                }
+
+                // Runs that order the lines without the field differently cannot be merged
+                if missing_field != config.missing_field.name() {
+                    return Err(invalid_data(format!("The checkpoint was created with the missing field policy {}", missing_field)));
+                }
+            },
+            Some("run") => {
+                let start = parse_entry(parts.next(), line)?;
+                let end = parse_entry(parts.next(), line)?;
+                let name = parse_entry(parts.next(), line)?;
+                let checksum = parse_entry(parts.next(), line)?;
+
+                if let Some(bytes) = parts.next() {
+                    state.rejects.push((end, parse_entry(Some(bytes), line)?));
+                }
+
+                state.runs.push(((start, end), name));
+                state.checksums.push((end, checksum));
+            },
+            Some("sorted") => state.sorted = Some((parse_entry(parts.next(), line)?, parse_entry(parts.next(), line)?)),
+            Some("pass") => {
+                state.passes += 1;
+                state.files = parts.map(str::to_string).collect();
+            },
+            _ => return Err(invalid_data(format!("Corrupted checkpoint entry: {}", line)))
+        }
+    }
+
+    Ok(state)
+}
+
+fn rejects(config: &Configuration) -> Option<RejectFile> {
+    match &config.missing_field {
+        MissingField::Reject(rejects) => Some(rejects.clone()),
+        _                             => None
+    }
+}
+
+fn parse_entry<T: std::str::FromStr>(part: Option<&str>, line: &str) -> io::Result<T> {
+    part.and_then(|part| part.parse().ok())
+        .ok_or_else(|| invalid_data(format!("Corrupted checkpoint entry: {}", line)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn open_journal(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+fn file_name(file: &Path) -> String {
+    file.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+fn sync(file: &Path) -> io::Result<()> {
+    File::open(file)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let content = "config 9 1 first\nrun 0 10 00000000 11\nrun 20 30 00000002 33\nrun 10 20 00000001 22\nsorted 30 33\n";
+        let state = replay(content, &Configuration::default()).unwrap();
+
+        assert_eq!(state.runs.len(), 3);
+        assert_eq!(state.valid_files(), vec!["00000000".to_string(), "00000001".to_string(), "00000002".to_string()]);
+        assert_eq!(state.sorted, Some((30, 33)));
+        assert_eq!(state.passes, 0);
+        assert_eq!(state.input_offset(), 30);
+        assert_eq!(state.input_prefix(), (30, 33));
+    }
+
+    #[test]
+    fn test_replay_pass() {
+        let content = "config 9 1 first\nrun 0 10 00000000 11\nrun 10 20 00000001 22\nsorted 20 22\npass 00000002\npa";
+        let state = replay(content, &Configuration::default()).unwrap();
+
+        assert_eq!(state.passes, 1);
+        assert_eq!(state.valid_files(), vec!["00000002".to_string()]);
+    }
+
+    #[test]
+    fn test_valid_files_with_gap() {
+        let content = "config 9 1 first\nrun 0 10 00000000 11\nrun 20 30 00000002 33\n";
+        let state = replay(content, &Configuration::default()).unwrap();
+
+        assert_eq!(state.input_offset(), 10);
+        assert_eq!(state.input_prefix(), (10, 11));
+        assert_eq!(state.valid_files(), vec!["00000000".to_string()]);
+    }
+
+    #[test]
+    fn test_overlapping_ranges() {
+        let content = "config 9 1 first\nrun 0 10 00000000 11\nrun 0 10 00000001 11\nrun 10 20 00000002 22\n";
+        let state = replay(content, &Configuration::default()).unwrap();
+
+        assert_eq!(state.input_offset(), 20);
+        assert_eq!(state.valid_files().len(), 3);
+    }
+
+    #[test]
+    fn test_reject_bytes() {
+        let content = "config 9 2 reject\nrun 0 10 00000000 11 4\nrun 10 20 00000001 22 9\nrun 30 40 00000003 44 20\n";
+        let state = replay(content, &Configuration { field: 2, missing_field: MissingField::Reject(RejectFile::new(vec![])), ..Configuration::default() }).unwrap();
+
+        // The rejects of the input after the gap are written again
+        assert_eq!(state.input_offset(), 20);
+        assert_eq!(state.reject_bytes(), 9);
+    }
+
+    #[test]
+    fn test_replay_empty() {
+        let state = replay("config 9 1 first\n", &Configuration::default()).unwrap();
+        assert_eq!(state.input_prefix(), (0, 0));
+    }
+
+    #[test]
+    fn test_replay_config_mismatch() {
+        let err = replay("config 44 2 first\n", &Configuration::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_replay_missing_field_mismatch() {
+        let err = replay("config 9 2 last\n", &Configuration { field: 2, ..Configuration::default() }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_replay_last_field() {
+        let config = Configuration { field: 2, last_field: Some(3), ..Configuration::default() };
+        assert_eq!(replay("config 9 2 first 3\nsorted 0 0\n", &config).unwrap().sorted, Some((0, 0)));
+    }
+
+    #[test]
+    fn test_replay_last_field_mismatch() {
+        let err = replay("config 9 2 first 3\n", &Configuration { field: 2, ..Configuration::default() }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_replay_corrupted() {
+        let err = replay("config 9 1 first\nrun 0 ten 00000000 11\n", &Configuration::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
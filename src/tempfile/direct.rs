@@ -0,0 +1,339 @@
+use std::{alloc::{alloc_zeroed, dealloc, Layout}, fs::{File, OpenOptions}, io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write}, os::unix::{fs::{FileExt, OpenOptionsExt}, io::AsRawFd}, path::Path, ptr::NonNull, slice};
+
+use bytesize::{KIB, MIB};
+
+/// The alignment of the buffers, offsets and lengths of direct I/O
+const ALIGNMENT: usize = 4 * KIB as usize;
+
+/// The size of a single read or write
+pub const BUFFER_SIZE: usize = MIB as usize;
+
+/// A heap buffer that is aligned for direct I/O
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout
+}
+
+// The buffer owns its memory, like a Vec
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, ALIGNMENT).unwrap();
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) }).expect("Failed to allocate an aligned buffer");
+
+        AlignedBuffer { ptr, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Opens a file with `O_DIRECT`. Fails with `InvalidInput` if the
+/// filesystem does not support direct I/O.
+pub fn open_direct(path: &Path, write: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .read(!write)
+        .write(write)
+        .create(write)
+        .truncate(write)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+/// A writer that bypasses the page cache. Only the unaligned end of the file
+/// is written through the page cache when the writer is flushed. It stays in
+/// the buffer and is written again, directly, with the bytes that follow it.
+pub struct DirectWriter {
+    file: File,
+    buffer: AlignedBuffer,
+
+    /// The number of bytes in the buffer
+    filled: usize,
+
+    /// The file offset of the buffer
+    offset: u64
+}
+
+impl DirectWriter {
+    pub fn new(file: File) -> Self {
+        DirectWriter { file, buffer: AlignedBuffer::new(BUFFER_SIZE), filled: 0, offset: 0 }
+    }
+
+    /// Writes the aligned part of the buffer and moves the rest to its start
+    fn write_buffer(&mut self) -> io::Result<()> {
+        let aligned = self.filled - self.filled % ALIGNMENT;
+
+        if aligned == 0 {
+            return Ok(());
+        }
+
+        self.file.write_all_at(&self.buffer.as_slice()[..aligned], self.offset)?;
+        self.buffer.as_mut_slice().copy_within(aligned..self.filled, 0);
+
+        self.offset += aligned as u64;
+        self.filled -= aligned;
+
+        Ok(())
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = buf.len().min(BUFFER_SIZE - self.filled);
+        self.buffer.as_mut_slice()[self.filled..self.filled + bytes_written].copy_from_slice(&buf[..bytes_written]);
+        self.filled += bytes_written;
+
+        if self.filled == BUFFER_SIZE {
+            self.write_buffer()?;
+        }
+
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+
+        if self.filled > 0 {
+            // The length of a direct write has to be aligned, so direct I/O is
+            // only turned off for the tail
+            set_direct(&self.file, false)?;
+            let written = self.file.write_all_at(&self.buffer.as_slice()[..self.filled], self.offset);
+            set_direct(&self.file, true)?;
+
+            written?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A reader that bypasses the page cache
+pub struct DirectReader {
+    file: File,
+    buffer: AlignedBuffer,
+
+    /// The position of the next unread byte in the buffer
+    position: usize,
+
+    /// The number of bytes in the buffer
+    filled: usize,
+
+    /// The file offset of the next read
+    offset: u64
+}
+
+impl DirectReader {
+    /// Opens a reader that starts at the given position in the file
+    pub fn new(file: File, position: u64) -> io::Result<Self> {
+        // Direct reads have to start at an aligned offset
+        let offset = position - position % ALIGNMENT as u64;
+
+        let mut reader = DirectReader { file, buffer: AlignedBuffer::new(BUFFER_SIZE), position: 0, filled: 0, offset };
+        reader.fill_buffer()?;
+        reader.position = ((position - offset) as usize).min(reader.filled);
+
+        Ok(reader)
+    }
+
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        let mut bytes_read = 0;
+
+        while bytes_read < BUFFER_SIZE {
+            match self.file.read_at(&mut self.buffer.as_mut_slice()[bytes_read..], self.offset + bytes_read as u64) {
+                Ok(0) => break,
+                Ok(n) => {
+                    bytes_read += n;
+
+                    // Only the end of the file can give an unaligned read
+                    if n % ALIGNMENT != 0 {
+                        break;
+                    }
+                },
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => return Err(err)
+            }
+        }
+
+        self.offset += bytes_read as u64;
+        self.position = 0;
+        self.filled = bytes_read;
+
+        Ok(())
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.filled {
+            self.fill_buffer()?;
+        }
+
+        let bytes_read = buf.len().min(self.filled - self.position);
+        buf[..bytes_read].copy_from_slice(&self.buffer.as_slice()[self.position..self.position + bytes_read]);
+        self.position += bytes_read;
+
+        Ok(bytes_read)
+    }
+}
+
+/// A writer that drops its pages from the page cache once it is flushed,
+/// for filesystems that do not support direct I/O
+pub struct UncachedWriter {
+    file: File
+}
+
+impl UncachedWriter {
+    pub fn new(file: File) -> Self {
+        UncachedWriter { file }
+    }
+}
+
+impl Write for UncachedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Only clean pages can be dropped
+        self.file.sync_data()?;
+        advise(&self.file, libc::POSIX_FADV_DONTNEED);
+
+        Ok(())
+    }
+}
+
+/// A reader that announces sequential reads and drops the pages it has read
+/// from the page cache, for filesystems that do not support direct I/O
+pub struct UncachedReader {
+    file: BufReader<File>
+}
+
+impl UncachedReader {
+    pub fn new(mut file: File, position: u64) -> io::Result<Self> {
+        advise(&file, libc::POSIX_FADV_SEQUENTIAL);
+        file.seek(SeekFrom::Start(position))?;
+
+        Ok(UncachedReader { file: BufReader::new(file) })
+    }
+}
+
+impl Read for UncachedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Drop for UncachedReader {
+    fn drop(&mut self) {
+        advise(self.file.get_ref(), libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+/// Gives the kernel a hint about the whole file, hints are allowed to fail
+fn advise(file: &File, advice: libc::c_int) {
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+}
+
+/// Turns `O_DIRECT` on or off for an open file
+fn set_direct(file: &File, direct: bool) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let flags = if direct { flags | libc::O_DIRECT } else { flags & !libc::O_DIRECT };
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content() -> Vec<u8> {
+        (0..2 * BUFFER_SIZE + 4321).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_direct_write_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run");
+
+        // Not every filesystem supports direct I/O
+        let Ok(file) = open_direct(&path, true) else {
+            return;
+        };
+
+        let content = content();
+
+        let mut writer = DirectWriter::new(file);
+        writer.write_all(&content).unwrap();
+        writer.flush().unwrap();
+
+        for position in [0, 1, 4096, BUFFER_SIZE as u64 + 5, content.len() as u64] {
+            let mut reader = DirectReader::new(open_direct(&path, false).unwrap(), position).unwrap();
+
+            let mut output = vec![];
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, &content[position as usize..]);
+        }
+    }
+
+    #[test]
+    fn test_direct_flush_keeps_direct_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run");
+
+        let Ok(file) = open_direct(&path, true) else {
+            return;
+        };
+
+        let content = content();
+        let mut writer = DirectWriter::new(file);
+
+        // Every flush leaves an unaligned tail that the next bytes are written after
+        for part in content.chunks(BUFFER_SIZE / 3 + 7) {
+            writer.write_all(part).unwrap();
+            writer.flush().unwrap();
+
+            let flags = unsafe { libc::fcntl(writer.file.as_raw_fd(), libc::F_GETFL) };
+            assert_ne!(flags & libc::O_DIRECT, 0);
+        }
+
+        let mut output = vec![];
+        DirectReader::new(open_direct(&path, false).unwrap(), 0).unwrap().read_to_end(&mut output).unwrap();
+        assert_eq!(output, content);
+    }
+
+    #[test]
+    fn test_uncached_write_and_read() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let content = content();
+
+        let mut writer = UncachedWriter::new(file.reopen().unwrap());
+        writer.write_all(&content).unwrap();
+        writer.flush().unwrap();
+
+        let mut output = vec![];
+        UncachedReader::new(file.reopen().unwrap(), 7).unwrap().read_to_end(&mut output).unwrap();
+        assert_eq!(output, &content[7..]);
+    }
+}
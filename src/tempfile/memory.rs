@@ -0,0 +1,115 @@
+use std::{io::{self, Read, Write}, sync::Arc};
+
+use super::{storage::TmpStorage, tmp_file::{TmpFileOpened, TmpFileClosed, TmpFileWrite, TmpFileRead}};
+
+/// A storage that keeps all sorted runs in memory
+#[derive(Default)]
+pub struct MemoryStorage;
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage
+    }
+}
+
+impl TmpStorage for MemoryStorage {
+    type Writer = MemoryFileWriter;
+    type Closed = ClosedMemoryFile;
+    type Reader = MemoryFileReader;
+
+    fn create_new_file(&mut self) -> io::Result<Self::Writer> {
+        Ok(MemoryFileWriter { data: vec![] })
+    }
+}
+
+pub struct MemoryFileWriter {
+    data: Vec<u8>
+}
+
+impl TmpFileWrite for MemoryFileWriter {}
+
+impl TmpFileOpened for MemoryFileWriter {
+    type Closed = ClosedMemoryFile;
+
+    fn close(self) -> io::Result<Self::Closed> {
+        Ok(ClosedMemoryFile { data: Arc::new(self.data) })
+    }
+}
+
+impl Write for MemoryFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ClosedMemoryFile {
+    data: Arc<Vec<u8>>
+}
+
+impl TmpFileClosed for ClosedMemoryFile {
+    type Reopened = MemoryFileReader;
+
+    fn reopen(self) -> io::Result<Self::Reopened> {
+        Ok(MemoryFileReader { data: self.data, position: 0 })
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn read_from(&self, offset: u64) -> io::Result<Self::Reopened> {
+        let position = (offset as usize).min(self.data.len());
+
+        Ok(MemoryFileReader { data: Arc::clone(&self.data), position })
+    }
+
+    fn remove(self) {}
+}
+
+pub struct MemoryFileReader {
+    data: Arc<Vec<u8>>,
+    position: usize
+}
+
+impl TmpFileRead for MemoryFileReader {
+    fn close_and_remove(self) {}
+}
+
+impl TmpFileOpened for MemoryFileReader {
+    type Closed = ClosedMemoryFile;
+
+    fn close(self) -> io::Result<Self::Closed> {
+        Ok(ClosedMemoryFile { data: self.data })
+    }
+}
+
+impl Read for MemoryFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = (&self.data[self.position..]).read(buf)?;
+        self.position += bytes_read;
+
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_reopen() {
+        let mut storage = MemoryStorage::new();
+
+        let mut file = storage.create_new_file().unwrap();
+        file.write_all("AAACLNNYAA\nAAAAAALTER\n".as_bytes()).unwrap();
+
+        let mut content = String::new();
+        file.close().unwrap().reopen().unwrap().read_to_string(&mut content).unwrap();
+
+        assert_eq!(content, "AAACLNNYAA\nAAAAAALTER\n");
+    }
+}
@@ -1,14 +1,28 @@
+mod backend;
+mod block;
+mod checkpoint;
+#[cfg(target_os = "linux")]
+mod direct;
+mod memory;
+mod storage;
 mod tmp_dir;
 mod tmp_file;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub use backend::IoBackend;
+pub use backend::IoOptions;
+
+pub use storage::TmpStorage;
+pub use storage::Recovered;
+pub use storage::InputRange;
 
 pub use tmp_dir::TmpDir;
 pub use tmp_dir::TmpDirBuilder;
 
+pub use memory::MemoryStorage;
+
 pub use tmp_file::TmpFileOpened;
 pub use tmp_file::TmpFileClosed;
 pub use tmp_file::TmpFileWrite;
 pub use tmp_file::TmpFileRead;
-
-pub use tmp_file::ClosedTmpFile;
-pub use tmp_file::TmpFileWriter;
-pub use tmp_file::TmpFileReader;
@@ -0,0 +1,99 @@
+use std::io;
+
+use crate::Configuration;
+
+use super::tmp_file::{TmpFileWrite, TmpFileClosed, TmpFileRead};
+
+/// A place to store sorted runs that do not fit in memory. The files of a
+/// storage go through the typestates of `TmpFileWrite`, `TmpFileClosed`
+/// and `TmpFileRead`.
+///
+/// `TmpDir` stores runs in files on the local filesystem and is the default
+/// storage. `MemoryStorage` keeps them in memory.
+pub trait TmpStorage {
+    /// A new file that is written to
+    type Writer: TmpFileWrite<Closed = Self::Closed> + 'static;
+
+    /// A file that has been written
+    type Closed: TmpFileClosed<Reopened = Self::Reader> + Send + Sync + 'static;
+
+    /// A file that is read again
+    type Reader: TmpFileRead<Closed = Self::Closed> + 'static;
+
+    /// Creates a new, empty file
+    fn create_new_file(&mut self) -> io::Result<Self::Writer>;
+
+    /// Returns the memory of the buffers of a file that is open, next to the
+    /// buffers of the merge that reads or writes it
+    fn file_buffer_size(&self) -> usize {
+        0
+    }
+
+    /// Returns true if the files in this storage have to survive an interruption
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    /// Starts recording the progress of the sort and returns the progress
+    /// of an earlier, interrupted sort
+    fn recover(&mut self, _config: &Configuration) -> io::Result<Recovered<Self::Closed>> {
+        Ok(Recovered::default())
+    }
+
+    /// Records that the given input range was written to a sorted file
+    fn record_run(&mut self, _range: InputRange, _file: &Self::Closed) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Records that the whole input, of the given size and checksum, has been
+    /// turned into sorted files
+    fn record_sorted(&mut self, _size: u64, _checksum: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Records that a merge pass completed and produced the given files
+    fn record_pass(&mut self, _files: &[Self::Closed]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the sort has completed
+    fn finish(&mut self) {}
+}
+
+/// The bytes of the input that a sorted run holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputRange {
+    pub start: u64,
+    pub end: u64,
+
+    /// The CRC32 checksum of the input up to the end of the range, which tells
+    /// if a resumed sort reads the same input
+    pub checksum: u32
+}
+
+/// The progress of an earlier, interrupted sort
+pub struct Recovered<C> {
+    /// The number of input bytes that are already stored in sorted runs, which
+    /// is the whole input once it is sorted
+    pub input_offset: u64,
+
+    /// The CRC32 checksum of the input bytes that are already stored in sorted
+    /// runs, which the input of the resumed sort has to match
+    pub input_checksum: u32,
+
+    /// The sorted files that can be reused
+    pub files: Vec<C>,
+
+    /// Whether the whole input has already been turned into sorted runs
+    pub sorted: bool,
+
+    /// The size of the reject file once the input of the recovered runs was
+    /// read, or `None` if no sort was resumed
+    pub reject_bytes: Option<u64>
+}
+
+impl<C> Default for Recovered<C> {
+    fn default() -> Self {
+        Recovered { input_offset: 0, input_checksum: 0, files: vec![], sorted: false, reject_bytes: None }
+    }
+}
@@ -1,19 +1,35 @@
-use std::{path::{PathBuf, Path}, fs::{read_dir, remove_file, remove_dir}, process::exit};
+use std::{io, path::{PathBuf, Path}, fs::{read_dir, remove_file, remove_dir, create_dir_all}, process::exit, sync::{Mutex, Once}};
 
-use tempfile;
+use crate::Configuration;
 
-use super::tmp_file::TmpFileWriter;
+use super::{backend::{IoBackend, IoOptions}, checkpoint::{Checkpoint, MANIFEST_NAME}, storage::{TmpStorage, Recovered, InputRange}, tmp_file::{TmpFileWriter, ClosedTmpFile, TmpFileReader}};
 
 const DEFAULT_TMP_DIR: &str = "/tmp";
 
+/// The temporary directories that are removed when the program is interrupted
+static TMP_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+
+/// There can only be a single Ctrl-C handler, which is set by the first temporary directory
+static SET_HANDLER: Once = Once::new();
+
+#[derive(Default)]
 pub struct TmpDirBuilder<'a> {
     /// The location of the temporary directory
-    location: Option<&'a PathBuf>
+    location: Option<&'a PathBuf>,
+
+    /// A persistent work directory that is kept when the sort is interrupted
+    work_dir: Option<&'a PathBuf>,
+
+    /// Whether to continue the sort recorded in the work directory
+    resume: bool,
+
+    /// The way the files are read and written
+    options: IoOptions
 }
 
 impl<'a> TmpDirBuilder<'a> {
     pub fn new() -> Self {
-        TmpDirBuilder { location: None }
+        TmpDirBuilder { location: None, work_dir: None, resume: false, options: IoOptions::default() }
     }
 
     pub fn with_location(&mut self, location: &'a PathBuf) -> &mut Self {
@@ -21,44 +37,243 @@ impl<'a> TmpDirBuilder<'a> {
         self
     }
 
-    pub fn build(&mut self) -> TmpDir {
-        let default_location = PathBuf::from(DEFAULT_TMP_DIR);
+    /// Use a persistent work directory instead of a temporary one. The progress
+    /// of the sort is recorded in this directory, so it can be resumed.
+    pub fn with_work_dir(&mut self, work_dir: &'a PathBuf) -> &mut Self {
+        self.work_dir = Some(work_dir);
+        self
+    }
+
+    /// Continue the interrupted sort recorded in the given work directory
+    pub fn resume(&mut self, work_dir: &'a PathBuf) -> &mut Self {
+        self.work_dir = Some(work_dir);
+        self.resume = true;
+        self
+    }
 
-        // Create a new temporary directory
-        let tmp_dir = tempfile::Builder::new()
-            .prefix("extsort")
-            .tempdir_in(self.location.unwrap_or_else(|| &default_location))
-            .expect("Failed to create temporary directory"); // TODO: map_err
+    /// Read and write the files with the given backend, if it is available
+    pub fn with_io_backend(&mut self, backend: IoBackend) -> &mut Self {
+        self.options.backend = backend;
+        self
+    }
+
+    /// Keep the files out of the page cache, with direct I/O if the filesystem supports it
+    pub fn with_direct_io(&mut self, direct: bool) -> &mut Self {
+        self.options.direct = direct;
+        self
+    }
 
-        let tmp_path = tmp_dir.path().to_owned();
+    pub fn build(&mut self) -> TmpDir {
+        let options = IoOptions { backend: self.options.backend.resolve(), ..self.options };
 
-        // Set a handler in case a user interrupts the program (SIGINT)
-        ctrlc::set_handler(move || {
-            delete_tmp_dir_and_files(&tmp_path);
-            exit(1);
-        }).expect("Error setting Ctrl-C handler"); // TODO: map_err
+        if let Some(work_dir) = self.work_dir {
+            return TmpDir {
+                work_dir: WorkDir::Persistent(work_dir.clone()),
+                resume: self.resume,
+                file_count: 0,
+                checkpoint: None,
+                options
+            };
+        }
 
-        TmpDir { tmp_dir, file_count: 0 }
+        let location = self.location.cloned().unwrap_or_else(|| PathBuf::from(DEFAULT_TMP_DIR));
+
+        TmpDir { work_dir: WorkDir::Unused(location), resume: false, file_count: 0, checkpoint: None, options }
     }
 }
 
+enum WorkDir {
+    /// A temporary directory that will be created in this location once it is needed
+    Unused(PathBuf),
+
+    /// A directory that is removed when it is dropped or the program is interrupted
+    Temporary(tempfile::TempDir),
+
+    /// A directory that survives an interrupted sort
+    Persistent(PathBuf)
+}
+
 pub struct TmpDir {
     /// The temporary directory
-    tmp_dir: tempfile::TempDir,
+    work_dir: WorkDir,
+
+    /// Whether to continue the sort recorded in the work directory
+    resume: bool,
 
     /// The number of files in the temporary directory
-    file_count: usize
+    file_count: usize,
+
+    /// The journal of a persistent work directory
+    checkpoint: Option<Checkpoint>,
+
+    /// The way the files are read and written
+    options: IoOptions
 }
 
 impl TmpDir {
-    pub fn create_new_file(&mut self) -> TmpFileWriter {
+    /// Returns the way the files are read and written
+    pub fn io_options(&self) -> IoOptions {
+        self.options
+    }
+
+    /// Returns the path of the directory, creating it if it does not exist yet
+    pub fn path(&mut self) -> &Path {
+        if let WorkDir::Unused(location) = &self.work_dir {
+            self.work_dir = WorkDir::Temporary(create_tmp_dir(location));
+        }
+
+        match &self.work_dir {
+            WorkDir::Unused(_)          => unreachable!(),
+            WorkDir::Temporary(tmp_dir) => tmp_dir.path(),
+            WorkDir::Persistent(path)   => path
+        }
+    }
+}
+
+impl Drop for TmpDir {
+    /// A temporary directory removes itself, so the Ctrl-C handler no longer has to
+    fn drop(&mut self) {
+        if let (WorkDir::Temporary(tmp_dir), Ok(mut tmp_dirs)) = (&self.work_dir, TMP_DIRS.lock()) {
+            tmp_dirs.retain(|path| path != tmp_dir.path());
+        }
+    }
+}
+
+impl TmpStorage for TmpDir {
+    type Writer = TmpFileWriter;
+    type Closed = ClosedTmpFile;
+    type Reader = TmpFileReader;
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(file = self.file_count)))]
+    fn create_new_file(&mut self) -> io::Result<TmpFileWriter> {
         let filename = format!("{:0>8}", self.file_count);
-        let path = self.tmp_dir.path().join(filename);
+        let path = self.path().join(filename);
 
         self.file_count += 1;
 
-        path.into()
+        TmpFileWriter::create(path, self.options)
+    }
+
+    fn file_buffer_size(&self) -> usize {
+        self.options.buffer_size()
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.checkpoint.is_some()
+    }
+
+    /// Starts recording the progress of the sort. For a resumed work directory,
+    /// this returns everything that can be reused and removes the files of
+    /// stages that did not finish.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration of the sort
+    ///
+    /// # Returns
+    ///
+    /// The progress of the interrupted sort
+    fn recover(&mut self, config: &Configuration) -> io::Result<Recovered<ClosedTmpFile>> {
+        let path = match &self.work_dir {
+            WorkDir::Persistent(path) => path.clone(),
+            _                         => return Ok(Recovered::default())
+        };
+
+        create_dir_all(&path)?;
+
+        if !self.resume {
+            self.checkpoint = Some(Checkpoint::create(&path, config)?);
+            return Ok(Recovered::default());
+        }
+
+        let checkpoint = Checkpoint::load(&path, config)?;
+        let state = checkpoint.state();
+        let valid_files = state.valid_files();
+        let (input_offset, input_checksum) = state.input_prefix();
+
+        // Remove the files of unfinished stages and continue numbering after the others
+        for file in read_dir(&path)?.flatten() {
+            let name = file.file_name().to_string_lossy().into_owned();
+
+            if name == MANIFEST_NAME {
+                continue;
+            }
+
+            if valid_files.contains(&name) {
+                if let Ok(index) = name.parse::<usize>() {
+                    self.file_count = self.file_count.max(index + 1);
+                }
+            } else {
+                let _ = remove_file(file.path());
+            }
+        }
+
+        let recovered = Recovered {
+            input_offset,
+            input_checksum,
+            files: valid_files.iter().map(|name| ClosedTmpFile::new(path.join(name), self.options)).collect(),
+            sorted: state.sorted.is_some(),
+            reject_bytes: Some(state.reject_bytes())
+        };
+
+        self.checkpoint = Some(checkpoint);
+
+        Ok(recovered)
     }
+
+    fn record_run(&mut self, range: InputRange, file: &ClosedTmpFile) -> io::Result<()> {
+        match &mut self.checkpoint {
+            Some(checkpoint) => checkpoint.record_run(range, file.path()),
+            None             => Ok(())
+        }
+    }
+
+    fn record_sorted(&mut self, size: u64, checksum: u32) -> io::Result<()> {
+        match &mut self.checkpoint {
+            Some(checkpoint) => checkpoint.record_sorted(size, checksum),
+            None             => Ok(())
+        }
+    }
+
+    fn record_pass(&mut self, files: &[ClosedTmpFile]) -> io::Result<()> {
+        match &mut self.checkpoint {
+            Some(checkpoint) => {
+                let paths: Vec<&Path> = files.iter().map(|file| file.path()).collect();
+                checkpoint.record_pass(&paths)
+            },
+            None => Ok(())
+        }
+    }
+
+    /// Removes a persistent work directory once the sort has completed
+    fn finish(&mut self) {
+        if let WorkDir::Persistent(path) = &self.work_dir {
+            self.checkpoint = None;
+            delete_tmp_dir_and_files(path);
+        }
+    }
+}
+
+fn create_tmp_dir(location: &Path) -> tempfile::TempDir {
+    // Create a new temporary directory
+    let tmp_dir = tempfile::Builder::new()
+        .prefix("extsort")
+        .tempdir_in(location)
+        .expect("Failed to create temporary directory"); // TODO: map_err
+
+    // Set a handler in case a user interrupts the program (SIGINT)
+    SET_HANDLER.call_once(|| {
+        ctrlc::set_handler(|| {
+            for path in TMP_DIRS.lock().unwrap().iter() {
+                delete_tmp_dir_and_files(path);
+            }
+            exit(1);
+        }).expect("Error setting Ctrl-C handler"); // TODO: map_err
+    });
+
+    TMP_DIRS.lock().unwrap().push(tmp_dir.path().to_owned());
+
+    tmp_dir
 }
 
 fn delete_tmp_dir_and_files(path: &Path) {
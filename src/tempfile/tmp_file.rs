@@ -1,61 +1,122 @@
-use std::{io::{Write, BufWriter, Read, BufReader}, fs::{File, remove_file}, path::PathBuf};
+use std::{io::{self, Write, Read}, fs::{remove_file, metadata}, path::{PathBuf, Path}};
 
+use super::{backend::{IoOptions, RunWriter, RunReader}, block::{BlockWriter, BlockReader, block_position, data_size}};
+
+/// A temporary file that is opened for reading or writing
 pub trait TmpFileOpened {
     type Closed: TmpFileClosed;
 
-    fn close(self) -> Self::Closed;
+    fn close(self) -> io::Result<Self::Closed>;
 }
 
+/// A temporary file that is closed, but still holds its content
 pub trait TmpFileClosed {
     type Reopened: TmpFileOpened;
 
-    fn reopen(self) -> Self::Reopened;
-}
+    /// Opens the file again to read its content
+    fn reopen(self) -> io::Result<Self::Reopened>;
+
+    /// Returns the number of bytes in the file
+    fn size(&self) -> io::Result<u64>;
+
+    /// Opens the file to read its content from the given offset. The file itself
+    /// stays closed, so several parts of it can be read at the same time.
+    fn read_from(&self, offset: u64) -> io::Result<Self::Reopened>;
 
-pub trait TmpFileWrite: TmpFileOpened {
-    type InnerWrite: Write;
+    /// Removes the file and its content
+    fn remove(self);
 }
 
-pub trait TmpFileRead: TmpFileOpened + Send {
-    type InnerRead: Read;
+/// A temporary file that is opened for writing
+pub trait TmpFileWrite: TmpFileOpened + Write + Send {}
 
+/// A temporary file that is opened for reading
+pub trait TmpFileRead: TmpFileOpened + Read + Send {
     fn close_and_remove(self);
 }
 
 pub struct ClosedTmpFile {
-    path: PathBuf
+    path: PathBuf,
+    options: IoOptions
+}
+
+impl ClosedTmpFile {
+    pub fn new(path: PathBuf, options: IoOptions) -> Self {
+        ClosedTmpFile { path, options }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl From<PathBuf> for ClosedTmpFile {
+    fn from(path: PathBuf) -> Self {
+        ClosedTmpFile { path, options: IoOptions::default() }
+    }
 }
 
 impl TmpFileClosed for ClosedTmpFile {
     type Reopened = TmpFileReader;
 
-    fn reopen(self) -> Self::Reopened {
-        let file = BufReader::new(File::open(&self.path).unwrap());
-        TmpFileReader { path: self.path, file }
+    fn reopen(self) -> io::Result<Self::Reopened> {
+        let file = RunReader::open(&self.path, 0, self.options)?;
+        let file = BlockReader::new(file, &self.path);
+
+        Ok(TmpFileReader { path: self.path, options: self.options, file })
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(data_size(metadata(&self.path)?.len()))
+    }
+
+    fn read_from(&self, offset: u64) -> io::Result<Self::Reopened> {
+        let (position, skip) = block_position(offset);
+
+        let file = RunReader::open(&self.path, position, self.options)?;
+
+        let mut file = BlockReader::new(file, &self.path);
+        file.skip(skip)?;
+
+        Ok(TmpFileReader { path: self.path.clone(), options: self.options, file })
+    }
+
+    fn remove(self) {
+        remove_file(&self.path).unwrap();
     }
 }
 
+/// A temporary file that is written in checksummed blocks
 pub struct TmpFileWriter {
     path: PathBuf,
-    file: BufWriter<File>
+    options: IoOptions,
+    file: BlockWriter<RunWriter>
 }
 
-impl TmpFileWrite for TmpFileWriter {
-    type InnerWrite = BufWriter<File>;
+impl TmpFileWriter {
+    /// Creates a new temporary file that is written with the given options
+    pub fn create(path: PathBuf, options: IoOptions) -> io::Result<Self> {
+        let file = RunWriter::create(&path, options)?;
+        Ok(TmpFileWriter { path, options, file: BlockWriter::new(file) })
+    }
 }
 
+impl TmpFileWrite for TmpFileWriter {}
+
 impl TmpFileOpened for TmpFileWriter {
     type Closed = ClosedTmpFile;
 
-    fn close(self) -> Self::Closed {
-        ClosedTmpFile { path: self.path }
+    fn close(mut self) -> io::Result<Self::Closed> {
+        self.file.finish()?;
+        Ok(ClosedTmpFile { path: self.path, options: self.options })
     }
 }
 
-impl From<PathBuf> for TmpFileWriter {
-    fn from(path: PathBuf) -> Self {
-        let file = BufWriter::new(File::create(&path).unwrap());
-        TmpFileWriter { path, file }
+impl TryFrom<PathBuf> for TmpFileWriter {
+    type Error = io::Error;
+
+    fn try_from(path: PathBuf) -> io::Result<Self> {
+        TmpFileWriter::create(path, IoOptions::default())
     }
 }
 
@@ -69,14 +130,14 @@ impl Write for TmpFileWriter {
     }
 }
 
+/// A temporary file whose blocks are verified while reading
 pub struct TmpFileReader {
     path: PathBuf,
-    file: BufReader<File>
+    options: IoOptions,
+    file: BlockReader<RunReader>
 }
 
 impl TmpFileRead for TmpFileReader {
-    type InnerRead = BufReader<File>;
-
     fn close_and_remove(self) {
         remove_file(&self.path).unwrap();
     }
@@ -85,13 +146,15 @@ impl TmpFileRead for TmpFileReader {
 impl TmpFileOpened for TmpFileReader {
     type Closed = ClosedTmpFile;
 
-    fn close(self) -> Self::Closed {
-        ClosedTmpFile { path: self.path }
+    fn close(self) -> io::Result<Self::Closed> {
+        Ok(ClosedTmpFile { path: self.path, options: self.options })
     }
 }
 
-impl From<ClosedTmpFile> for TmpFileReader {
-    fn from(closed: ClosedTmpFile) -> Self {
+impl TryFrom<ClosedTmpFile> for TmpFileReader {
+    type Error = io::Error;
+
+    fn try_from(closed: ClosedTmpFile) -> io::Result<Self> {
         closed.reopen()
     }
 }
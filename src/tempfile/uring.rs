@@ -0,0 +1,322 @@
+use std::{collections::VecDeque, fs::File, io::{self, Read, Write}, os::unix::{fs::FileExt, io::AsRawFd}};
+
+use bytesize::KIB;
+use io_uring::{opcode, types, IoUring};
+
+/// The size of a single read or write that is queued
+pub const BUFFER_SIZE: usize = 128 * KIB as usize;
+
+/// A writer that queues writes with io_uring, so up to `queue_depth` buffers
+/// are written while the next one is filled
+pub struct UringWriter {
+    file: File,
+    ring: IoUring,
+
+    /// The buffer that is being filled
+    buffer: Vec<u8>,
+
+    /// The file offset of the buffer that is being filled
+    offset: u64,
+
+    /// The buffers that are being written and their file offsets, by slot
+    in_flight: Vec<Option<(Vec<u8>, u64)>>,
+
+    /// Written buffers that can be filled again
+    free: Vec<Vec<u8>>
+}
+
+impl UringWriter {
+    pub fn new(file: File, queue_depth: u32) -> io::Result<Self> {
+        let ring = IoUring::new(queue_depth)?;
+        let in_flight = (0..queue_depth).map(|_| None).collect();
+
+        Ok(UringWriter { file, ring, buffer: Vec::with_capacity(BUFFER_SIZE), offset: 0, in_flight, free: vec![] })
+    }
+
+    /// Queues the buffer that is being filled
+    fn submit_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let slot = match self.in_flight.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.wait_for_completion()?;
+                self.in_flight.iter().position(Option::is_none).unwrap()
+            }
+        };
+
+        let next = self.free.pop().unwrap_or_else(|| Vec::with_capacity(BUFFER_SIZE));
+        let buffer = std::mem::replace(&mut self.buffer, next);
+
+        let entry = opcode::Write::new(types::Fd(self.file.as_raw_fd()), buffer.as_ptr(), buffer.len() as u32)
+            .offset(self.offset)
+            .build()
+            .user_data(slot as u64);
+
+        // The buffer is kept alive in its slot until the write has completed
+        unsafe { self.ring.submission().push(&entry) }.map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        self.ring.submit()?;
+
+        let offset = self.offset;
+        self.offset += buffer.len() as u64;
+        self.in_flight[slot] = Some((buffer, offset));
+
+        Ok(())
+    }
+
+    /// Waits until at least one write has completed
+    fn wait_for_completion(&mut self) -> io::Result<()> {
+        self.ring.submit_and_wait(1)?;
+
+        let completions: Vec<(usize, i32)> = self.ring
+            .completion()
+            .map(|entry| (entry.user_data() as usize, entry.result()))
+            .collect();
+
+        let mut status = Ok(());
+
+        for (slot, result) in completions {
+            let (mut buffer, offset) = self.in_flight[slot].take().unwrap();
+
+            if result < 0 {
+                status = Err(io::Error::from_raw_os_error(-result));
+            } else if (result as usize) < buffer.len() {
+                // Finish a short write without the ring
+                let written = result as usize;
+                status = status.and(self.file.write_all_at(&buffer[written..], offset + written as u64));
+            }
+
+            buffer.clear();
+            self.free.push(buffer);
+        }
+
+        status
+    }
+}
+
+impl Write for UringWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = buf.len().min(BUFFER_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..bytes_written]);
+
+        if self.buffer.len() == BUFFER_SIZE {
+            self.submit_buffer()?;
+        }
+
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.submit_buffer()?;
+
+        while self.in_flight.iter().any(Option::is_some) {
+            self.wait_for_completion()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for UringWriter {
+    fn drop(&mut self) {
+        // The kernel may still read from the buffers
+        while self.in_flight.iter().any(Option::is_some) {
+            if self.ring.submit_and_wait(1).is_err() {
+                break;
+            }
+
+            let _ = self.wait_for_completion();
+        }
+    }
+}
+
+/// A reader that queues reads of the next `queue_depth` buffers with io_uring
+pub struct UringReader {
+    file: File,
+    ring: IoUring,
+
+    /// The buffers that are being read, by slot
+    slots: Vec<ReadSlot>,
+
+    /// The slots in the order of their file offsets
+    pending: VecDeque<usize>,
+
+    /// The file offset of the next read to queue
+    next_offset: u64,
+
+    /// The buffer that is being consumed
+    buffer: Vec<u8>,
+
+    /// The position of the next unread byte in the buffer that is being consumed
+    position: usize,
+
+    /// Whether the end of the file has been reached
+    exhausted: bool
+}
+
+struct ReadSlot {
+    buffer: Vec<u8>,
+    offset: u64,
+    result: Option<i32>
+}
+
+impl UringReader {
+    pub fn new(file: File, offset: u64, queue_depth: u32) -> io::Result<Self> {
+        let ring = IoUring::new(queue_depth)?;
+        let slots = (0..queue_depth).map(|_| ReadSlot { buffer: vec![0; BUFFER_SIZE], offset: 0, result: None }).collect();
+
+        let mut reader = UringReader {
+            file, ring, slots, pending: VecDeque::new(), next_offset: offset, buffer: vec![], position: 0, exhausted: false
+        };
+
+        for slot in 0..queue_depth as usize {
+            reader.submit_read(slot)?;
+        }
+
+        Ok(reader)
+    }
+
+    /// Queues a read of the next buffer of the file into the given slot
+    fn submit_read(&mut self, slot: usize) -> io::Result<()> {
+        let read_slot = &mut self.slots[slot];
+        read_slot.buffer.resize(BUFFER_SIZE, 0);
+        read_slot.offset = self.next_offset;
+        read_slot.result = None;
+
+        let entry = opcode::Read::new(types::Fd(self.file.as_raw_fd()), read_slot.buffer.as_mut_ptr(), BUFFER_SIZE as u32)
+            .offset(self.next_offset)
+            .build()
+            .user_data(slot as u64);
+
+        // The buffer is kept alive in its slot until the read has completed
+        unsafe { self.ring.submission().push(&entry) }.map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        self.ring.submit()?;
+
+        self.next_offset += BUFFER_SIZE as u64;
+        self.pending.push_back(slot);
+
+        Ok(())
+    }
+
+    /// Waits until at least one read has completed
+    fn wait_for_completion(&mut self) -> io::Result<()> {
+        self.ring.submit_and_wait(1)?;
+
+        let completions: Vec<(usize, i32)> = self.ring
+            .completion()
+            .map(|entry| (entry.user_data() as usize, entry.result()))
+            .collect();
+
+        for (slot, result) in completions {
+            self.slots[slot].result = Some(result);
+        }
+
+        Ok(())
+    }
+
+    /// Makes the next buffer of the file the buffer that is being consumed
+    fn next_buffer(&mut self) -> io::Result<()> {
+        let Some(slot) = self.pending.pop_front() else {
+            self.buffer.clear();
+            self.position = 0;
+            return Ok(());
+        };
+
+        while self.slots[slot].result.is_none() {
+            self.wait_for_completion()?;
+        }
+
+        let result = self.slots[slot].result.unwrap();
+        if result < 0 {
+            return Err(io::Error::from_raw_os_error(-result));
+        }
+
+        // Finish a short read without the ring, the end of the file is only reached
+        // when nothing more can be read
+        let read_slot = &mut self.slots[slot];
+        let mut bytes_read = result as usize;
+
+        while bytes_read < BUFFER_SIZE {
+            match self.file.read_at(&mut read_slot.buffer[bytes_read..], read_slot.offset + bytes_read as u64)? {
+                0 => {
+                    self.exhausted = true;
+                    break;
+                },
+                n => bytes_read += n
+            }
+        }
+
+        read_slot.buffer.truncate(bytes_read);
+        std::mem::swap(&mut self.buffer, &mut read_slot.buffer);
+        self.position = 0;
+
+        if !self.exhausted {
+            self.submit_read(slot)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            if self.exhausted {
+                return Ok(0);
+            }
+
+            self.next_buffer()?;
+        }
+
+        let bytes_read = buf.len().min(self.buffer.len() - self.position);
+        buf[..bytes_read].copy_from_slice(&self.buffer[self.position..self.position + bytes_read]);
+        self.position += bytes_read;
+
+        Ok(bytes_read)
+    }
+}
+
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        // The kernel may still write to the buffers
+        while self.pending.iter().any(|slot| self.slots[*slot].result.is_none()) {
+            if self.wait_for_completion().is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Returns true if io_uring can be used on this system
+pub fn is_supported() -> bool {
+    IoUring::new(2).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() {
+        if !is_supported() {
+            return;
+        }
+
+        let content: Vec<u8> = (0..3 * BUFFER_SIZE + 17).map(|i| (i % 251) as u8).collect();
+        let file = tempfile::tempfile().unwrap();
+
+        let mut writer = UringWriter::new(file.try_clone().unwrap(), 2).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.flush().unwrap();
+
+        let mut output = vec![];
+        UringReader::new(file.try_clone().unwrap(), 0, 4).unwrap().read_to_end(&mut output).unwrap();
+        assert_eq!(output, content);
+
+        let mut output = vec![];
+        UringReader::new(file, 5, 4).unwrap().read_to_end(&mut output).unwrap();
+        assert_eq!(output, &content[5..]);
+    }
+}
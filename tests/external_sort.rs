@@ -0,0 +1,186 @@
+//! Property tests that compare `external_sort` with stably sorting all lines in memory
+
+use std::{fs::read_dir, io::Cursor};
+
+use proptest::prelude::*;
+use sorter::{external_sort, Configuration, IoBackend, IoOptions, MemoryStorage, MissingField, RunGeneration, TmpDirBuilder};
+
+/// The bytes that fields are made of, few enough for many duplicate keys
+const ALPHABET: &[u8] = b"abcAB0 ";
+
+/// Returns whether a line sorts after the lines with the field, and the part
+/// of the line that it is sorted on. A field starts at the delimiter in front of
+/// it and runs until the end of the line, or until the delimiter after the last
+/// field if there is one. Returns `None` for a line that is left out.
+fn sort_key<'a>(line: &'a [u8], config: &Configuration) -> Option<(bool, &'a [u8])> {
+    let delimiters: Vec<usize> = line
+        .iter()
+        .enumerate()
+        .filter(|(_, byte)| **byte == config.delimiter)
+        .map(|(offset, _)| offset)
+        .collect();
+
+    let start = if config.field <= 1 { Some(0) } else { delimiters.get(config.field - 2).copied() };
+
+    let Some(start) = start else {
+        return match config.missing_field {
+            MissingField::First => Some((false, b"")),
+            MissingField::Last  => Some((true, b"")),
+            _                   => None
+        };
+    };
+
+    let end = config.last_field.and_then(|last_field| delimiters.get(last_field - 1)).copied().unwrap_or(line.len());
+
+    Some((false, &line[start..end]))
+}
+
+fn lines(content: &[u8]) -> Vec<&[u8]> {
+    // Every line ends with a newline, so the last part is empty
+    let mut lines: Vec<&[u8]> = content.split(|byte| *byte == b'\n').collect();
+    lines.pop();
+    lines
+}
+
+/// Lines with up to two fields more than `fields`, separated by the delimiter.
+/// Lines with fewer fields miss the field they are sorted on.
+fn input(fields: usize, delimiter: u8) -> impl Strategy<Value = Vec<u8>> {
+    let field = prop::collection::vec(prop::sample::select(ALPHABET), 0..8);
+    let line = prop::collection::vec(field, 1..fields + 3).prop_map(move |fields| fields.join(&delimiter));
+
+    prop::collection::vec(line, 0..300).prop_map(|lines| lines.into_iter().flat_map(|mut line| { line.push(b'\n'); line }).collect())
+}
+
+/// The settings of a sort, which are shown when a test fails
+#[derive(Clone, Debug)]
+struct Settings {
+    threads: usize,
+    thread_buffer_size: usize,
+    fan_in: Option<usize>,
+    field: usize,
+    last_field: Option<usize>,
+    missing_field: &'static str,
+    delimiter: u8,
+    replacement_selection: bool,
+    storage: Storage
+}
+
+/// Where the runs of a sort are stored
+#[derive(Clone, Copy, Debug)]
+enum Storage {
+    Memory,
+
+    /// Checksummed files in a temporary directory, read and written with the given options
+    Files(IoOptions)
+}
+
+impl Settings {
+    fn config(&self) -> Configuration {
+        let run_generation = if self.replacement_selection { RunGeneration::ReplacementSelection } else { RunGeneration::Chunks };
+
+        let missing_field = match self.missing_field {
+            "first" => MissingField::First,
+            "last"  => MissingField::Last,
+            _       => MissingField::Skip
+        };
+
+        // Replacement selection reads the input in chunks of a sixteenth of the buffer
+        let min_buffer_size = if self.replacement_selection { 1024 } else { 0 };
+
+        Configuration {
+            threads: self.threads,
+            buffer_size: (self.threads * self.thread_buffer_size).max(min_buffer_size),
+            chunk_size: self.fan_in,
+            min_stream_buffer: 8,
+            delimiter: self.delimiter,
+            field: self.field,
+            last_field: self.last_field,
+            missing_field,
+            run_generation,
+            ..Configuration::default()
+        }
+    }
+}
+
+/// Settings with tiny buffers, which create many runs that need several merge passes
+fn settings() -> impl Strategy<Value = Settings> {
+    // The first field of the key, and how many fields after it the key covers if it has a last field
+    let fields = (1..=3usize, prop::option::of(0..=2usize));
+
+    let missing_field = prop::sample::select(vec!["first", "last", "skip"]);
+
+    // Half of the sorts write their runs to files, with each of the ways to read and write them
+    let storage = prop::sample::select(vec![
+        Storage::Memory,
+        Storage::Memory,
+        Storage::Files(IoOptions::default()),
+        Storage::Files(IoOptions { direct: true, ..IoOptions::default() }),
+        Storage::Files(IoOptions { backend: IoBackend::IoUring { queue_depth: 4 }, direct: false })
+    ]);
+
+    let delimiter = prop::sample::select(vec![b'\t', b',']);
+
+    (1..=4usize, 96..1024usize, prop::option::of(2..=4usize), fields, missing_field, delimiter, any::<bool>(), storage)
+        .prop_map(|(threads, thread_buffer_size, fan_in, (field, last_field), missing_field, delimiter, replacement_selection, storage)| Settings {
+            threads,
+            thread_buffer_size,
+            fan_in,
+            field,
+            last_field: last_field.map(|fields| field + fields),
+            missing_field,
+            delimiter,
+            replacement_selection,
+            storage
+        })
+}
+
+fn settings_and_input() -> impl Strategy<Value = (Settings, Vec<u8>)> {
+    settings().prop_flat_map(|settings| {
+        let input = input(settings.field, settings.delimiter);
+        (Just(settings), input)
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_sort_like_memory((settings, input) in settings_and_input()) {
+        let config = settings.config();
+        prop_assert!(config.validate().is_ok());
+
+        let mut output = vec![];
+
+        match settings.storage {
+            Storage::Memory => {
+                external_sort(&mut Cursor::new(&input), &mut output, &mut MemoryStorage::new(), config.clone()).unwrap();
+            },
+            Storage::Files(options) => {
+                let location = tempfile::tempdir().unwrap();
+                let location_path = location.path().to_path_buf();
+
+                let mut tmp_dir = TmpDirBuilder::new()
+                    .with_location(&location_path)
+                    .with_io_backend(options.backend)
+                    .with_direct_io(options.direct)
+                    .build();
+
+                external_sort(&mut Cursor::new(&input), &mut output, &mut tmp_dir, config.clone()).unwrap();
+
+                drop(tmp_dir);
+                prop_assert_eq!(read_dir(location.path()).unwrap().count(), 0);
+            }
+        }
+
+        // The sort is stable: lines with the same key keep the order of the input,
+        // lines that are left out have no key
+        let mut expected_lines: Vec<(bool, &[u8], &[u8])> = lines(&input)
+            .into_iter()
+            .filter_map(|line| sort_key(line, &config).map(|(last, key)| (last, key, line)))
+            .collect();
+        expected_lines.sort_by_key(|(last, key, _)| (*last, *key));
+
+        let expected_lines: Vec<&[u8]> = expected_lines.into_iter().map(|(_, _, line)| line).collect();
+        prop_assert_eq!(lines(&output), expected_lines);
+    }
+}